@@ -0,0 +1,36 @@
+use ::doip2http::common::log::init_logger;
+use ::doip2http::doip_entity::DoipEntity;
+use ::doip2http::doip_sim::{VehicleDescription, VirtualVehicle};
+use log::info;
+use std::env;
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+  // Vehicle description file from the first argument or DOIP_SIM_VEHICLE
+  let Some(path) = env::args()
+    .nth(1)
+    .or_else(|| env::var("DOIP_SIM_VEHICLE").ok())
+  else {
+    eprintln!("Usage: doip-sim <vehicle.json>");
+    return Ok(ExitCode::from(2));
+  };
+
+  init_logger();
+  let mut description = VehicleDescription::load(&path)?;
+  // Listen somewhere else than the file says, e.g. 127.0.0.2 next to a second vehicle
+  if let Ok(address) = env::var("DOIP_SIM_ADDRESS")
+    && !address.is_empty()
+  {
+    description.address = address;
+  }
+
+  let config = description.entity_config()?;
+  let entity = DoipEntity::bind(config, VirtualVehicle::new(&description)).await?;
+  for ecu in &description.ecus {
+    info!("DoipSim: ECU {} at 0x{:04X}", ecu.name, ecu.logical_address);
+  }
+  entity.run().await?;
+
+  Ok(ExitCode::SUCCESS)
+}
@@ -1,4 +1,4 @@
-use log::{debug, error, info, trace, warn};
+use log::warn;
 use std::io::{self, Write};
 use std::net::IpAddr;
 
@@ -1,5 +1,5 @@
 use env_logger::{Builder, Env};
-use std::io::{self, Write};
+use std::io::Write;
 
 pub fn init_logger() {
   // 1. Init logger early, with defaults
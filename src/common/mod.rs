@@ -1,3 +1,6 @@
+#[allow(dead_code)]
 pub mod console_input;
 pub mod log;
+pub mod net;
+pub mod serde_hex;
 pub mod unity;
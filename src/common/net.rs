@@ -0,0 +1,218 @@
+use std::ffi::{CStr, CString};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket, lookup_host};
+
+// Strip the brackets of an IPv6 literal ("[fe80::1%eth0]" -> "fe80::1%eth0")
+fn strip_brackets(address: &str) -> &str {
+  address
+    .strip_prefix('[')
+    .and_then(|inner| inner.strip_suffix(']'))
+    .unwrap_or(address)
+}
+
+// Return the host part of an ECU address without brackets and IPv6 zone,
+// e.g. for use as TLS server name
+pub fn host_name(address: &str) -> &str {
+  let address = strip_brackets(address);
+  match address.split_once('%') {
+    Some((host, _)) => host,
+    None => address,
+  }
+}
+
+// Convert an IPv6 zone (interface name such as "eth0" or numeric index) into a scope id
+pub fn scope_id(zone: &str) -> Result<u32, Error> {
+  if let Ok(index) = zone.parse::<u32>() {
+    return Ok(index);
+  }
+
+  let name = CString::new(zone).map_err(|_| {
+    Error::new(
+      ErrorKind::InvalidInput,
+      format!("Invalid interface name: {}", zone),
+    )
+  })?;
+  // SAFETY: `name` is a valid NUL terminated string for the duration of the call
+  let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+  if index == 0 {
+    return Err(Error::new(
+      ErrorKind::InvalidInput,
+      format!("Unknown network interface: {}", zone),
+    ));
+  }
+  Ok(index)
+}
+
+// Return the interface name of a scope id, or the index itself if it has none
+fn zone_name(scope_id: u32) -> String {
+  let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+  // SAFETY: `name` has room for IF_NAMESIZE bytes as required by if_indextoname
+  let result = unsafe { libc::if_indextoname(scope_id, name.as_mut_ptr()) };
+  if result.is_null() {
+    return scope_id.to_string();
+  }
+  // SAFETY: on success if_indextoname wrote a NUL terminated name into `name`
+  unsafe { CStr::from_ptr(name.as_ptr()) }
+    .to_string_lossy()
+    .into_owned()
+}
+
+// Resolve an ECU address given as IPv4 ("192.168.1.10"), IPv6, optionally in
+// brackets and with a zone for link-local addresses ("fe80::1%eth0"), or a host name
+pub async fn resolve_address(address: &str, port: u16) -> Result<SocketAddr, Error> {
+  let host = strip_brackets(address);
+
+  if let Some((ip, zone)) = host.split_once('%') {
+    let ip = ip.parse::<Ipv6Addr>().map_err(|e| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid IPv6 address {}: {}", address, e),
+      )
+    })?;
+    return Ok(SocketAddr::V6(SocketAddrV6::new(
+      ip,
+      port,
+      0,
+      scope_id(zone)?,
+    )));
+  }
+
+  if let Ok(ip) = host.parse::<IpAddr>() {
+    return Ok(SocketAddr::new(ip, port));
+  }
+
+  lookup_host((host, port))
+    .await
+    .map_err(|e| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        format!("Failed to resolve {}: {}", address, e),
+      )
+    })?
+    .next()
+    .ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        format!("No address found for {}", address),
+      )
+    })
+}
+
+// Format the IP of `address` so it can be passed to `resolve_address` again,
+// link-local IPv6 addresses keep their zone ("fe80::1%eth0")
+pub fn format_ip(address: &SocketAddr) -> String {
+  match address {
+    SocketAddr::V6(v6) if v6.scope_id() != 0 => {
+      format!("{}%{}", v6.ip(), zone_name(v6.scope_id()))
+    }
+    _ => address.ip().to_string(),
+  }
+}
+
+// Wildcard address of the family of `remote`
+pub fn unspecified_address(remote: &SocketAddr) -> SocketAddr {
+  match remote {
+    SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+    SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+  }
+}
+
+// Local address and/or network interface DoIP sockets are bound to before
+// talking to an entity, so traffic leaves through the NIC facing the vehicle
+// instead of the route the OS would pick
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalBinding {
+  pub address: Option<SocketAddr>,
+  // Interface name bound with SO_BINDTODEVICE, e.g. "eth1" or "eth0.100"
+  pub interface: Option<String>,
+}
+
+impl LocalBinding {
+  // Return a copy with the given local IP and interface replacing the current ones
+  pub async fn with_overrides(
+    &self,
+    local_ip: Option<&str>,
+    interface: Option<&str>,
+  ) -> Result<Self, Error> {
+    let mut binding = self.clone();
+    if let Some(local_ip) = local_ip {
+      binding.address = Some(resolve_address(local_ip, 0).await?);
+    }
+    if let Some(interface) = interface {
+      scope_id(interface)?;
+      binding.interface = Some(interface.to_string());
+    }
+    Ok(binding)
+  }
+
+  fn local_address(&self, remote: &SocketAddr) -> Result<SocketAddr, Error> {
+    match self.address {
+      Some(local) if local.is_ipv4() != remote.is_ipv4() => Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+          "Local address {} and {} are of different address families",
+          local.ip(),
+          remote.ip()
+        ),
+      )),
+      Some(local) => Ok(local),
+      None => Ok(unspecified_address(remote)),
+    }
+  }
+
+  // Open a TCP connection to `remote` from the bound address and interface
+  pub async fn connect(&self, remote: SocketAddr) -> Result<TcpStream, Error> {
+    let local = self.local_address(&remote)?;
+    let socket = match remote {
+      SocketAddr::V4(_) => TcpSocket::new_v4()?,
+      SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(interface) = &self.interface {
+      #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+      socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| bind_device_error(interface, e))?;
+      #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+      return Err(bind_device_unsupported(interface));
+    }
+    if self.address.is_some() {
+      socket.bind(local)?;
+    }
+    socket.connect(remote).await
+  }
+
+  // Bind a UDP socket able to reach `remote` to the bound address and interface
+  pub async fn udp_socket(&self, remote: &SocketAddr) -> Result<UdpSocket, Error> {
+    let socket = UdpSocket::bind(self.local_address(remote)?).await?;
+    if let Some(interface) = &self.interface {
+      #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+      socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| bind_device_error(interface, e))?;
+      #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+      return Err(bind_device_unsupported(interface));
+    }
+    Ok(socket)
+  }
+}
+
+// SO_BINDTODEVICE needs CAP_NET_RAW, name the interface so the cause is clear
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device_error(interface: &str, e: Error) -> Error {
+  Error::new(
+    e.kind(),
+    format!("Failed to bind to interface {}: {}", interface, e),
+  )
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device_unsupported(interface: &str) -> Error {
+  Error::new(
+    ErrorKind::Unsupported,
+    format!(
+      "Binding to interface {} is not supported on this platform",
+      interface
+    ),
+  )
+}
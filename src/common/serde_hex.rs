@@ -0,0 +1,44 @@
+// Serde helpers for the "0x" hex strings used in JSON configuration files
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+
+use crate::common::unity::parse_hex_string_to_bytes;
+
+// Parse "0x1A2B" into an unsigned number that has to fit `T`
+pub fn parse_hex_number<T: TryFrom<u64>>(hex_str: &str) -> Result<T, String> {
+  let digits = hex_str
+    .strip_prefix("0x")
+    .ok_or_else(|| format!("Hex number {} must start with '0x'", hex_str))?;
+  let value =
+    u64::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex number: {}", hex_str))?;
+  T::try_from(value).map_err(|_| format!("Hex number {} is out of range", hex_str))
+}
+
+pub fn hex_number<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+  deserializer: D,
+) -> Result<T, D::Error> {
+  parse_hex_number(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+pub fn hex_numbers<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+  deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+  Vec::<String>::deserialize(deserializer)?
+    .iter()
+    .map(|hex_str| parse_hex_number(hex_str))
+    .collect::<Result<_, _>>()
+    .map_err(de::Error::custom)
+}
+
+pub fn optional_hex_number<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+  deserializer: D,
+) -> Result<Option<T>, D::Error> {
+  Option::<String>::deserialize(deserializer)?
+    .map(|hex_str| parse_hex_number(&hex_str))
+    .transpose()
+    .map_err(de::Error::custom)
+}
+
+pub fn hex_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+  parse_hex_string_to_bytes(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
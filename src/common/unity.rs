@@ -11,7 +11,7 @@ pub fn parse_hex_string_to_bytes(hex_str: &str) -> Result<Vec<u8>, String> {
     return Err("Hex string after '0x' is empty".to_string());
   }
 
-  if hex_digits.len() % 2 != 0 {
+  if !hex_digits.len().is_multiple_of(2) {
     return Err("Hex string must have an even number of digits".to_string());
   }
 
@@ -29,3 +29,14 @@ pub fn parse_hex_string_to_bytes(hex_str: &str) -> Result<Vec<u8>, String> {
 
   Ok(bytes)
 }
+
+// Formats bytes as a hex string starting with "0x", the inverse of
+// `parse_hex_string_to_bytes`
+pub fn format_bytes_as_hex_string(bytes: &[u8]) -> String {
+  let mut hex_str = String::with_capacity(2 + bytes.len() * 2);
+  hex_str.push_str("0x");
+  for byte in bytes {
+    hex_str.push_str(&format!("{:02X}", byte));
+  }
+  hex_str
+}
@@ -0,0 +1,331 @@
+use log::{debug, info, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+
+use crate::doip_client::DoipError;
+use crate::uds_client::{ConnectionState, UdsClient};
+use crate::uds_security::{SecurityAccessStatus, SeedKeyAlgorithm};
+use crate::uds_service::UdsError;
+
+// Requests a connection queues before new ones are rejected
+pub static DEFAULT_REQUEST_QUEUE_SIZE: usize = 32;
+// Requests a connection runs at once, e.g. to different target ECUs behind a gateway
+pub static MAX_CONCURRENT_REQUESTS: usize = 8;
+// TesterPresent keep-alive interval, well below the usual S3 server time of 5s
+pub static DEFAULT_TESTER_PRESENT_INTERVAL_MS: u64 = 2000;
+
+// Errors of a request handed to a connection
+#[derive(Debug)]
+pub enum RequestError {
+  // The request queue of the connection is full
+  QueueFull,
+  // The connection was closed before the request completed
+  Closed,
+  Doip(DoipError),
+  // The ECU rejected a typed service request or its response was invalid
+  Uds(UdsError),
+}
+
+impl fmt::Display for RequestError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RequestError::QueueFull => write!(f, "Request queue of the connection is full"),
+      RequestError::Closed => write!(f, "Connection was closed"),
+      RequestError::Doip(e) => write!(f, "{}", e),
+      RequestError::Uds(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<DoipError> for RequestError {
+  fn from(e: DoipError) -> Self {
+    RequestError::Doip(e)
+  }
+}
+
+impl From<UdsError> for RequestError {
+  fn from(e: UdsError) -> Self {
+    match e {
+      UdsError::Doip(e) => RequestError::Doip(e),
+      e => RequestError::Uds(e),
+    }
+  }
+}
+
+enum Command {
+  Diagnostic {
+    target_address: u16,
+    uds_data: Vec<u8>,
+    reply: oneshot::Sender<Result<Vec<u8>, DoipError>>,
+  },
+  AliveCheck {
+    reply: oneshot::Sender<Result<u16, DoipError>>,
+  },
+  SecurityUnlock {
+    target_address: u16,
+    level: u8,
+    algorithm: Arc<dyn SeedKeyAlgorithm>,
+    reply: oneshot::Sender<Result<SecurityAccessStatus, UdsError>>,
+  },
+}
+
+impl Command {
+  async fn execute(self, client: &UdsClient) {
+    // The caller may have given up waiting, the reply is dropped then
+    match self {
+      Command::Diagnostic {
+        target_address,
+        uds_data,
+        reply,
+      } => {
+        let _ = reply.send(client.request(target_address, &uds_data).await);
+      }
+      Command::AliveCheck { reply } => {
+        let _ = reply.send(client.alive_check().await);
+      }
+      Command::SecurityUnlock {
+        target_address,
+        level,
+        algorithm,
+        reply,
+      } => {
+        let _ = reply.send(
+          client
+            .security_unlock(target_address, level, algorithm.as_ref())
+            .await,
+        );
+      }
+    }
+  }
+}
+
+// Task owning the UDS client of one connection. Queued requests are taken up to
+// MAX_CONCURRENT_REQUESTS at a time, requests to the same target ECU still run one
+// after another.
+async fn run(
+  client: Arc<UdsClient>,
+  mut commands: mpsc::Receiver<Command>,
+  in_flight: Arc<AtomicUsize>,
+) {
+  let mut running = JoinSet::new();
+  loop {
+    tokio::select! {
+      command = commands.recv(), if running.len() < MAX_CONCURRENT_REQUESTS => {
+        let Some(command) = command else {
+          break;
+        };
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        let client = client.clone();
+        let in_flight = in_flight.clone();
+        running.spawn(async move {
+          command.execute(&client).await;
+          in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+      }
+      Some(result) = running.join_next(), if !running.is_empty() => {
+        if let Err(e) = result {
+          warn!("Connection: Request task failed: {}", e);
+        }
+      }
+    }
+  }
+
+  // Every handle is gone, let the requests still running finish
+  while running.join_next().await.is_some() {}
+}
+
+// Keep-alive sending TesterPresent 3E 80 to `target_address`, a physical ECU address or
+// a functional address reaching several ECUs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TesterPresentConfig {
+  pub target_address: u16,
+  pub interval: Duration,
+}
+
+struct KeepAlive {
+  config: TesterPresentConfig,
+  task: AbortHandle,
+}
+
+// Keep the diagnostic sessions behind `config.target_address` alive. The task sends
+// next to the queued requests, requests to the same target ECU hold its channel so
+// TesterPresent waits for them. It pauses while the connection is reconnecting and
+// ends when it is down for good.
+async fn keep_alive(client: Arc<UdsClient>, config: TesterPresentConfig) {
+  let mut interval = tokio::time::interval(config.interval);
+  interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  loop {
+    interval.tick().await;
+    match client.state() {
+      ConnectionState::Connected => {}
+      ConnectionState::Reconnecting => continue,
+      ConnectionState::Disconnected => break,
+    }
+    match client.keep_alive(config.target_address).await {
+      Ok(()) => debug!(
+        "Connection: TesterPresent sent to 0x{:04X}",
+        config.target_address
+      ),
+      Err(e) => warn!(
+        "Connection: TesterPresent to 0x{:04X} failed: {}",
+        config.target_address, e
+      ),
+    }
+  }
+  info!(
+    "Connection: TesterPresent to 0x{:04X} stopped, connection is down",
+    config.target_address
+  );
+}
+
+// Handle to a connection task, cheap to clone. The connection map is only locked to
+// look a handle up, requests are queued to the task without holding it.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+  client: Arc<UdsClient>,
+  commands: mpsc::Sender<Command>,
+  in_flight: Arc<AtomicUsize>,
+  actor: AbortHandle,
+  keep_alive: Arc<Mutex<Option<KeepAlive>>>,
+}
+
+impl ConnectionHandle {
+  // Start the task of `client` with room for `queue_size` waiting requests
+  pub fn spawn(client: UdsClient, queue_size: usize) -> Self {
+    let client = Arc::new(client);
+    let (commands, receiver) = mpsc::channel(queue_size);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let actor = tokio::spawn(run(client.clone(), receiver, in_flight.clone())).abort_handle();
+
+    Self {
+      client,
+      commands,
+      in_flight,
+      actor,
+      keep_alive: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  // The client for reading connection state, requests go through the queue
+  pub fn client(&self) -> &UdsClient {
+    &self.client
+  }
+
+  // Requests waiting for a free slot
+  pub fn queued_requests(&self) -> usize {
+    self.commands.max_capacity() - self.commands.capacity()
+  }
+
+  // Requests currently exchanged with the entity
+  pub fn in_flight_requests(&self) -> usize {
+    self.in_flight.load(Ordering::SeqCst)
+  }
+
+  pub fn is_busy(&self) -> bool {
+    self.in_flight_requests() > 0 || self.queued_requests() > 0
+  }
+
+  fn enqueue(&self, command: Command) -> Result<(), RequestError> {
+    self.commands.try_send(command).map_err(|e| match e {
+      mpsc::error::TrySendError::Full(_) => RequestError::QueueFull,
+      mpsc::error::TrySendError::Closed(_) => RequestError::Closed,
+    })
+  }
+
+  // Queue a UDS request to `target_address` and wait for the final response
+  pub async fn diagnostic(
+    &self,
+    target_address: u16,
+    uds_data: Vec<u8>,
+  ) -> Result<Vec<u8>, RequestError> {
+    let (reply, response) = oneshot::channel();
+    self.enqueue(Command::Diagnostic {
+      target_address,
+      uds_data,
+      reply,
+    })?;
+    Ok(response.await.map_err(|_| RequestError::Closed)??)
+  }
+
+  // Queue a DoIP alive check, returns the logical address of the entity
+  pub async fn alive_check(&self) -> Result<u16, RequestError> {
+    let (reply, response) = oneshot::channel();
+    self.enqueue(Command::AliveCheck { reply })?;
+    Ok(response.await.map_err(|_| RequestError::Closed)??)
+  }
+
+  // Queue a SecurityAccess seed/key exchange unlocking `level` of the ECU
+  pub async fn security_unlock(
+    &self,
+    target_address: u16,
+    level: u8,
+    algorithm: Arc<dyn SeedKeyAlgorithm>,
+  ) -> Result<SecurityAccessStatus, RequestError> {
+    let (reply, response) = oneshot::channel();
+    self.enqueue(Command::SecurityUnlock {
+      target_address,
+      level,
+      algorithm,
+      reply,
+    })?;
+    Ok(response.await.map_err(|_| RequestError::Closed)??)
+  }
+
+  // Start sending TesterPresent, replacing a keep-alive already running
+  pub fn start_tester_present(&self, config: TesterPresentConfig) {
+    let task = tokio::spawn(keep_alive(self.client.clone(), config)).abort_handle();
+    let previous = self
+      .keep_alive
+      .lock()
+      .unwrap()
+      .replace(KeepAlive { config, task });
+    if let Some(previous) = previous {
+      previous.task.abort();
+    }
+    info!(
+      "Connection: TesterPresent to 0x{:04X} every {}ms",
+      config.target_address,
+      config.interval.as_millis()
+    );
+  }
+
+  // Stop sending TesterPresent, returns false when no keep-alive was running
+  pub fn stop_tester_present(&self) -> bool {
+    let Some(keep_alive) = self.keep_alive.lock().unwrap().take() else {
+      return false;
+    };
+    keep_alive.task.abort();
+    info!(
+      "Connection: TesterPresent to 0x{:04X} stopped",
+      keep_alive.config.target_address
+    );
+    true
+  }
+
+  // The running keep-alive, None when stopped or ended with the connection
+  pub fn tester_present(&self) -> Option<TesterPresentConfig> {
+    self
+      .keep_alive
+      .lock()
+      .unwrap()
+      .as_ref()
+      .filter(|keep_alive| !keep_alive.task.is_finished())
+      .map(|keep_alive| keep_alive.config)
+  }
+
+  // Stop the task and the keep-alive, failing queued and running requests with Closed,
+  // and close the socket
+  pub async fn shutdown(&self) {
+    self.stop_tester_present();
+    self.actor.abort();
+    self.client.shutdown().await;
+    info!("Connection: Shut down");
+  }
+}
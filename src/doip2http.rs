@@ -1,26 +1,98 @@
 use axum::{Router, extract::State, http::StatusCode, response::Json, routing::post};
-use log::{error, info};
+use log::info;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::hash_map::Entry;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::Mutex;
 
 use crate::common::log::init_logger;
-use crate::common::unity::parse_hex_string_to_bytes;
-use crate::uds_client::UdsClient;
+use crate::common::net::{LocalBinding, format_ip, resolve_address};
+use crate::common::serde_hex::parse_hex_number;
+use crate::common::unity::{format_bytes_as_hex_string, parse_hex_string_to_bytes};
+use crate::connection::{
+  ConnectionHandle, DEFAULT_REQUEST_QUEUE_SIZE, DEFAULT_TESTER_PRESENT_INTERVAL_MS, RequestError,
+  TesterPresentConfig,
+};
+use crate::doip_client::{DoipConfig, DoipError};
+use crate::doip_discovery::{
+  DEFAULT_DISCOVERY_TIMEOUT_MS, DOIP_UDP_DISCOVERY_PORT, VehicleIdFilter, discover_vehicles,
+  entity_status, parse_vin, power_mode, power_mode_name,
+};
+use crate::doip_message::{
+  DEFAULT_PROTOCOL_VERSION, RoutingActivationResponse, is_supported_protocol_version,
+};
+use crate::doip_tls::DoipTlsConfig;
+use crate::uds_client::{
+  ConnectionState, NegativeResponse, ReconnectPolicy, UdsClient, UdsServiceType, UdsTiming,
+  service_name,
+};
+use crate::uds_security::KeyAlgorithms;
+use crate::uds_service::{MAX_REQUEST_SEED_LEVEL, UdsError, is_request_seed_level};
 
-// Shared application state
+// How often idle and dead connections are looked for
+pub static REAPER_INTERVAL_SECS: u64 = 5;
+
+// Server wide defaults, requests may override them
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+  // Local address/interface DoIP traffic leaves from
+  pub local_binding: LocalBinding,
+  // Close connections that had no request for this long, never when None
+  pub idle_timeout: Option<Duration>,
+  // Seed/key algorithms of the ECUs for /security_access
+  pub key_algorithms: KeyAlgorithms,
+}
+
+// A pooled connection
 #[derive(Clone)]
+pub struct Connection {
+  // Task owning the client, requests are queued to it after the map lookup
+  pub handle: ConnectionHandle,
+  pub idle_timeout: Option<Duration>,
+}
+
+// Shared application state
+#[derive(Clone, Default)]
 pub struct AppState {
-  pub connections: Arc<Mutex<HashMap<String, UdsClient>>>,
+  pub connections: Arc<Mutex<HashMap<String, Connection>>>,
+  pub config: ServerConfig,
 }
 
 impl AppState {
   pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_config(config: ServerConfig) -> Self {
     Self {
-      connections: Arc::new(Mutex::new(HashMap::new())),
+      config,
+      ..Self::default()
     }
   }
+
+  // Look up a connection, the map lock is released before the caller uses it
+  async fn connection(&self, connection_id: &str) -> Option<Connection> {
+    self.connections.lock().await.get(connection_id).cloned()
+  }
+
+  // Protocol version of a pooled connection to `ecu_ip`, entities reject requests with
+  // a version they do not speak
+  async fn protocol_version(&self, ecu_ip: &str) -> u8 {
+    let connections = self.connections.lock().await;
+    connections
+      .iter()
+      .find(|(connection_id, _)| {
+        connection_id
+          .rsplit_once(':')
+          .is_some_and(|(ip, _)| ip == ecu_ip)
+      })
+      .map(|(_, connection)| connection.handle.client().protocol_version())
+      .unwrap_or(DEFAULT_PROTOCOL_VERSION)
+  }
 }
 
 // Request/Response structures
@@ -28,6 +100,99 @@ impl AppState {
 pub struct ConnectRequest {
   pub ecu_ip: String,
   pub doip_source_address: String,
+  // UDS P2 client timeout in milliseconds
+  #[serde(default)]
+  pub p2_timeout_ms: Option<u64>,
+  // UDS P2* (extended, after 0x78 response pending) timeout in milliseconds
+  #[serde(default)]
+  pub p2_star_timeout_ms: Option<u64>,
+  // Largest DoIP payload accepted from the entity in bytes
+  #[serde(default)]
+  pub max_payload_size: Option<u32>,
+  // Use a TLS secured connection on port 3496
+  #[serde(default)]
+  pub tls: Option<DoipTlsConfig>,
+  // DoIP protocol version: "0x01" (2010), "0x02" (2012), "0x03" (2019) or "auto"
+  #[serde(default)]
+  pub protocol_version: Option<String>,
+  // Routing activation type, e.g. "0x00" (default), "0x01" (WWH-OBD) or "0xE0" (central security)
+  #[serde(default)]
+  pub activation_type: Option<String>,
+  // 4 bytes of OEM specific routing activation data, e.g. "0x12345678"
+  #[serde(default)]
+  pub activation_oem_data: Option<String>,
+  // Local IP address to connect from, overrides DOIP_LOCAL_IP
+  #[serde(default)]
+  pub local_ip: Option<String>,
+  // Network interface to connect through (SO_BINDTODEVICE), overrides DOIP_INTERFACE
+  #[serde(default)]
+  pub interface: Option<String>,
+  // Reconnect when the entity closes the socket, the connection stays down if omitted
+  #[serde(default)]
+  pub reconnect: Option<ReconnectRequest>,
+  // Close the connection after this long without requests, 0 keeps it open.
+  // Overrides DOIP_IDLE_TIMEOUT_MS.
+  #[serde(default)]
+  pub idle_timeout_ms: Option<u64>,
+  // Requests that may wait for the connection before new ones are rejected with 503
+  #[serde(default)]
+  pub request_queue_size: Option<usize>,
+  // Keep diagnostic sessions alive with TesterPresent, not sent if omitted
+  #[serde(default)]
+  pub tester_present: Option<TesterPresentRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct TesterPresentRequest {
+  // Physical ECU address or functional address, e.g. "0x1010" or "0xE400"
+  pub target_address: String,
+  #[serde(default)]
+  pub interval_ms: Option<u64>,
+}
+
+impl TesterPresentRequest {
+  fn config(&self) -> Result<TesterPresentConfig, String> {
+    let target_address = parse_hex_number::<u16>(&self.target_address)
+      .map_err(|e| format!("Invalid TesterPresent target address: {}", e))?;
+    let interval_ms = self
+      .interval_ms
+      .unwrap_or(DEFAULT_TESTER_PRESENT_INTERVAL_MS);
+    if interval_ms == 0 {
+      return Err("TesterPresent interval must be positive".to_string());
+    }
+    Ok(TesterPresentConfig {
+      target_address,
+      interval: Duration::from_millis(interval_ms),
+    })
+  }
+}
+
+#[derive(Deserialize)]
+pub struct TesterPresentToggleRequest {
+  pub ecu_ip: String,
+  pub doip_source_address: String,
+  // Start (or restart with new settings) or stop the keep-alive
+  pub enabled: bool,
+  // Needed when enabling, as for /connect
+  #[serde(default)]
+  pub target_address: Option<String>,
+  #[serde(default)]
+  pub interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReconnectRequest {
+  // Failed attempts before giving up, unlimited if omitted
+  #[serde(default)]
+  pub max_attempts: Option<u32>,
+  // Delay before the first attempt, doubled after each failure up to max_delay_ms
+  #[serde(default)]
+  pub initial_delay_ms: Option<u64>,
+  #[serde(default)]
+  pub max_delay_ms: Option<u64>,
+  // Re-enter the diagnostic session of each target ECU, true if omitted
+  #[serde(default)]
+  pub restore_session: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -38,11 +203,54 @@ pub struct DiagnosticRequest {
   pub uds_data: String,
 }
 
+#[derive(Deserialize)]
+pub struct SecurityAccessRequest {
+  pub ecu_ip: String,
+  pub doip_source_address: String,
+  pub doip_target_address: String,
+  // Odd requestSeed level, e.g. "0x01"
+  pub level: String,
+}
+
+#[derive(Deserialize)]
+pub struct DiscoverRequest {
+  // Broadcast or unicast destination, defaults to the limited broadcast address
+  #[serde(default)]
+  pub target_ip: Option<String>,
+  #[serde(default)]
+  pub timeout_ms: Option<u64>,
+  // Only entities with this VIN (17 characters) should answer
+  #[serde(default)]
+  pub vin: Option<String>,
+  // Only entities with this EID (hex, e.g. 0x001122334455) should answer
+  #[serde(default)]
+  pub eid: Option<String>,
+  // Local IP address and network interface to send from, as for /connect
+  #[serde(default)]
+  pub local_ip: Option<String>,
+  #[serde(default)]
+  pub interface: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EntityRequest {
+  pub ecu_ip: String,
+  #[serde(default)]
+  pub timeout_ms: Option<u64>,
+  // Local IP address and network interface to send from, as for /connect
+  #[serde(default)]
+  pub local_ip: Option<String>,
+  #[serde(default)]
+  pub interface: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct ConnectResponse {
   pub success: bool,
   pub message: String,
   pub connection_id: Option<String>,
+  // Routing activation response code when the entity denied the activation
+  pub routing_activation_code: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -50,6 +258,118 @@ pub struct DiagnosticResponse {
   pub success: bool,
   pub message: String,
   pub response_data: Option<String>,
+  // Set when the ECU answered with a negative response
+  #[serde(flatten)]
+  pub negative_response: Option<NegativeResponseInfo>,
+}
+
+#[derive(Serialize)]
+pub struct NegativeResponseInfo {
+  // Negative response code, e.g. "0x33"
+  pub nrc: String,
+  // ISO 14229-1 name, e.g. "securityAccessDenied"
+  pub nrc_name: String,
+  pub nrc_description: String,
+  // Rejected service, e.g. "0x27"
+  pub service: String,
+  pub service_name: String,
+}
+
+impl From<NegativeResponse> for NegativeResponseInfo {
+  fn from(negative: NegativeResponse) -> Self {
+    Self {
+      nrc: format!("0x{:02X}", negative.nrc.code()),
+      nrc_name: negative.nrc.name().to_string(),
+      nrc_description: negative.nrc.description().to_string(),
+      service: format!("0x{:02X}", negative.service_id),
+      service_name: service_name(negative.service_id).to_string(),
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct SecurityAccessResult {
+  pub success: bool,
+  pub message: String,
+  // unlocked, already_unlocked, invalid_key, exceeded_number_of_attempts or
+  // required_time_delay_not_expired
+  pub status: Option<String>,
+  // Set when the ECU answered with a negative response
+  #[serde(flatten)]
+  pub negative_response: Option<NegativeResponseInfo>,
+}
+
+#[derive(Serialize)]
+pub struct TesterPresentResult {
+  pub success: bool,
+  pub message: String,
+  // The keep-alive running after the request
+  pub tester_present: Option<TesterPresentInfo>,
+}
+
+#[derive(Serialize)]
+pub struct TesterPresentInfo {
+  pub target_address: String,
+  pub interval_ms: u64,
+}
+
+impl From<TesterPresentConfig> for TesterPresentInfo {
+  fn from(config: TesterPresentConfig) -> Self {
+    Self {
+      target_address: format!("0x{:04X}", config.target_address),
+      interval_ms: config.interval.as_millis() as u64,
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct DiscoverResponse {
+  pub success: bool,
+  pub message: String,
+  pub entities: Vec<DiscoveredEntityInfo>,
+}
+
+#[derive(Serialize)]
+pub struct DiscoveredEntityInfo {
+  pub ecu_ip: String,
+  pub protocol_version: String,
+  pub vin: String,
+  pub logical_address: String,
+  pub eid: String,
+  pub gid: String,
+  pub further_action: String,
+  pub sync_status: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EntityStatusResult {
+  pub success: bool,
+  pub message: String,
+  pub node_type: Option<String>,
+  pub max_concurrent_sockets: Option<u8>,
+  pub open_sockets: Option<u8>,
+  pub max_data_size: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct PowerModeResult {
+  pub success: bool,
+  pub message: String,
+  pub power_mode: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AliveCheckResult {
+  pub success: bool,
+  pub message: String,
+  pub doip_entity_address: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DisconnectResponse {
+  pub success: bool,
+  pub message: String,
+  pub connection_id: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -63,7 +383,27 @@ pub struct ConnectionInfo {
   pub connection_id: String,
   pub ecu_ip: String,
   pub doip_source_address: String,
+  pub doip_entity_address: String,
+  pub protocol_version: String,
   pub connected: bool,
+  // connected, reconnecting or disconnected
+  pub state: String,
+  // Time since the last request and after which the connection is closed
+  pub idle_ms: u64,
+  pub idle_timeout_ms: Option<u64>,
+  // Requests waiting in the connection's queue and being exchanged with the entity
+  pub queued_requests: usize,
+  pub in_flight_requests: usize,
+  // Running TesterPresent keep-alive
+  pub tester_present: Option<TesterPresentInfo>,
+  pub events: Vec<ConnectionEventInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionEventInfo {
+  pub timestamp_ms: u64,
+  pub event: String,
+  pub message: String,
 }
 
 // HTTP Handlers
@@ -78,17 +418,36 @@ pub async fn get_status(
     request.ecu_ip, request.doip_source_address
   );
 
-  let connections = state.connections.lock().unwrap();
   let connection_key = format!("{}:{}", request.ecu_ip, request.doip_source_address);
 
-  if let Some(client) = connections.get(&connection_key) {
+  if let Some(connection) = state.connection(&connection_key).await {
+    let client = connection.handle.client();
     Json(StatusResponse {
       active_connections: 1,
       connections: vec![ConnectionInfo {
         connection_id: connection_key,
         ecu_ip: request.ecu_ip,
         doip_source_address: request.doip_source_address,
+        doip_entity_address: format!("0x{:04X}", client.entity_address()),
+        protocol_version: format!("0x{:02X}", client.protocol_version()),
         connected: client.is_connected(),
+        state: client.state().name().to_string(),
+        idle_ms: client.idle_time().as_millis() as u64,
+        idle_timeout_ms: connection
+          .idle_timeout
+          .map(|idle_timeout| idle_timeout.as_millis() as u64),
+        queued_requests: connection.handle.queued_requests(),
+        in_flight_requests: connection.handle.in_flight_requests(),
+        tester_present: connection.handle.tester_present().map(Into::into),
+        events: client
+          .events()
+          .into_iter()
+          .map(|event| ConnectionEventInfo {
+            timestamp_ms: event.timestamp_ms,
+            event: event.event.to_string(),
+            message: event.message,
+          })
+          .collect(),
       }],
     })
   } else {
@@ -111,21 +470,6 @@ pub async fn connect(
 
   let connection_id = format!("{}:{}", request.ecu_ip, request.doip_source_address);
 
-  // Check if connection already exists
-  {
-    let connections = state.connections.lock().unwrap();
-    if connections.contains_key(&connection_id) {
-      return (
-        StatusCode::CONFLICT,
-        Json(ConnectResponse {
-          success: false,
-          message: "Connection already exists".to_string(),
-          connection_id: Some(connection_id),
-        }),
-      );
-    }
-  }
-
   // Check request data
   {
     if request.ecu_ip.is_empty() {
@@ -135,6 +479,7 @@ pub async fn connect(
           success: false,
           message: "ECU IP address is required".to_string(),
           connection_id: None,
+          routing_activation_code: None,
         }),
       );
     }
@@ -146,6 +491,7 @@ pub async fn connect(
           success: false,
           message: "Source address must start with '0x'".to_string(),
           connection_id: None,
+          routing_activation_code: None,
         }),
       );
     }
@@ -153,61 +499,272 @@ pub async fn connect(
 
   // Try parsing the hex number after "0x"
   let address_str = &request.doip_source_address[2..];
-  let parsed = u16::from_str_radix(address_str, 16);
+  let source_address = match u16::from_str_radix(address_str, 16) {
+    // valid: a u16 always fits the 2-byte source address range 0x0000 - 0xFFFF
+    Ok(num) => num,
+    Err(_) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(ConnectResponse {
+          success: false,
+          message: "Source address must be a valid hexadecimal number".to_string(),
+          connection_id: None,
+          routing_activation_code: None,
+        }),
+      );
+    }
+  };
+
+  let mut timing = UdsTiming::default();
+  if let Some(p2) = request.p2_timeout_ms {
+    timing.p2 = Duration::from_millis(p2);
+  }
+  if let Some(p2_star) = request.p2_star_timeout_ms {
+    timing.p2_star = Duration::from_millis(p2_star);
+  }
 
-  match parsed {
-    Ok(num) if num <= 0xFFFF => {
-      // valid: num is the parsed u16 source address
-      // continue with your logic here…
+  let mut doip_config = DoipConfig::default();
+  if let Some(max_payload_size) = request.max_payload_size {
+    doip_config.max_payload_size = max_payload_size;
+  }
+  doip_config.tls = request.tls;
+  match request.protocol_version.as_deref() {
+    None => {}
+    Some("auto") => doip_config.auto_detect_version = true,
+    Some(version) => match parse_hex_string_to_bytes(version).as_deref() {
+      Ok([version]) if is_supported_protocol_version(*version) => {
+        doip_config.protocol_version = *version;
+      }
+      _ => {
+        return (
+          StatusCode::BAD_REQUEST,
+          Json(ConnectResponse {
+            success: false,
+            message: "Protocol version must be 0x01, 0x02, 0x03 or auto".to_string(),
+            connection_id: None,
+            routing_activation_code: None,
+          }),
+        );
+      }
+    },
+  }
+  if let Some(activation_type) = &request.activation_type {
+    match parse_hex_string_to_bytes(activation_type).as_deref() {
+      Ok([activation_type]) => doip_config.routing_activation.activation_type = *activation_type,
+      _ => {
+        return (
+          StatusCode::BAD_REQUEST,
+          Json(ConnectResponse {
+            success: false,
+            message: "Activation type must be one byte, e.g. 0x00".to_string(),
+            connection_id: None,
+            routing_activation_code: None,
+          }),
+        );
+      }
+    }
+  }
+  if let Some(oem_data) = &request.activation_oem_data {
+    match parse_hex_string_to_bytes(oem_data).map(|bytes| <[u8; 4]>::try_from(bytes.as_slice())) {
+      Ok(Ok(oem_data)) => doip_config.routing_activation.oem_data = Some(oem_data),
+      _ => {
+        return (
+          StatusCode::BAD_REQUEST,
+          Json(ConnectResponse {
+            success: false,
+            message: "Activation OEM data must be 4 bytes, e.g. 0x00000000".to_string(),
+            connection_id: None,
+            routing_activation_code: None,
+          }),
+        );
+      }
     }
-    Ok(_) => {
-      // number > u16::MAX, but technically unreachable since u16::from_str_radix never returns Ok with out-of-range value
+  }
+
+  doip_config.local_binding = match state
+    .config
+    .local_binding
+    .with_overrides(request.local_ip.as_deref(), request.interface.as_deref())
+    .await
+  {
+    Ok(local_binding) => local_binding,
+    Err(e) => {
       return (
         StatusCode::BAD_REQUEST,
         Json(ConnectResponse {
           success: false,
-          message: "Source address must be a 2-byte hex value (0x0000 - 0xFFFF)".to_string(),
+          message: format!("Invalid local binding: {}", e),
           connection_id: None,
+          routing_activation_code: None,
         }),
       );
     }
-    Err(_) => {
+  };
+
+  let mut reconnect = ReconnectPolicy::default();
+  if let Some(reconnect_request) = &request.reconnect {
+    reconnect.enabled = true;
+    reconnect.max_attempts = reconnect_request.max_attempts;
+    if let Some(initial_delay) = reconnect_request.initial_delay_ms {
+      reconnect.initial_delay = Duration::from_millis(initial_delay);
+    }
+    if let Some(max_delay) = reconnect_request.max_delay_ms {
+      reconnect.max_delay = Duration::from_millis(max_delay);
+    }
+    if let Some(restore_session) = reconnect_request.restore_session {
+      reconnect.restore_session = restore_session;
+    }
+    if reconnect.initial_delay.is_zero() || reconnect.max_delay < reconnect.initial_delay {
       return (
         StatusCode::BAD_REQUEST,
         Json(ConnectResponse {
           success: false,
-          message: "Source address must be a valid hexadecimal number".to_string(),
+          message: "Reconnect delays must be positive with max_delay_ms >= initial_delay_ms"
+            .to_string(),
+          connection_id: None,
+          routing_activation_code: None,
+        }),
+      );
+    }
+  }
+
+  let tester_present = match request.tester_present.as_ref().map(|tp| tp.config()) {
+    Some(Err(message)) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(ConnectResponse {
+          success: false,
+          message,
+          connection_id: None,
+          routing_activation_code: None,
+        }),
+      );
+    }
+    Some(Ok(config)) => Some(config),
+    None => None,
+  };
+
+  let request_queue_size = match request.request_queue_size {
+    Some(0) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(ConnectResponse {
+          success: false,
+          message: "Request queue size must be at least 1".to_string(),
           connection_id: None,
+          routing_activation_code: None,
         }),
       );
     }
+    Some(request_queue_size) => request_queue_size,
+    None => DEFAULT_REQUEST_QUEUE_SIZE,
+  };
+  let idle_timeout = match request.idle_timeout_ms {
+    Some(0) => None,
+    Some(idle_timeout) => Some(Duration::from_millis(idle_timeout)),
+    None => state.config.idle_timeout,
+  };
+
+  // Check if connection already exists, one that is down for good is replaced. Only a
+  // valid request may replace it.
+  let replaced = {
+    let mut connections = state.connections.lock().await;
+    match connections.get(&connection_id) {
+      Some(connection) if connection.handle.client().state() == ConnectionState::Disconnected => {
+        connections.remove(&connection_id)
+      }
+      Some(_) => {
+        return (
+          StatusCode::CONFLICT,
+          Json(ConnectResponse {
+            success: false,
+            message: "Connection already exists".to_string(),
+            connection_id: Some(connection_id),
+            routing_activation_code: None,
+          }),
+        );
+      }
+      None => None,
+    }
+  };
+  if let Some(connection) = replaced {
+    info!("Replacing disconnected connection {}", connection_id);
+    connection.handle.shutdown().await;
   }
 
   // Create new UDS client
-  let uds_client = UdsClient::new(request.ecu_ip.clone(), parsed.unwrap());
+  let uds_client = UdsClient::new(
+    request.ecu_ip.clone(),
+    source_address,
+    timing,
+    doip_config,
+    reconnect,
+  )
+  .await;
 
-  if uds_client.is_connected() {
-    // Store the connection
-    let mut connections = state.connections.lock().unwrap();
-    connections.insert(connection_id.clone(), uds_client);
+  match uds_client {
+    Ok(uds_client) => {
+      // Store the connection
+      let handle = ConnectionHandle::spawn(uds_client, request_queue_size);
+      if let Some(config) = tester_present {
+        handle.start_tester_present(config);
+      }
+      // A concurrent /connect for the same ECU and source address may have won the race
+      // while this one was activating routing
+      let mut connections = state.connections.lock().await;
+      match connections.entry(connection_id.clone()) {
+        Entry::Occupied(_) => {
+          drop(connections);
+          handle.shutdown().await;
+          return (
+            StatusCode::CONFLICT,
+            Json(ConnectResponse {
+              success: false,
+              message: "Connection already exists".to_string(),
+              connection_id: Some(connection_id),
+              routing_activation_code: None,
+            }),
+          );
+        }
+        Entry::Vacant(entry) => {
+          entry.insert(Connection {
+            handle,
+            idle_timeout,
+          });
+        }
+      }
 
-    (
-      StatusCode::OK,
+      (
+        StatusCode::OK,
+        Json(ConnectResponse {
+          success: true,
+          message: "Successfully connected to ECU".to_string(),
+          connection_id: Some(connection_id),
+          routing_activation_code: None,
+        }),
+      )
+    }
+    Err(DoipError::RoutingActivationDenied(code)) => (
+      StatusCode::BAD_REQUEST,
       Json(ConnectResponse {
-        success: true,
-        message: "Successfully connected to ECU".to_string(),
-        connection_id: Some(connection_id),
+        success: false,
+        message: format!(
+          "Routing activation denied: {}",
+          RoutingActivationResponse::reason(code)
+        ),
+        connection_id: None,
+        routing_activation_code: Some(format!("0x{:02X}", code)),
       }),
-    )
-  } else {
-    (
+    ),
+    Err(e) => (
       StatusCode::BAD_REQUEST,
       Json(ConnectResponse {
         success: false,
-        message: "Failed to connect to ECU".to_string(),
+        message: format!("Failed to connect to ECU: {}", e),
         connection_id: None,
+        routing_activation_code: None,
       }),
-    )
+    ),
   }
 }
 
@@ -230,6 +787,7 @@ pub async fn diagnostic_handler(
           success: false,
           message: "ECU IP address is required".to_string(),
           response_data: None,
+          negative_response: None,
         }),
       );
     }
@@ -241,6 +799,7 @@ pub async fn diagnostic_handler(
           success: false,
           message: "Source address must start with '0x'".to_string(),
           response_data: None,
+          negative_response: None,
         }),
       );
     }
@@ -252,6 +811,7 @@ pub async fn diagnostic_handler(
           success: false,
           message: "Target address must start with '0x'".to_string(),
           response_data: None,
+          negative_response: None,
         }),
       );
     }
@@ -263,45 +823,90 @@ pub async fn diagnostic_handler(
           success: false,
           message: "UDS data must start with '0x'".to_string(),
           response_data: None,
+          negative_response: None,
         }),
       );
     }
   }
 
-  //Parse address and UDS data
-  let source_address_str = &request.doip_source_address[2..];
-  let target_address_str = &request.doip_target_address[2..];
-
-  let source_address = u16::from_str_radix(source_address_str, 16).unwrap();
-  let target_address = u16::from_str_radix(target_address_str, 16).unwrap();
-
-  let mut uds_data_with_address = Vec::new();
-  uds_data_with_address.extend_from_slice(&source_address.to_be_bytes());
-  uds_data_with_address.extend_from_slice(&target_address.to_be_bytes());
-  uds_data_with_address.extend_from_slice(&parse_hex_string_to_bytes(&request.uds_data).unwrap());
+  // Parse target address and UDS data, the client adds its own source address
+  let target_address = match u16::from_str_radix(&request.doip_target_address[2..], 16) {
+    Ok(target_address) => target_address,
+    Err(_) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(DiagnosticResponse {
+          success: false,
+          message: "Target address must be a valid hexadecimal number".to_string(),
+          response_data: None,
+          negative_response: None,
+        }),
+      );
+    }
+  };
+  let uds_data = match parse_hex_string_to_bytes(&request.uds_data) {
+    Ok(uds_data) => uds_data,
+    Err(e) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(DiagnosticResponse {
+          success: false,
+          message: format!("Invalid UDS data: {}", e),
+          response_data: None,
+          negative_response: None,
+        }),
+      );
+    }
+  };
 
   // Check if connection exists for this ECU and source address
   let connection_key = format!("{}:{}", request.ecu_ip, request.doip_source_address);
 
-  let mut connections = state.connections.lock().unwrap();
-  if let Some(uds_client) = connections.get_mut(&connection_key) {
+  if let Some(connection) = state.connection(&connection_key).await {
+    let uds_client = connection.handle.client();
     if !uds_client.is_connected() {
       return (
         StatusCode::BAD_REQUEST,
         Json(DiagnosticResponse {
           success: false,
-          message: "Not connected to ECU".to_string(),
+          message: format!("Not connected to ECU ({})", uds_client.state().name()),
           response_data: None,
+          negative_response: None,
         }),
       );
     }
-    match uds_client.doip(Some(&uds_data_with_address)) {
-      Ok(response) => (
-        StatusCode::OK,
+    match connection.handle.diagnostic(target_address, uds_data).await {
+      Ok(response) => match NegativeResponse::parse(&response) {
+        // The exchange worked but the ECU rejected the request
+        Some(negative) => (
+          StatusCode::OK,
+          Json(DiagnosticResponse {
+            success: false,
+            message: format!("Negative response: {}", negative),
+            response_data: Some(format!("0x{:X?}", response)),
+            negative_response: Some(negative.into()),
+          }),
+        ),
+        None => (
+          StatusCode::OK,
+          Json(DiagnosticResponse {
+            success: true,
+            message: "Successfully sent diagnostic message".to_string(),
+            response_data: Some(format!("0x{:X?}", response)),
+            negative_response: None,
+          }),
+        ),
+      },
+      Err(RequestError::QueueFull) => (
+        StatusCode::SERVICE_UNAVAILABLE,
         Json(DiagnosticResponse {
-          success: true,
-          message: "Successfully sent diagnostic message".to_string(),
-          response_data: Some(format!("0x{:X?}", response)),
+          success: false,
+          message: format!(
+            "Failed to send diagnostic message: {}",
+            RequestError::QueueFull
+          ),
+          response_data: None,
+          negative_response: None,
         }),
       ),
       Err(e) => (
@@ -310,6 +915,7 @@ pub async fn diagnostic_handler(
           success: false,
           message: format!("Failed to send diagnostic message: {}", e),
           response_data: None,
+          negative_response: None,
         }),
       ),
     }
@@ -320,34 +926,603 @@ pub async fn diagnostic_handler(
         success: false,
         message: "Connection not found".to_string(),
         response_data: None,
+        negative_response: None,
       }),
     )
   }
 }
 
-// Create the router
-pub fn create_router() -> Router {
-  let state = AppState::new();
+// POST /security_access - Unlock a security level with the ECU's seed/key algorithm
+pub async fn security_access(
+  State(state): State<AppState>,
+  Json(request): Json<SecurityAccessRequest>,
+) -> (StatusCode, Json<SecurityAccessResult>) {
+  info!(
+    "Security access request: ECU={}, Source={}, Target={}, Level={}",
+    request.ecu_ip, request.doip_source_address, request.doip_target_address, request.level
+  );
+
+  let failure = |status: StatusCode, message: String| {
+    (
+      status,
+      Json(SecurityAccessResult {
+        success: false,
+        message,
+        status: None,
+        negative_response: None,
+      }),
+    )
+  };
+
+  let target_address = match parse_hex_number::<u16>(&request.doip_target_address) {
+    Ok(target_address) => target_address,
+    Err(e) => {
+      return failure(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid target address: {}", e),
+      );
+    }
+  };
+  let level = match parse_hex_number::<u8>(&request.level) {
+    Ok(level) if is_request_seed_level(level) => level,
+    _ => {
+      return failure(
+        StatusCode::BAD_REQUEST,
+        format!(
+          "Security level must be odd and at most 0x{:02X}, e.g. 0x01",
+          MAX_REQUEST_SEED_LEVEL
+        ),
+      );
+    }
+  };
+  let Some(algorithm) = state.config.key_algorithms.get(target_address, level) else {
+    return failure(
+      StatusCode::BAD_REQUEST,
+      format!(
+        "No seed/key algorithm configured for ECU 0x{:04X} level 0x{:02X}",
+        target_address, level
+      ),
+    );
+  };
+
+  let connection_key = format!("{}:{}", request.ecu_ip, request.doip_source_address);
+  let Some(connection) = state.connection(&connection_key).await else {
+    return failure(StatusCode::BAD_REQUEST, "Connection not found".to_string());
+  };
+  let uds_client = connection.handle.client();
+  if !uds_client.is_connected() {
+    return failure(
+      StatusCode::BAD_REQUEST,
+      format!("Not connected to ECU ({})", uds_client.state().name()),
+    );
+  }
+
+  match connection
+    .handle
+    .security_unlock(target_address, level, algorithm)
+    .await
+  {
+    Ok(status) if status.is_unlocked() => (
+      StatusCode::OK,
+      Json(SecurityAccessResult {
+        success: true,
+        message: format!("Security level 0x{:02X} unlocked", level),
+        status: Some(status.name().to_string()),
+        negative_response: None,
+      }),
+    ),
+    // The ECU counted the attempt or is still delaying new ones
+    Ok(status) => (
+      StatusCode::OK,
+      Json(SecurityAccessResult {
+        success: false,
+        message: format!("Security level 0x{:02X} not unlocked", level),
+        status: Some(status.name().to_string()),
+        negative_response: status.nrc().map(|nrc| {
+          NegativeResponse {
+            service_id: UdsServiceType::SecurityAccess as u8,
+            nrc,
+          }
+          .into()
+        }),
+      }),
+    ),
+    Err(RequestError::Uds(UdsError::NegativeResponse { service_id, nrc })) => {
+      let negative = NegativeResponse { service_id, nrc };
+      (
+        StatusCode::OK,
+        Json(SecurityAccessResult {
+          success: false,
+          message: format!("Negative response: {}", negative),
+          status: None,
+          negative_response: Some(negative.into()),
+        }),
+      )
+    }
+    Err(RequestError::Uds(e @ UdsError::Key(_))) => failure(
+      StatusCode::INTERNAL_SERVER_ERROR,
+      format!("Security access failed: {}", e),
+    ),
+    Err(RequestError::QueueFull) => failure(
+      StatusCode::SERVICE_UNAVAILABLE,
+      format!("Security access failed: {}", RequestError::QueueFull),
+    ),
+    Err(e) => failure(
+      StatusCode::BAD_REQUEST,
+      format!("Security access failed: {}", e),
+    ),
+  }
+}
+
+// POST /tester_present - Start or stop the TesterPresent keep-alive of a connection
+pub async fn tester_present(
+  State(state): State<AppState>,
+  Json(request): Json<TesterPresentToggleRequest>,
+) -> (StatusCode, Json<TesterPresentResult>) {
+  info!(
+    "Tester present request: ECU={}, Source={}, Enabled={}",
+    request.ecu_ip, request.doip_source_address, request.enabled
+  );
+
+  let failure = |message: String| {
+    (
+      StatusCode::BAD_REQUEST,
+      Json(TesterPresentResult {
+        success: false,
+        message,
+        tester_present: None,
+      }),
+    )
+  };
+
+  let connection_key = format!("{}:{}", request.ecu_ip, request.doip_source_address);
+  let Some(connection) = state.connection(&connection_key).await else {
+    return failure("Connection not found".to_string());
+  };
+
+  if !request.enabled {
+    let message = if connection.handle.stop_tester_present() {
+      "TesterPresent stopped"
+    } else {
+      "TesterPresent was not running"
+    };
+    return (
+      StatusCode::OK,
+      Json(TesterPresentResult {
+        success: true,
+        message: message.to_string(),
+        tester_present: None,
+      }),
+    );
+  }
+
+  let Some(target_address) = request.target_address else {
+    return failure("Target address is required to enable TesterPresent".to_string());
+  };
+  let config = match (TesterPresentRequest {
+    target_address,
+    interval_ms: request.interval_ms,
+  })
+  .config()
+  {
+    Ok(config) => config,
+    Err(message) => return failure(message),
+  };
+  if connection.handle.client().state() == ConnectionState::Disconnected {
+    return failure("Not connected to ECU (disconnected)".to_string());
+  }
+  connection.handle.start_tester_present(config);
+  (
+    StatusCode::OK,
+    Json(TesterPresentResult {
+      success: true,
+      message: format!(
+        "Sending TesterPresent to 0x{:04X} every {}ms",
+        config.target_address,
+        config.interval.as_millis()
+      ),
+      tester_present: Some(config.into()),
+    }),
+  )
+}
+
+// POST /discover - Find DoIP entities with a UDP vehicle identification request
+pub async fn discover(
+  State(state): State<AppState>,
+  Json(request): Json<DiscoverRequest>,
+) -> (StatusCode, Json<DiscoverResponse>) {
+  info!(
+    "Discover request: Target={:?}, VIN={:?}, EID={:?}",
+    request.target_ip, request.vin, request.eid
+  );
+
+  let bad_request = |message: String| {
+    (
+      StatusCode::BAD_REQUEST,
+      Json(DiscoverResponse {
+        success: false,
+        message,
+        entities: vec![],
+      }),
+    )
+  };
+
+  let target = match &request.target_ip {
+    Some(target_ip) => match resolve_address(target_ip, DOIP_UDP_DISCOVERY_PORT).await {
+      Ok(address) => address,
+      Err(e) => return bad_request(format!("Invalid target IP address: {}", e)),
+    },
+    None => SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DOIP_UDP_DISCOVERY_PORT),
+  };
+  let local_binding = match state
+    .config
+    .local_binding
+    .with_overrides(request.local_ip.as_deref(), request.interface.as_deref())
+    .await
+  {
+    Ok(local_binding) => local_binding,
+    Err(e) => return bad_request(format!("Invalid local binding: {}", e)),
+  };
+
+  let filter = match (&request.vin, &request.eid) {
+    (Some(_), Some(_)) => return bad_request("Specify either VIN or EID, not both".to_string()),
+    (Some(vin), None) => match parse_vin(vin) {
+      Ok(vin) => VehicleIdFilter::Vin(vin),
+      Err(e) => return bad_request(e.to_string()),
+    },
+    (None, Some(eid)) => {
+      match parse_hex_string_to_bytes(eid).map(|bytes| <[u8; 6]>::try_from(bytes.as_slice())) {
+        Ok(Ok(eid)) => VehicleIdFilter::Eid(eid),
+        Ok(Err(_)) => return bad_request("EID must be 6 bytes".to_string()),
+        Err(e) => return bad_request(format!("Invalid EID: {}", e)),
+      }
+    }
+    (None, None) => VehicleIdFilter::All,
+  };
+
+  let wait = Duration::from_millis(request.timeout_ms.unwrap_or(DEFAULT_DISCOVERY_TIMEOUT_MS));
+
+  match discover_vehicles(target, filter, &local_binding, wait).await {
+    Ok(entities) => (
+      StatusCode::OK,
+      Json(DiscoverResponse {
+        success: true,
+        message: format!("Found {} DoIP entities", entities.len()),
+        entities: entities
+          .into_iter()
+          .map(|entity| DiscoveredEntityInfo {
+            ecu_ip: format_ip(&entity.address),
+            protocol_version: format!("0x{:02X}", entity.protocol_version),
+            vin: entity.announcement.vin,
+            logical_address: format!("0x{:04X}", entity.announcement.logical_address),
+            eid: format_bytes_as_hex_string(&entity.announcement.eid),
+            gid: format_bytes_as_hex_string(&entity.announcement.gid),
+            further_action: format!("0x{:02X}", entity.announcement.further_action),
+            sync_status: entity
+              .announcement
+              .sync_status
+              .map(|status| format!("0x{:02X}", status)),
+          })
+          .collect(),
+      }),
+    ),
+    Err(e) => (
+      StatusCode::INTERNAL_SERVER_ERROR,
+      Json(DiscoverResponse {
+        success: false,
+        message: format!("Vehicle discovery failed: {}", e),
+        entities: vec![],
+      }),
+    ),
+  }
+}
+
+// POST /entity_status - Query node type, socket usage and max data size over UDP
+pub async fn get_entity_status(
+  State(state): State<AppState>,
+  Json(request): Json<EntityRequest>,
+) -> (StatusCode, Json<EntityStatusResult>) {
+  info!("Entity status request: ECU={}", request.ecu_ip);
 
+  let failure = |status: StatusCode, message: String| {
+    (
+      status,
+      Json(EntityStatusResult {
+        success: false,
+        message,
+        node_type: None,
+        max_concurrent_sockets: None,
+        open_sockets: None,
+        max_data_size: None,
+      }),
+    )
+  };
+
+  let ecu = match resolve_address(&request.ecu_ip, DOIP_UDP_DISCOVERY_PORT).await {
+    Ok(address) => address,
+    Err(e) => {
+      return failure(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid ECU IP address: {}", e),
+      );
+    }
+  };
+  let local_binding = match state
+    .config
+    .local_binding
+    .with_overrides(request.local_ip.as_deref(), request.interface.as_deref())
+    .await
+  {
+    Ok(local_binding) => local_binding,
+    Err(e) => {
+      return failure(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid local binding: {}", e),
+      );
+    }
+  };
+  let wait = Duration::from_millis(request.timeout_ms.unwrap_or(DEFAULT_DISCOVERY_TIMEOUT_MS));
+
+  let version = state.protocol_version(&request.ecu_ip).await;
+  match entity_status(ecu, &local_binding, version, wait).await {
+    Ok(status) => (
+      StatusCode::OK,
+      Json(EntityStatusResult {
+        success: true,
+        message: "Received entity status".to_string(),
+        node_type: Some(status.node_type_name().to_string()),
+        max_concurrent_sockets: Some(status.max_concurrent_sockets),
+        open_sockets: Some(status.open_sockets),
+        max_data_size: status.max_data_size,
+      }),
+    ),
+    Err(e) => failure(
+      StatusCode::BAD_REQUEST,
+      format!("Entity status request failed: {}", e),
+    ),
+  }
+}
+
+// POST /power_mode - Query the diagnostic power mode over UDP
+pub async fn get_power_mode(
+  State(state): State<AppState>,
+  Json(request): Json<EntityRequest>,
+) -> (StatusCode, Json<PowerModeResult>) {
+  info!("Power mode request: ECU={}", request.ecu_ip);
+
+  let ecu = match resolve_address(&request.ecu_ip, DOIP_UDP_DISCOVERY_PORT).await {
+    Ok(address) => address,
+    Err(e) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(PowerModeResult {
+          success: false,
+          message: format!("Invalid ECU IP address: {}", e),
+          power_mode: None,
+        }),
+      );
+    }
+  };
+  let local_binding = match state
+    .config
+    .local_binding
+    .with_overrides(request.local_ip.as_deref(), request.interface.as_deref())
+    .await
+  {
+    Ok(local_binding) => local_binding,
+    Err(e) => {
+      return (
+        StatusCode::BAD_REQUEST,
+        Json(PowerModeResult {
+          success: false,
+          message: format!("Invalid local binding: {}", e),
+          power_mode: None,
+        }),
+      );
+    }
+  };
+  let wait = Duration::from_millis(request.timeout_ms.unwrap_or(DEFAULT_DISCOVERY_TIMEOUT_MS));
+
+  let version = state.protocol_version(&request.ecu_ip).await;
+  match power_mode(ecu, &local_binding, version, wait).await {
+    Ok(mode) => (
+      StatusCode::OK,
+      Json(PowerModeResult {
+        success: true,
+        message: format!("Received power mode 0x{:02X}", mode),
+        power_mode: Some(power_mode_name(mode).to_string()),
+      }),
+    ),
+    Err(e) => (
+      StatusCode::BAD_REQUEST,
+      Json(PowerModeResult {
+        success: false,
+        message: format!("Power mode request failed: {}", e),
+        power_mode: None,
+      }),
+    ),
+  }
+}
+
+// POST /alive_check - Confirm a pooled connection is still served by the DoIP entity
+pub async fn alive_check(
+  State(state): State<AppState>,
+  Json(request): Json<ConnectRequest>,
+) -> (StatusCode, Json<AliveCheckResult>) {
+  info!(
+    "Alive check request: ECU={}, Source={}",
+    request.ecu_ip, request.doip_source_address
+  );
+
+  let connection_key = format!("{}:{}", request.ecu_ip, request.doip_source_address);
+  let Some(connection) = state.connection(&connection_key).await else {
+    return (
+      StatusCode::BAD_REQUEST,
+      Json(AliveCheckResult {
+        success: false,
+        message: "Connection not found".to_string(),
+        doip_entity_address: None,
+      }),
+    );
+  };
+
+  match connection.handle.alive_check().await {
+    Ok(entity_address) => (
+      StatusCode::OK,
+      Json(AliveCheckResult {
+        success: true,
+        message: "DoIP entity answered the alive check".to_string(),
+        doip_entity_address: Some(format!("0x{:04X}", entity_address)),
+      }),
+    ),
+    Err(RequestError::QueueFull) => (
+      StatusCode::SERVICE_UNAVAILABLE,
+      Json(AliveCheckResult {
+        success: false,
+        message: format!("Alive check failed: {}", RequestError::QueueFull),
+        doip_entity_address: None,
+      }),
+    ),
+    Err(e) => (
+      StatusCode::BAD_REQUEST,
+      Json(AliveCheckResult {
+        success: false,
+        message: format!("Alive check failed: {}", e),
+        doip_entity_address: None,
+      }),
+    ),
+  }
+}
+
+// POST /disconnect - Close a pooled connection
+pub async fn disconnect(
+  State(state): State<AppState>,
+  Json(request): Json<ConnectRequest>,
+) -> (StatusCode, Json<DisconnectResponse>) {
+  info!(
+    "Disconnect request: ECU={}, Source={}",
+    request.ecu_ip, request.doip_source_address
+  );
+
+  let connection_id = format!("{}:{}", request.ecu_ip, request.doip_source_address);
+  let connection = state.connections.lock().await.remove(&connection_id);
+  let Some(connection) = connection else {
+    return (
+      StatusCode::BAD_REQUEST,
+      Json(DisconnectResponse {
+        success: false,
+        message: "Connection not found".to_string(),
+        connection_id: None,
+      }),
+    );
+  };
+
+  connection.handle.shutdown().await;
+  (
+    StatusCode::OK,
+    Json(DisconnectResponse {
+      success: true,
+      message: "Disconnected from ECU".to_string(),
+      connection_id: Some(connection_id),
+    }),
+  )
+}
+
+// Close and remove connections that were idle longer than their timeout or that are
+// down and won't reconnect
+pub async fn reap_connections(state: &AppState) {
+  let expired: Vec<(String, Connection, &str)> = {
+    let mut connections = state.connections.lock().await;
+    let expired_ids: Vec<(String, &str)> = connections
+      .iter()
+      .filter_map(|(connection_id, connection)| {
+        let client = connection.handle.client();
+        if client.state() == ConnectionState::Disconnected {
+          Some((connection_id.clone(), "connection lost"))
+        } else if !connection.handle.is_busy()
+          && connection
+            .idle_timeout
+            .is_some_and(|idle_timeout| client.idle_time() >= idle_timeout)
+        {
+          Some((connection_id.clone(), "idle timeout"))
+        } else {
+          None
+        }
+      })
+      .collect();
+    expired_ids
+      .into_iter()
+      .filter_map(|(connection_id, reason)| {
+        let connection = connections.remove(&connection_id)?;
+        Some((connection_id, connection, reason))
+      })
+      .collect()
+  };
+
+  for (connection_id, connection, reason) in expired {
+    info!("Removing connection {}: {}", connection_id, reason);
+    connection.handle.shutdown().await;
+  }
+}
+
+// Create the router
+pub fn create_router(state: AppState) -> Router {
   Router::new()
     .route("/status", post(get_status))
     .route("/connect", post(connect))
+    .route("/disconnect", post(disconnect))
     .route("/diagnostic", post(diagnostic_handler))
+    .route("/security_access", post(security_access))
+    .route("/tester_present", post(tester_present))
+    .route("/discover", post(discover))
+    .route("/alive_check", post(alive_check))
+    .route("/entity_status", post(get_entity_status))
+    .route("/power_mode", post(get_power_mode))
     .with_state(state)
 }
 
 // Main server function
-pub async fn run_server(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_server(port: u16, config: ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
   init_logger();
 
-  let app = create_router();
   let addr = format!("0.0.0.0:{}", port);
 
   info!("DoIP2HTTP server starting on {}", addr);
+  if config.local_binding != LocalBinding::default() {
+    info!("DoIP traffic bound to {:?}", config.local_binding);
+  }
+  if let Some(idle_timeout) = config.idle_timeout {
+    info!("Closing connections idle for {:?}", idle_timeout);
+  }
+  if !config.key_algorithms.is_empty() {
+    info!(
+      "Seed/key algorithms configured for {} ECU levels",
+      config.key_algorithms.len()
+    );
+  }
+  let state = AppState::with_config(config);
+
+  let reaper_state = state.clone();
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(REAPER_INTERVAL_SECS));
+    loop {
+      interval.tick().await;
+      reap_connections(&reaper_state).await;
+    }
+  });
+
+  let app = create_router(state);
   info!("Available endpoints:");
   info!("  GET  /status     - Get connection status");
   info!("  POST /connect    - Connect to ECU (ecu_ip, source_address)");
+  info!("  POST /disconnect - Close a connection (ecu_ip, source_address)");
   info!("  POST /diagnostic - Send diagnostic message (target_address, uds_data)");
+  info!("  POST /security_access - Unlock a security level (target_address, level)");
+  info!("  POST /tester_present - Start or stop the TesterPresent keep-alive (enabled)");
+  info!("  POST /discover   - Discover DoIP entities via UDP (target_ip, vin, eid)");
+  info!("  POST /alive_check - Check a pooled connection is alive (ecu_ip, source_address)");
+  info!("  POST /entity_status - Query DoIP entity status via UDP (ecu_ip)");
+  info!("  POST /power_mode - Query diagnostic power mode via UDP (ecu_ip)");
 
   let listener = TcpListener::bind(&addr).await?;
   axum::serve(listener, app).await?;
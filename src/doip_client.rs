@@ -1,155 +1,863 @@
+use bytes::BytesMut;
 use log::{error, info, warn};
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::codec::Decoder;
 
-static PROTO_VERSION: u8 = 0x02;
-static INVER_PROTO_VERSION: u8 = 0xfd;
+use crate::common::net::{LocalBinding, host_name, resolve_address};
+use crate::doip_codec::{
+  DoipCodec, DoipCodecError, NACK_UNKNOWN_PAYLOAD_TYPE, generic_nack_reason, nack_closes_socket,
+};
+use crate::doip_discovery::{DOIP_UDP_DISCOVERY_PORT, detect_protocol_version};
+use crate::doip_message::{
+  DEFAULT_PROTOCOL_VERSION, DiagnosticMessage, DoipMessage, DoipPayload, RoutingActivationRequest,
+  RoutingActivationResponse, is_supported_protocol_version,
+};
+use crate::doip_tls::{DOIP_TLS_PORT, DoipTlsConfig, connect_tls};
 
 pub static DEFAULT_ACTIVATION_TYPE: u8 = 0x00;
-pub static DEFAULT_ACTIVATION_RESERVED: u32 = 0x00;
+pub static ROUTING_ACTIVATION_CONFIRMATION_REQUIRED: u8 = 0x11;
+// How long to wait for the final response after 0x11 "confirmation required"
+pub static DEFAULT_ACTIVATION_CONFIRMATION_TIMEOUT_SECS: u64 = 30;
 
 // Default connection timeout in seconds
 pub static DEFAULT_CONNECTION_TIMEOUT_SECS: u64 = 5;
 // Default read/write timeout in seconds
 pub static DEFAULT_IO_TIMEOUT_SECS: u64 = 10;
+// Port of unsecured diagnostic connections
+pub static DOIP_TCP_PORT: u16 = 13400;
+// How long to wait for the UDP vehicle announcement when auto-detecting the version
+pub static VERSION_DETECTION_TIMEOUT_MS: u64 = 500;
+// Default largest payload accepted from an entity, bigger frames are discarded
+pub static DEFAULT_MAX_PAYLOAD_SIZE: u32 = 0x0010_0000;
 
-#[repr(u16)]
-pub enum VehicleConnectionPayloadType {
-  GenericNegativeAck = 0x0000,
-  VehicleIdRequest = 0x0001,
-  VehicleIdRequestWithEID = 0x0002,
-  VehicleIdRequestWithVIN = 0x0003,
-  VehicleAnnouncement = 0x0004,
-  RoutingActivationRequest = 0x0005,
-  RoutingActivationResponse = 0x0006,
-  AliveCheckRequest = 0x0007,
-  AliveCheckResponse = 0x0008,
+// Bytes requested from the socket per read, frames may span several reads
+static READ_BUFFER_SIZE: usize = 4096;
+
+// Errors raised while exchanging DoIP messages with an entity
+#[derive(Debug)]
+pub enum DoipError {
+  Io(Error),
+  // DiagnosticNegativeAck (0x8003) with the NACK code sent by the entity
+  DiagnosticNack(u8),
+  // RoutingActivationResponse (0x0006) with a code other than "successfully activated"
+  RoutingActivationDenied(u8),
+  // GenericNegativeAck (0x0000): the entity rejected the header of our message
+  GenericNack(u8),
+}
+
+impl DoipError {
+  pub fn diagnostic_nack_reason(code: u8) -> &'static str {
+    match code {
+      0x02 => "invalid source address",
+      0x03 => "unknown target address",
+      0x04 => "diagnostic message too large",
+      0x05 => "out of memory",
+      0x06 => "target unreachable",
+      0x07 => "unknown network",
+      0x08 => "transport protocol error",
+      _ => "reserved",
+    }
+  }
+}
+
+impl fmt::Display for DoipError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DoipError::Io(e) => write!(f, "{}", e),
+      DoipError::DiagnosticNack(code) => write!(
+        f,
+        "Diagnostic message rejected with NACK 0x{:02X} ({})",
+        code,
+        DoipError::diagnostic_nack_reason(*code)
+      ),
+      DoipError::RoutingActivationDenied(code) => write!(
+        f,
+        "Routing activation denied with code 0x{:02X} ({})",
+        code,
+        RoutingActivationResponse::reason(*code)
+      ),
+      DoipError::GenericNack(code) => write!(
+        f,
+        "Message rejected with generic NACK 0x{:02X} ({})",
+        code,
+        generic_nack_reason(*code)
+      ),
+    }
+  }
+}
+
+impl std::error::Error for DoipError {}
+
+impl From<Error> for DoipError {
+  fn from(e: Error) -> Self {
+    DoipError::Io(e)
+  }
+}
+
+// RoutingActivationRequest (0x0005) parameters
+#[derive(Debug, Clone)]
+pub struct RoutingActivation {
+  // 0x00 default, 0x01 WWH-OBD, 0xE0 central security, 0xE1-0xFF OEM specific
+  pub activation_type: u8,
+  // Optional OEM specific part appended after the ISO reserved bytes
+  pub oem_data: Option<[u8; 4]>,
+}
+
+impl Default for RoutingActivation {
+  fn default() -> Self {
+    Self {
+      activation_type: DEFAULT_ACTIVATION_TYPE,
+      oem_data: None,
+    }
+  }
 }
 
-#[repr(u16)]
-enum EntityStatusPayloadType {
-  EntityStatusRequest = 0x4001,
-  EntityStatusResponse = 0x4002,
-  PowerModeInfoRequest = 0x4003,
-  PowerModeInfoResponse = 0x4004,
+impl RoutingActivation {
+  pub fn request(&self, source_address: u16) -> RoutingActivationRequest {
+    RoutingActivationRequest {
+      source_address,
+      activation_type: self.activation_type,
+      oem_data: self.oem_data,
+    }
+  }
+}
+
+// Per-connection DoIP transport settings
+#[derive(Debug, Clone)]
+pub struct DoipConfig {
+  // Largest payload accepted from the entity, answered with NACK 0x02 otherwise
+  pub max_payload_size: u32,
+  // Connect to the TLS port 3496 instead of plain TCP when set
+  pub tls: Option<DoipTlsConfig>,
+  // Generic header protocol version sent and expected on the connection
+  pub protocol_version: u8,
+  // Take the protocol version from the entity's vehicle announcement or first
+  // response instead, `protocol_version` is only used until then
+  pub auto_detect_version: bool,
+  pub routing_activation: RoutingActivation,
+  // Local address and interface the TCP connection and UDP requests are bound to
+  pub local_binding: LocalBinding,
+}
+
+impl Default for DoipConfig {
+  fn default() -> Self {
+    Self {
+      max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+      tls: None,
+      protocol_version: DEFAULT_PROTOCOL_VERSION,
+      auto_detect_version: false,
+      routing_activation: RoutingActivation::default(),
+      local_binding: LocalBinding::default(),
+    }
+  }
+}
+
+// Plain TCP or TLS stream to the DoIP entity
+trait DoipStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> DoipStream for T {}
+
+type DoipReader = ReadHalf<Box<dyn DoipStream>>;
+type DoipWriter = WriteHalf<Box<dyn DoipStream>>;
+
+// Hands the frames read from the socket to their waiting requests: diagnostic frames
+// by the logical address of the ECU that sent them, everything else on the control
+// channel. A gateway serves many target ECUs over one socket this way. Both are None
+// once the socket is closed.
+struct FrameRouter {
+  control: std::sync::Mutex<Option<mpsc::UnboundedSender<DoipPayload>>>,
+  targets: std::sync::Mutex<Option<HashMap<u16, mpsc::UnboundedSender<DoipPayload>>>>,
+}
+
+impl FrameRouter {
+  // Returns false once the client is gone
+  fn route(&self, payload: DoipPayload) -> bool {
+    let ecu_address = match &payload {
+      DoipPayload::DiagnosticMessage(message) => Some(message.source_address),
+      DoipPayload::DiagnosticPositiveAck(ack) | DoipPayload::DiagnosticNegativeAck(ack) => {
+        Some(ack.source_address)
+      }
+      _ => None,
+    };
+
+    if let Some(ecu_address) = ecu_address {
+      match self
+        .targets
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|targets| targets.get(&ecu_address))
+      {
+        Some(target) => {
+          let _ = target.send(payload);
+        }
+        None => warn!(
+          "DoipClient: Dropping payload type 0x{:04X} from unknown target 0x{:04X}",
+          payload.payload_type(),
+          ecu_address
+        ),
+      }
+      return true;
+    }
+
+    if let DoipPayload::GenericNack(_) = payload {
+      // The NACK does not say which message it rejects, every waiting request sees it
+      for target in self
+        .targets
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|targets| targets.values())
+      {
+        let _ = target.send(payload.clone());
+      }
+    }
+    match self.control.lock().unwrap().as_ref() {
+      Some(control) => control.send(payload).is_ok(),
+      None => false,
+    }
+  }
+
+  // Receive the frames of `target_address` on `sender`. After close the sender is
+  // dropped, so the channel reports the closed connection right away.
+  fn register(&self, target_address: u16, sender: mpsc::UnboundedSender<DoipPayload>) {
+    if let Some(targets) = self.targets.lock().unwrap().as_mut() {
+      targets.insert(target_address, sender);
+    }
+  }
+
+  // Socket closed: wake up every request waiting for a target or a control frame
+  fn close(&self) {
+    self.targets.lock().unwrap().take();
+    self.control.lock().unwrap().take();
+  }
 }
 
-#[repr(u16)]
-pub enum DiagnosticPayloadType {
-  DiagnosticMessage = 0x8001,
-  DiagnosticPositiveAck = 0x8002,
-  DiagnosticNegativeAck = 0x8003,
+// Diagnostic frames received from one target ECU. Lock it for a whole request and
+// response exchange, a tester has at most one request outstanding per ECU.
+pub struct TargetChannel {
+  target_address: u16,
+  frames: mpsc::UnboundedReceiver<DoipPayload>,
+  // Diagnostic response that arrived in place of the ACK
+  early_response: Option<Vec<u8>>,
 }
 
 pub struct DoipClient {
-  stream: Option<TcpStream>,
-  connected: bool,
+  writer: Arc<Mutex<DoipWriter>>,
+  router: Arc<FrameRouter>,
+  // Frames that are not diagnostic messages, e.g. routing activation and alive check
+  // responses
+  control: Mutex<mpsc::UnboundedReceiver<DoipPayload>>,
+  targets: std::sync::Mutex<HashMap<u16, Arc<Mutex<TargetChannel>>>>,
+  reader: JoinHandle<()>,
+  // Cleared once the socket is closed or the entity stops answering
+  connected: Arc<watch::Sender<bool>>,
+  // Protocol version sent, follows the entity until the version is negotiated
+  version: Arc<AtomicU8>,
+  source_address: u16,
+  routing_activation: RoutingActivation,
 }
 
-fn encode_doip_message(payload_type: u16, uds_msg: Option<&[u8]>) -> Vec<u8> {
-  let mut message = Vec::new();
+// Payload types a tester handles on a TCP_DATA socket, others are answered with a
+// generic NACK
+fn tester_handles(payload: &DoipPayload) -> bool {
+  matches!(
+    payload,
+    DoipPayload::GenericNack(_)
+      | DoipPayload::RoutingActivationResponse(_)
+      | DoipPayload::AliveCheckRequest
+      | DoipPayload::AliveCheckResponse(_)
+      | DoipPayload::DiagnosticMessage(_)
+      | DoipPayload::DiagnosticPositiveAck(_)
+      | DoipPayload::DiagnosticNegativeAck(_)
+  )
+}
+
+// Encode and send one message on the shared write half
+async fn write_message(
+  writer: &Mutex<DoipWriter>,
+  version: u8,
+  payload: DoipPayload,
+) -> Result<Vec<u8>, Error> {
+  let message = DoipMessage::new(version, payload).encode();
+  let mut writer = writer.lock().await;
+  with_io_timeout(writer.write_all(&message)).await?; // send the message completely
+  Ok(message)
+}
+
+// Background task owning the read half of the socket. Alive check requests from the
+// entity are answered here so they are never mistaken for responses, every other
+// frame is routed to the request waiting for it.
+async fn read_loop(
+  mut reader: DoipReader,
+  writer: Arc<Mutex<DoipWriter>>,
+  source_address: u16,
+  mut codec: DoipCodec,
+  version: Arc<AtomicU8>,
+  router: Arc<FrameRouter>,
+  connected: Arc<watch::Sender<bool>>,
+) {
+  let mut buffer = BytesMut::with_capacity(READ_BUFFER_SIZE);
+  loop {
+    let message = match codec.decode(&mut buffer) {
+      Ok(Some(message)) => message,
+      Ok(None) => match reader.read_buf(&mut buffer).await {
+        Ok(0) => {
+          warn!("DoipClient: Connection closed by DoIP entity");
+          break;
+        }
+        Ok(_) => continue,
+        Err(e) => {
+          error!("DoipClient: Failed to read from DoIP entity: {}", e);
+          break;
+        }
+      },
+      Err(DoipCodecError::Nack(code)) => {
+        warn!(
+          "DoipClient: Rejecting frame from DoIP entity: {}",
+          generic_nack_reason(code)
+        );
+        let payload = DoipPayload::GenericNack(code);
+        match write_message(&writer, version.load(Ordering::SeqCst), payload).await {
+          Ok(message) => info!("DoipClient: sent generic NACK: {:x?}", message),
+          Err(e) => {
+            error!("DoipClient: Failed to send generic NACK: {}", e);
+            break;
+          }
+        }
+        if nack_closes_socket(code) {
+          let _ = writer.lock().await.shutdown().await;
+          break;
+        }
+        continue;
+      }
+      Err(DoipCodecError::Io(e)) => {
+        error!("DoipClient: Failed to read from DoIP entity: {}", e);
+        break;
+      }
+    };
+    info!("DoipClient: received {:x?}", message);
+
+    if version.swap(message.version, Ordering::SeqCst) != message.version {
+      info!(
+        "DoipClient: Negotiated DoIP protocol version 0x{:02X}",
+        message.version
+      );
+    }
 
-  message.push(PROTO_VERSION);
-  message.push(INVER_PROTO_VERSION);
-  message.push((payload_type >> 8) as u8);
-  message.push((payload_type & 0xFF) as u8);
+    if !tester_handles(&message.payload) {
+      let payload = DoipPayload::GenericNack(NACK_UNKNOWN_PAYLOAD_TYPE);
+      if let Err(e) = write_message(&writer, message.version, payload).await {
+        error!("DoipClient: Failed to send generic NACK: {}", e);
+        break;
+      }
+      continue;
+    }
 
-  let payload = uds_msg.unwrap_or(&[]);
-  let payload_len = payload.len() as u32;
-  message.extend_from_slice(&payload_len.to_be_bytes());
+    if message.payload == DoipPayload::AliveCheckRequest {
+      let payload = DoipPayload::AliveCheckResponse(source_address);
+      match write_message(&writer, message.version, payload).await {
+        Ok(message) => info!("DoipClient: answered alive check request: {:x?}", message),
+        Err(e) => {
+          error!("DoipClient: Failed to answer alive check request: {}", e);
+          break;
+        }
+      }
+      continue;
+    }
 
-  message.extend_from_slice(payload);
+    if !router.route(message.payload) {
+      // The client has been dropped
+      break;
+    }
+  }
 
-  message
+  connected.send_replace(false);
+  router.close();
+}
+
+// Next frame routed to `frames`, NotConnected once the socket is closed. A generic
+// NACK from the entity is returned as an error.
+async fn next_frame(
+  frames: &mut mpsc::UnboundedReceiver<DoipPayload>,
+) -> Result<DoipPayload, DoipError> {
+  let payload = frames.recv().await.ok_or_else(|| {
+    Error::new(
+      ErrorKind::NotConnected,
+      "Connection to DoIP entity was closed",
+    )
+  })?;
+
+  if let DoipPayload::GenericNack(code) = payload {
+    warn!(
+      "DoipClient: Generic NACK 0x{:02X} ({}) from DoIP entity",
+      code,
+      generic_nack_reason(code)
+    );
+    return Err(DoipError::GenericNack(code));
+  }
+
+  Ok(payload)
+}
+
+// Wait up to `wait` for the next frame routed to `frames`
+async fn next_frame_within(
+  frames: &mut mpsc::UnboundedReceiver<DoipPayload>,
+  wait: Duration,
+) -> Result<DoipPayload, DoipError> {
+  match timeout(wait, next_frame(frames)).await {
+    Ok(result) => result,
+    Err(_) => Err(DoipError::Io(Error::new(
+      ErrorKind::TimedOut,
+      format!("No data from ECU within {}ms", wait.as_millis()),
+    ))),
+  }
+}
+
+// Drop frames still queued from an earlier exchange
+fn drain_stale(frames: &mut mpsc::UnboundedReceiver<DoipPayload>) {
+  while let Ok(stale) = frames.try_recv() {
+    warn!(
+      "DoipClient: Dropping stale payload type 0x{:04X}",
+      stale.payload_type()
+    );
+  }
+}
+
+// Run a socket operation, failing with TimedOut once the I/O timeout elapses
+async fn with_io_timeout<T>(operation: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+  match timeout(Duration::from_secs(DEFAULT_IO_TIMEOUT_SECS), operation).await {
+    Ok(result) => result,
+    Err(_) => Err(Error::new(
+      ErrorKind::TimedOut,
+      format!("No data from ECU within {}s", DEFAULT_IO_TIMEOUT_SECS),
+    )),
+  }
 }
 
 impl DoipClient {
-  pub fn new(ecu_ip: String) -> Self {
-    let address = format!("{}:13400", ecu_ip);
+  pub async fn new(ecu_ip: String, source_address: u16, config: DoipConfig) -> Result<Self, Error> {
+    let port = if config.tls.is_some() {
+      DOIP_TLS_PORT
+    } else {
+      DOIP_TCP_PORT
+    };
+    let address = resolve_address(&ecu_ip, port)
+      .await
+      .inspect_err(|e| error!("DoipClient: Invalid ECU address {}: {}", ecu_ip, e))?;
     info!(
       "DoipClient: Attempting to connect to {} with timeout {:?}",
       address, DEFAULT_CONNECTION_TIMEOUT_SECS
     );
+    if config.local_binding != LocalBinding::default() {
+      info!(
+        "DoipClient: Binding connection to {:?}",
+        config.local_binding
+      );
+    }
 
-    match address.parse::<SocketAddr>() {
-      Ok(socket_addr) => {
-        match TcpStream::connect_timeout(
-          &socket_addr,
+    let stream = match timeout(
+      Duration::from_secs(DEFAULT_CONNECTION_TIMEOUT_SECS),
+      config.local_binding.connect(address),
+    )
+    .await
+    {
+      Ok(Ok(stream)) => stream,
+      Ok(Err(e)) => {
+        error!("DoipClient: Failed to connect to {}: {}", address, e);
+        return Err(e);
+      }
+      Err(_) => {
+        error!(
+          "DoipClient: Timed out connecting to {} after {}s",
+          address, DEFAULT_CONNECTION_TIMEOUT_SECS
+        );
+        return Err(Error::new(
+          ErrorKind::TimedOut,
+          format!(
+            "Timed out connecting to {} after {}s",
+            address, DEFAULT_CONNECTION_TIMEOUT_SECS
+          ),
+        ));
+      }
+    };
+
+    info!("DoipClient: Successfully connected to {}", address);
+    // DoIP frames are small request/response exchanges, don't let Nagle delay them
+    if let Err(e) = stream.set_nodelay(true) {
+      warn!("DoipClient: Failed to set TCP_NODELAY: {}", e);
+    }
+
+    let stream: Box<dyn DoipStream> = match &config.tls {
+      Some(tls) => {
+        let handshake = timeout(
           Duration::from_secs(DEFAULT_CONNECTION_TIMEOUT_SECS),
-        ) {
-          Ok(stream) => {
-            info!("DoipClient: Successfully connected to {}", address);
-            // Set read and write timeouts for the stream
-            if let Err(e) =
-              stream.set_read_timeout(Some(Duration::from_secs(DEFAULT_IO_TIMEOUT_SECS)))
-            {
-              warn!("DoipClient: Failed to set read timeout: {}", e);
-            }
-            if let Err(e) =
-              stream.set_write_timeout(Some(Duration::from_secs(DEFAULT_IO_TIMEOUT_SECS)))
-            {
-              warn!("DoipClient: Failed to set write timeout: {}", e);
-            }
-
-            Self {
-              stream: Some(stream),
-              connected: true,
-            }
+          connect_tls(stream, host_name(&ecu_ip), tls),
+        );
+        match handshake.await {
+          Ok(Ok(stream)) => Box::new(stream),
+          Ok(Err(e)) => {
+            error!("DoipClient: TLS handshake with {} failed: {}", address, e);
+            return Err(e);
           }
-          Err(e) => {
-            error!("DoipClient: Failed to connect to {}: {}", address, e);
-            Self {
-              stream: None,
-              connected: false,
-            }
+          Err(_) => {
+            error!(
+              "DoipClient: Timed out during TLS handshake with {}",
+              address
+            );
+            return Err(Error::new(
+              ErrorKind::TimedOut,
+              format!("Timed out during TLS handshake with {}", address),
+            ));
           }
         }
       }
-      Err(e) => {
-        error!("DoipClient: Invalid address format {}: {}", address, e);
-        Self {
-          stream: None,
-          connected: false,
+      None => Box::new(stream),
+    };
+
+    let mut protocol_version = config.protocol_version;
+    let mut negotiated = !config.auto_detect_version;
+    if config.auto_detect_version {
+      let mut udp_address = address;
+      udp_address.set_port(DOIP_UDP_DISCOVERY_PORT);
+      match detect_protocol_version(
+        udp_address,
+        &config.local_binding,
+        Duration::from_millis(VERSION_DETECTION_TIMEOUT_MS),
+      )
+      .await
+      {
+        Ok(version) if is_supported_protocol_version(version) => {
+          info!(
+            "DoipClient: {} announced DoIP protocol version 0x{:02X}",
+            ecu_ip, version
+          );
+          protocol_version = version;
+          negotiated = true;
         }
+        Ok(version) => warn!(
+          "DoipClient: {} announced unsupported protocol version 0x{:02X}",
+          ecu_ip, version
+        ),
+        Err(e) => info!(
+          "DoipClient: No vehicle announcement from {} ({}), using the version of the first response",
+          ecu_ip, e
+        ),
       }
     }
+    let version = Arc::new(AtomicU8::new(protocol_version));
+    let codec = if negotiated {
+      DoipCodec::with_version(protocol_version, config.max_payload_size)
+    } else {
+      DoipCodec::new(config.max_payload_size)
+    };
+
+    let (reader, writer) = tokio::io::split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    let connected = Arc::new(watch::Sender::new(true));
+    let (control_sender, control) = mpsc::unbounded_channel();
+    let router = Arc::new(FrameRouter {
+      control: std::sync::Mutex::new(Some(control_sender)),
+      targets: std::sync::Mutex::new(Some(HashMap::new())),
+    });
+    let reader = tokio::spawn(read_loop(
+      reader,
+      writer.clone(),
+      source_address,
+      codec,
+      version.clone(),
+      router.clone(),
+      connected.clone(),
+    ));
+
+    Ok(Self {
+      writer,
+      router,
+      control: Mutex::new(control),
+      targets: std::sync::Mutex::new(HashMap::new()),
+      reader,
+      connected,
+      version,
+      source_address,
+      routing_activation: config.routing_activation,
+    })
   }
 
   pub fn is_connected(&self) -> bool {
-    self.connected
+    *self.connected.borrow()
   }
 
-  pub fn send_and_receive(
-    &mut self,
-    payload_type: u16,
-    uds_data: Option<&[u8]>,
-  ) -> Result<Vec<u8>, std::io::Error> {
-    if let Some(stream) = self.stream.as_mut() {
-      let message = encode_doip_message(payload_type, uds_data);
-      stream.write_all(&message)?; // send the message completely
+  // Resolves once the connection is lost: socket closed or reset by the entity, a
+  // failed write or an unanswered alive check
+  pub async fn closed(&self) {
+    let _ = self
+      .connected
+      .subscribe()
+      .wait_for(|connected| !connected)
+      .await;
+  }
 
-      info!("DoipClient: sent raw data: {:x?}", message);
+  // Close the socket towards the entity and stop reading from it
+  pub async fn shutdown(&self) {
+    self.connected.send_replace(false);
+    self.reader.abort();
+    if let Err(e) = self.writer.lock().await.shutdown().await {
+      warn!("DoipClient: Failed to shut down the connection: {}", e);
+    }
+    info!("DoipClient: Connection closed");
+  }
 
-      let mut header = [0u8; 8]; // DoIP general header is 8 bytes
-      stream.read_exact(&mut header)?; // read header fully
+  // Protocol version currently used on the connection
+  pub fn protocol_version(&self) -> u8 {
+    self.version.load(Ordering::SeqCst)
+  }
 
-      // Parse header: protocol version, inverse version, payload type, payload length
-      let payload_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
-      let mut payload = vec![0u8; payload_len as usize];
-      stream.read_exact(&mut payload)?; // read the payload fully
+  pub async fn send_message(&self, payload: DoipPayload) -> Result<(), Error> {
+    if !self.is_connected() {
+      return Err(Error::new(ErrorKind::NotConnected, "Not connected to ECU"));
+    }
 
-      let mut raw = Vec::with_capacity(8 + payload.len());
-      raw.extend_from_slice(&header);
-      raw.extend_from_slice(&payload);
-      info!("DoipClient: received raw data: {:x?}", raw);
+    let message = write_message(&self.writer, self.protocol_version(), payload)
+      .await
+      .inspect_err(|e| {
+        if matches!(
+          e.kind(),
+          ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+        ) {
+          error!("DoipClient: Connection to DoIP entity lost: {}", e);
+          self.connected.send_replace(false);
+        }
+      })?;
+    info!("DoipClient: sent raw data: {:x?}", message);
+    Ok(())
+  }
 
-      Ok(payload) // return the response payload
-    } else {
-      Err(std::io::Error::new(
-        std::io::ErrorKind::NotConnected,
-        "Not connected to ECU",
-      ))
+  // Wait for the next RoutingActivationResponse on the control channel
+  async fn receive_routing_activation(
+    &self,
+    control: &mut mpsc::UnboundedReceiver<DoipPayload>,
+    wait: Duration,
+  ) -> Result<RoutingActivationResponse, DoipError> {
+    let response = match next_frame_within(control, wait).await? {
+      DoipPayload::RoutingActivationResponse(response) => response,
+      other => {
+        error!(
+          "DoipClient: Expected routing activation response, got payload type 0x{:04X}",
+          other.payload_type()
+        );
+        return Err(
+          Error::new(
+            ErrorKind::InvalidData,
+            format!(
+              "Expected routing activation response, got payload type 0x{:04X}",
+              other.payload_type()
+            ),
+          )
+          .into(),
+        );
+      }
+    };
+    info!(
+      "DoipClient: Routing activation response from entity 0x{:04X}: code 0x{:02X} ({}), OEM data {:?}",
+      response.entity_address,
+      response.response_code,
+      RoutingActivationResponse::reason(response.response_code),
+      response.oem_data
+    );
+    if response.tester_address != self.source_address {
+      warn!(
+        "DoipClient: Routing activation response is for tester 0x{:04X}, expected 0x{:04X}",
+        response.tester_address, self.source_address
+      );
     }
+    Ok(response)
+  }
+
+  // Activate routing for our source address. When the entity requires confirmation
+  // (0x11) the final response is awaited, any code but 0x10 is returned as an error.
+  pub async fn activate_routing(&self) -> Result<RoutingActivationResponse, DoipError> {
+    let mut control = self.control.lock().await;
+    drain_stale(&mut control);
+
+    let request = self.routing_activation.request(self.source_address);
+    self
+      .send_message(DoipPayload::RoutingActivationRequest(request))
+      .await?;
+
+    let mut response = self
+      .receive_routing_activation(&mut control, Duration::from_secs(DEFAULT_IO_TIMEOUT_SECS))
+      .await?;
+    if response.response_code == ROUTING_ACTIVATION_CONFIRMATION_REQUIRED {
+      info!(
+        "DoipClient: Routing activation needs confirmation, waiting up to {}s",
+        DEFAULT_ACTIVATION_CONFIRMATION_TIMEOUT_SECS
+      );
+      response = self
+        .receive_routing_activation(
+          &mut control,
+          Duration::from_secs(DEFAULT_ACTIVATION_CONFIRMATION_TIMEOUT_SECS),
+        )
+        .await?;
+    }
+
+    if !response.is_success() {
+      return Err(DoipError::RoutingActivationDenied(response.response_code));
+    }
+    Ok(response)
+  }
+
+  // Send an alive check request and wait for the entity's response, confirming the
+  // connection is still usable. Returns the logical address of the entity.
+  pub async fn alive_check(&self) -> Result<u16, DoipError> {
+    let mut control = self.control.lock().await;
+    drain_stale(&mut control);
+
+    self.send_message(DoipPayload::AliveCheckRequest).await?;
+
+    loop {
+      let wait = Duration::from_secs(DEFAULT_IO_TIMEOUT_SECS);
+      let payload = match next_frame_within(&mut control, wait).await {
+        Ok(frame) => frame,
+        Err(DoipError::Io(e)) if e.kind() == ErrorKind::TimedOut => {
+          // An entity that doesn't answer alive checks is treated as gone
+          self.connected.send_replace(false);
+          return Err(DoipError::Io(e));
+        }
+        Err(e) => return Err(e),
+      };
+
+      if let DoipPayload::AliveCheckResponse(address) = payload {
+        return Ok(address);
+      }
+      warn!(
+        "DoipClient: Ignoring payload type 0x{:04X} while waiting for alive check response",
+        payload.payload_type()
+      );
+    }
+  }
+
+  // Channel receiving the diagnostic frames sent by `target_address`, created on first use
+  pub fn target_channel(&self, target_address: u16) -> Arc<Mutex<TargetChannel>> {
+    let mut targets = self.targets.lock().unwrap();
+    targets
+      .entry(target_address)
+      .or_insert_with(|| {
+        let (sender, frames) = mpsc::unbounded_channel();
+        self.router.register(target_address, sender);
+        Arc::new(Mutex::new(TargetChannel {
+          target_address,
+          frames,
+          early_response: None,
+        }))
+      })
+      .clone()
+  }
+
+  // Send a DiagnosticMessage to the target of `channel` and wait for the entity's
+  // acknowledgement. A negative ACK is returned as an error.
+  pub async fn send_diagnostic(
+    &self,
+    channel: &mut TargetChannel,
+    message: DiagnosticMessage,
+  ) -> Result<(), DoipError> {
+    let source_address = message.source_address;
+    let target_address = message.target_address;
+    if target_address != channel.target_address {
+      return Err(DoipError::Io(Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+          "Diagnostic message for target 0x{:04X} sent on the channel of 0x{:04X}",
+          target_address, channel.target_address
+        ),
+      )));
+    }
+
+    channel.early_response = None;
+    drain_stale(&mut channel.frames);
+    self
+      .send_message(DoipPayload::DiagnosticMessage(message))
+      .await?;
+
+    loop {
+      let wait = Duration::from_secs(DEFAULT_IO_TIMEOUT_SECS);
+      let payload = next_frame_within(&mut channel.frames, wait).await?;
+
+      // Acknowledgements are addressed from the target ECU back to the tester
+      match payload {
+        DoipPayload::DiagnosticPositiveAck(ack) if ack.target_address == source_address => {
+          return Ok(());
+        }
+        DoipPayload::DiagnosticNegativeAck(ack) if ack.target_address == source_address => {
+          return Err(DoipError::DiagnosticNack(ack.code));
+        }
+        DoipPayload::DiagnosticMessage(response) if response.target_address == source_address => {
+          // Some entities skip the ACK, keep the response for receive_diagnostic
+          warn!("DoipClient: Diagnostic response received before ACK");
+          channel.early_response = Some(response.user_data);
+          return Ok(());
+        }
+        other => {
+          warn!(
+            "DoipClient: Ignoring payload type 0x{:04X} while waiting for diagnostic ACK",
+            other.payload_type()
+          );
+        }
+      }
+    }
+  }
+
+  // Wait up to `wait` for the next DiagnosticMessage sent by the target of `channel`
+  // to `source_address` and return its UDS data
+  pub async fn receive_diagnostic(
+    &self,
+    channel: &mut TargetChannel,
+    source_address: u16,
+    wait: Duration,
+  ) -> Result<Vec<u8>, DoipError> {
+    if let Some(response) = channel.early_response.take() {
+      return Ok(response);
+    }
+
+    let frames = &mut channel.frames;
+    let response = timeout(wait, async {
+      loop {
+        match next_frame(frames).await? {
+          DoipPayload::DiagnosticMessage(response) if response.target_address == source_address => {
+            return Ok::<_, DoipError>(response.user_data);
+          }
+          other => warn!(
+            "DoipClient: Ignoring payload type 0x{:04X} while waiting for diagnostic response",
+            other.payload_type()
+          ),
+        }
+      }
+    })
+    .await;
+
+    match response {
+      Ok(result) => result,
+      Err(_) => Err(DoipError::Io(Error::new(
+        ErrorKind::TimedOut,
+        format!(
+          "No diagnostic response from ECU within {}ms",
+          wait.as_millis()
+        ),
+      ))),
+    }
+  }
+}
+
+impl Drop for DoipClient {
+  fn drop(&mut self) {
+    self.reader.abort();
   }
 }
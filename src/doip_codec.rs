@@ -0,0 +1,180 @@
+use bytes::{Buf, BytesMut};
+use std::fmt;
+use std::io::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::doip_message::{
+  DoipMessage, DoipPayload, is_supported_protocol_version, valid_payload_length,
+};
+
+// Generic header: protocol version, inverse version, payload type and payload length
+pub static DOIP_HEADER_LEN: usize = 8;
+
+// GenericNegativeAck (0x0000) codes
+pub static NACK_INCORRECT_PATTERN_FORMAT: u8 = 0x00;
+pub static NACK_UNKNOWN_PAYLOAD_TYPE: u8 = 0x01;
+pub static NACK_MESSAGE_TOO_LARGE: u8 = 0x02;
+pub static NACK_OUT_OF_MEMORY: u8 = 0x03;
+pub static NACK_INVALID_PAYLOAD_LENGTH: u8 = 0x04;
+
+pub fn generic_nack_reason(code: u8) -> &'static str {
+  match code {
+    0x00 => "incorrect pattern format",
+    0x01 => "unknown payload type",
+    0x02 => "message too large",
+    0x03 => "out of memory",
+    0x04 => "invalid payload length",
+    _ => "reserved",
+  }
+}
+
+// Incorrect pattern and invalid length leave the stream unusable, the socket is closed
+pub fn nack_closes_socket(code: u8) -> bool {
+  code == NACK_INCORRECT_PATTERN_FORMAT || code == NACK_INVALID_PAYLOAD_LENGTH
+}
+
+#[derive(Debug)]
+pub enum DoipCodecError {
+  Io(Error),
+  // A received frame was rejected, the peer is answered with this generic NACK code.
+  // Decoding can go on unless the code closes the socket.
+  Nack(u8),
+}
+
+impl fmt::Display for DoipCodecError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DoipCodecError::Io(e) => write!(f, "{}", e),
+      DoipCodecError::Nack(code) => write!(
+        f,
+        "Frame rejected with generic NACK 0x{:02X} ({})",
+        code,
+        generic_nack_reason(*code)
+      ),
+    }
+  }
+}
+
+impl std::error::Error for DoipCodecError {}
+
+impl From<Error> for DoipCodecError {
+  fn from(e: Error) -> Self {
+    DoipCodecError::Io(e)
+  }
+}
+
+// Frames DoIP messages on a TCP stream. Frames may arrive split over several reads
+// or several in one read. The payload of a rejected frame is skipped so decoding can
+// continue with the next frame; FramedRead stops at the first error, decode from
+// your own buffer to keep going.
+#[derive(Debug, Clone)]
+pub struct DoipCodec {
+  max_payload_size: u32,
+  // Version every frame has to carry, taken from the first frame when None
+  version: Option<u8>,
+  // Payload bytes of a rejected frame still to be skipped
+  discard: usize,
+}
+
+impl DoipCodec {
+  // Accept any supported protocol version, the first one received is kept
+  pub fn new(max_payload_size: u32) -> Self {
+    Self {
+      max_payload_size,
+      version: None,
+      discard: 0,
+    }
+  }
+
+  // Only accept frames with `version`
+  pub fn with_version(version: u8, max_payload_size: u32) -> Self {
+    Self {
+      max_payload_size,
+      version: Some(version),
+      discard: 0,
+    }
+  }
+
+  // Protocol version expected from the peer, None until the first frame
+  pub fn version(&self) -> Option<u8> {
+    self.version
+  }
+
+  fn validate_header(&self, header: &[u8]) -> Result<(), u8> {
+    let version = header[0];
+    let version_accepted = match self.version {
+      Some(expected) => version == expected,
+      None => is_supported_protocol_version(version),
+    };
+    if header[1] != !version || !version_accepted {
+      return Err(NACK_INCORRECT_PATTERN_FORMAT);
+    }
+
+    let payload_type = u16::from_be_bytes([header[2], header[3]]);
+    let payload_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let Some(valid_length) = valid_payload_length(payload_type, payload_len) else {
+      return Err(NACK_UNKNOWN_PAYLOAD_TYPE);
+    };
+    if payload_len > self.max_payload_size {
+      return Err(NACK_MESSAGE_TOO_LARGE);
+    }
+    if !valid_length {
+      return Err(NACK_INVALID_PAYLOAD_LENGTH);
+    }
+    Ok(())
+  }
+}
+
+impl Decoder for DoipCodec {
+  type Item = DoipMessage;
+  type Error = DoipCodecError;
+
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<DoipMessage>, DoipCodecError> {
+    if self.discard > 0 {
+      let skipped = self.discard.min(src.len());
+      src.advance(skipped);
+      self.discard -= skipped;
+      if self.discard > 0 {
+        return Ok(None);
+      }
+    }
+
+    if src.len() < DOIP_HEADER_LEN {
+      return Ok(None);
+    }
+
+    let header = &src[..DOIP_HEADER_LEN];
+    let version = header[0];
+    let payload_type = u16::from_be_bytes([header[2], header[3]]);
+    let payload_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+    if let Err(code) = self.validate_header(header) {
+      src.advance(DOIP_HEADER_LEN);
+      if !nack_closes_socket(code) {
+        self.discard = payload_len;
+      }
+      return Err(DoipCodecError::Nack(code));
+    }
+    self.version = Some(version);
+
+    let frame_len = DOIP_HEADER_LEN + payload_len;
+    if src.len() < frame_len {
+      src.reserve(frame_len - src.len());
+      return Ok(None);
+    }
+
+    let frame = src.split_to(frame_len);
+    let payload = DoipPayload::parse(payload_type, &frame[DOIP_HEADER_LEN..])
+      .ok_or(DoipCodecError::Nack(NACK_INVALID_PAYLOAD_LENGTH))?;
+    Ok(Some(DoipMessage::new(version, payload)))
+  }
+}
+
+impl Encoder<DoipMessage> for DoipCodec {
+  type Error = DoipCodecError;
+
+  fn encode(&mut self, item: DoipMessage, dst: &mut BytesMut) -> Result<(), DoipCodecError> {
+    dst.extend_from_slice(&item.encode());
+    Ok(())
+  }
+}
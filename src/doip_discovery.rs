@@ -0,0 +1,272 @@
+use log::{info, warn};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::{Instant, timeout_at};
+
+use crate::common::net::LocalBinding;
+use crate::doip_message::{
+  DOIP_VERSION_DEFAULT, EntityStatus, EntityStatusPayloadType, VIN_LEN, VehicleAnnouncement,
+  VehicleConnectionPayloadType, encode_doip_message,
+};
+
+pub static DOIP_UDP_DISCOVERY_PORT: u16 = 13400;
+// A_DoIP_Ctrl: how long entities may take to answer a vehicle identification request
+pub static DEFAULT_DISCOVERY_TIMEOUT_MS: u64 = 2000;
+
+// Which entities should answer a vehicle identification request
+pub enum VehicleIdFilter {
+  All,
+  Eid([u8; 6]),
+  Vin([u8; 17]),
+}
+
+// DiagnosticPowerModeInfoResponse (0x4004) power mode
+pub fn power_mode_name(power_mode: u8) -> &'static str {
+  match power_mode {
+    0x00 => "not_ready",
+    0x01 => "ready",
+    0x02 => "not_supported",
+    _ => "reserved",
+  }
+}
+
+// A DoIP entity that answered a vehicle identification request
+#[derive(Debug, Clone)]
+pub struct DiscoveredEntity {
+  pub address: SocketAddr,
+  // Protocol version of the entity's response header
+  pub protocol_version: u8,
+  pub announcement: VehicleAnnouncement,
+}
+
+// A generic header whose inverse version byte matches the version
+fn has_valid_pattern(datagram: &[u8]) -> bool {
+  datagram.len() >= 8 && datagram[1] == !datagram[0]
+}
+
+// Send a vehicle identification request to `target` (an IPv4 broadcast, IPv6 multicast
+// such as ff02::1 with the interface as scope, or a unicast address) and collect every
+// announcement received until `wait` elapses. The request leaves from `binding`.
+pub async fn discover_vehicles(
+  target: SocketAddr,
+  filter: VehicleIdFilter,
+  binding: &LocalBinding,
+  wait: Duration,
+) -> Result<Vec<DiscoveredEntity>, Error> {
+  let (payload_type, payload) = match &filter {
+    VehicleIdFilter::All => (VehicleConnectionPayloadType::VehicleIdRequest, Vec::new()),
+    VehicleIdFilter::Eid(eid) => (
+      VehicleConnectionPayloadType::VehicleIdRequestWithEID,
+      eid.to_vec(),
+    ),
+    VehicleIdFilter::Vin(vin) => (
+      VehicleConnectionPayloadType::VehicleIdRequestWithVIN,
+      vin.to_vec(),
+    ),
+  };
+
+  let socket = binding.udp_socket(&target).await?;
+  // IPv6 has no broadcast, link-local multicast leaves on the interface of the target's scope
+  if target.is_ipv4() {
+    socket.set_broadcast(true)?;
+  }
+
+  let message = encode_doip_message(DOIP_VERSION_DEFAULT, payload_type as u16, Some(&payload));
+  socket.send_to(&message, target).await?;
+  info!(
+    "DoipDiscovery: sent vehicle identification request to {}: {:x?}",
+    target, message
+  );
+
+  let deadline = Instant::now() + wait;
+  let mut entities: Vec<DiscoveredEntity> = Vec::new();
+  let mut buffer = [0u8; 1024];
+
+  loop {
+    let (len, peer) = match timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+      Ok(result) => result?,
+      Err(_) => break,
+    };
+
+    let datagram = &buffer[..len];
+    if !has_valid_pattern(datagram) {
+      warn!("DoipDiscovery: Ignoring malformed datagram from {}", peer);
+      continue;
+    }
+
+    let payload_type = u16::from_be_bytes([datagram[2], datagram[3]]);
+    if payload_type != VehicleConnectionPayloadType::VehicleAnnouncement as u16 {
+      warn!(
+        "DoipDiscovery: Ignoring payload type 0x{:04X} from {}",
+        payload_type, peer
+      );
+      continue;
+    }
+
+    let Some(announcement) = VehicleAnnouncement::parse(&datagram[8..]) else {
+      warn!(
+        "DoipDiscovery: Malformed vehicle announcement from {}",
+        peer
+      );
+      continue;
+    };
+
+    info!(
+      "DoipDiscovery: {} answered with VIN {} logical address 0x{:04X}",
+      peer, announcement.vin, announcement.logical_address
+    );
+
+    // Entities may repeat their announcement, report each one once
+    if !entities.iter().any(|entity| {
+      entity.address.ip() == peer.ip()
+        && entity.announcement.logical_address == announcement.logical_address
+    }) {
+      entities.push(DiscoveredEntity {
+        address: peer,
+        protocol_version: datagram[0],
+        announcement,
+      });
+    }
+  }
+
+  Ok(entities)
+}
+
+// Send one UDP request from `binding` to the entity at `destination` and wait for the
+// response with `response_type`, returning its protocol version and payload
+async fn udp_request(
+  destination: SocketAddr,
+  binding: &LocalBinding,
+  version: u8,
+  payload_type: u16,
+  response_type: u16,
+  wait: Duration,
+) -> Result<(u8, Vec<u8>), Error> {
+  let socket = binding.udp_socket(&destination).await?;
+  let message = encode_doip_message(version, payload_type, None);
+  socket.send_to(&message, destination).await?;
+  info!(
+    "DoipDiscovery: sent request to {}: {:x?}",
+    destination, message
+  );
+
+  let deadline = Instant::now() + wait;
+  let mut buffer = [0u8; 1024];
+
+  loop {
+    let (len, peer) = match timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+      Ok(result) => result?,
+      Err(_) => {
+        return Err(Error::new(
+          ErrorKind::TimedOut,
+          format!(
+            "No response from {} within {}ms",
+            destination,
+            wait.as_millis()
+          ),
+        ));
+      }
+    };
+
+    let datagram = &buffer[..len];
+    if peer.ip() != destination.ip() || !has_valid_pattern(datagram) {
+      warn!("DoipDiscovery: Ignoring datagram from {}", peer);
+      continue;
+    }
+
+    let received_type = u16::from_be_bytes([datagram[2], datagram[3]]);
+    if received_type == response_type {
+      info!("DoipDiscovery: received from {}: {:x?}", peer, datagram);
+      return Ok((datagram[0], datagram[8..].to_vec()));
+    }
+    if received_type == VehicleConnectionPayloadType::GenericNegativeAck as u16 {
+      return Err(Error::new(
+        ErrorKind::Unsupported,
+        format!(
+          "{} rejected the request with generic NACK {:x?}",
+          peer,
+          &datagram[8..]
+        ),
+      ));
+    }
+    warn!(
+      "DoipDiscovery: Ignoring payload type 0x{:04X} from {}",
+      received_type, peer
+    );
+  }
+}
+
+// Query node type, socket usage and max data size of the entity at `ecu` with the
+// generic header `version`
+pub async fn entity_status(
+  ecu: SocketAddr,
+  binding: &LocalBinding,
+  version: u8,
+  wait: Duration,
+) -> Result<EntityStatus, Error> {
+  let (_, payload) = udp_request(
+    ecu,
+    binding,
+    version,
+    EntityStatusPayloadType::EntityStatusRequest as u16,
+    EntityStatusPayloadType::EntityStatusResponse as u16,
+    wait,
+  )
+  .await?;
+
+  EntityStatus::parse(&payload)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed entity status response"))
+}
+
+// Query the diagnostic power mode of the entity at `ecu` with the generic header
+// `version`
+pub async fn power_mode(
+  ecu: SocketAddr,
+  binding: &LocalBinding,
+  version: u8,
+  wait: Duration,
+) -> Result<u8, Error> {
+  let (_, payload) = udp_request(
+    ecu,
+    binding,
+    version,
+    EntityStatusPayloadType::PowerModeInfoRequest as u16,
+    EntityStatusPayloadType::PowerModeInfoResponse as u16,
+    wait,
+  )
+  .await?;
+
+  payload
+    .first()
+    .copied()
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed power mode response"))
+}
+
+// Protocol version the entity at `ecu` uses in its vehicle announcement
+pub async fn detect_protocol_version(
+  ecu: SocketAddr,
+  binding: &LocalBinding,
+  wait: Duration,
+) -> Result<u8, Error> {
+  let (version, _) = udp_request(
+    ecu,
+    binding,
+    DOIP_VERSION_DEFAULT,
+    VehicleConnectionPayloadType::VehicleIdRequest as u16,
+    VehicleConnectionPayloadType::VehicleAnnouncement as u16,
+    wait,
+  )
+  .await?;
+  Ok(version)
+}
+
+// Parse the VIN given as a 17 character string
+pub fn parse_vin(vin: &str) -> Result<[u8; 17], Error> {
+  vin.as_bytes().try_into().map_err(|_| {
+    Error::new(
+      ErrorKind::InvalidInput,
+      format!("VIN must be {} characters", VIN_LEN),
+    )
+  })
+}
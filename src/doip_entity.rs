@@ -0,0 +1,680 @@
+use bytes::BytesMut;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{Instant, sleep_until, timeout};
+use tokio_util::codec::Decoder;
+
+use crate::doip_client::{DEFAULT_MAX_PAYLOAD_SIZE, DOIP_TCP_PORT};
+use crate::doip_codec::{
+  DOIP_HEADER_LEN, DoipCodec, DoipCodecError, NACK_INCORRECT_PATTERN_FORMAT,
+  NACK_INVALID_PAYLOAD_LENGTH, NACK_UNKNOWN_PAYLOAD_TYPE, generic_nack_reason, nack_closes_socket,
+};
+use crate::doip_discovery::DOIP_UDP_DISCOVERY_PORT;
+use crate::doip_message::{
+  DEFAULT_PROTOCOL_VERSION, DOIP_VERSION_DEFAULT, DiagnosticAck, DiagnosticMessage, DoipMessage,
+  DoipPayload, EntityStatus, ROUTING_ACTIVATION_SUCCESS, RoutingActivationRequest,
+  RoutingActivationResponse, VehicleAnnouncement, VehicleConnectionPayloadType,
+  valid_payload_length,
+};
+
+// RoutingActivationResponse codes sent by the entity, every code but success closes
+// the socket
+pub static ROUTING_ACTIVATION_UNKNOWN_SOURCE_ADDRESS: u8 = 0x00;
+pub static ROUTING_ACTIVATION_ALL_SOCKETS_ACTIVE: u8 = 0x01;
+pub static ROUTING_ACTIVATION_DIFFERENT_SOURCE_ADDRESS: u8 = 0x02;
+pub static ROUTING_ACTIVATION_SOURCE_ADDRESS_ACTIVE: u8 = 0x03;
+pub static ROUTING_ACTIVATION_UNSUPPORTED_TYPE: u8 = 0x06;
+
+// DiagnosticNegativeAck (0x8003) codes
+pub static DIAGNOSTIC_NACK_INVALID_SOURCE_ADDRESS: u8 = 0x02;
+pub static DIAGNOSTIC_NACK_UNKNOWN_TARGET_ADDRESS: u8 = 0x03;
+
+// EntityStatusResponse node types
+pub static NODE_TYPE_GATEWAY: u8 = 0x00;
+pub static NODE_TYPE_NODE: u8 = 0x01;
+
+// T_TCP_Initial_Inactivity: a socket without routing activation is closed after this
+pub static DEFAULT_INITIAL_INACTIVITY_MS: u64 = 2000;
+// T_TCP_General_Inactivity: an activated socket without traffic is closed after this
+pub static DEFAULT_GENERAL_INACTIVITY_MS: u64 = 300_000;
+// T_TCP_Alive_Check: how long a tester may take to answer an alive check request
+pub static DEFAULT_ALIVE_CHECK_TIMEOUT_MS: u64 = 500;
+pub static DEFAULT_MAX_SOCKETS: u8 = 4;
+
+static READ_BUFFER_SIZE: usize = 4096;
+static MAX_DATAGRAM_SIZE: usize = 4096;
+
+// Identity and behaviour of a simulated DoIP entity
+#[derive(Debug, Clone)]
+pub struct DoipEntityConfig {
+  // Address the TCP and UDP sockets are bound to, e.g. 127.0.0.1 for tests
+  pub address: IpAddr,
+  pub tcp_port: u16,
+  pub udp_port: u16,
+  // Generic header protocol version sent and accepted
+  pub protocol_version: u8,
+  pub logical_address: u16,
+  pub vin: String,
+  pub eid: [u8; 6],
+  pub gid: [u8; 6],
+  // Logical addresses diagnostic messages are accepted for, the others are answered
+  // with NACK 0x03. Include the entity's own address when it is an ECU itself.
+  pub target_addresses: Vec<u16>,
+  // Tester source addresses routing is activated for, every address when empty
+  pub accepted_testers: Vec<u16>,
+  pub activation_types: Vec<u8>,
+  // Sockets with activated routing at the same time
+  pub max_sockets: u8,
+  pub max_data_size: u32,
+  pub node_type: u8,
+  // DiagnosticPowerModeInfoResponse value, 0x01 ready
+  pub power_mode: u8,
+  pub initial_inactivity: Duration,
+  pub general_inactivity: Duration,
+  pub alive_check_timeout: Duration,
+}
+
+impl Default for DoipEntityConfig {
+  fn default() -> Self {
+    Self {
+      address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+      tcp_port: DOIP_TCP_PORT,
+      udp_port: DOIP_UDP_DISCOVERY_PORT,
+      protocol_version: DEFAULT_PROTOCOL_VERSION,
+      logical_address: 0x1001,
+      vin: "DOIP2HTTPSIM00001".to_string(),
+      eid: [0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+      gid: [0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+      target_addresses: vec![0x1001],
+      accepted_testers: Vec::new(),
+      activation_types: vec![0x00, 0x01],
+      max_sockets: DEFAULT_MAX_SOCKETS,
+      max_data_size: DEFAULT_MAX_PAYLOAD_SIZE,
+      node_type: NODE_TYPE_GATEWAY,
+      power_mode: 0x01,
+      initial_inactivity: Duration::from_millis(DEFAULT_INITIAL_INACTIVITY_MS),
+      general_inactivity: Duration::from_millis(DEFAULT_GENERAL_INACTIVITY_MS),
+      alive_check_timeout: Duration::from_millis(DEFAULT_ALIVE_CHECK_TIMEOUT_MS),
+    }
+  }
+}
+
+// A UDS request received in a diagnostic message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdsRequest {
+  pub tester_address: u16,
+  pub target_address: u16,
+  pub data: Vec<u8>,
+}
+
+// Sends diagnostic messages from the target ECU of a request back to its tester
+#[derive(Clone)]
+pub struct Responder {
+  writer: Arc<Mutex<OwnedWriteHalf>>,
+  version: u8,
+  tester_address: u16,
+  target_address: u16,
+}
+
+impl Responder {
+  pub async fn send(&self, data: Vec<u8>) -> Result<(), Error> {
+    let message = DiagnosticMessage {
+      source_address: self.target_address,
+      target_address: self.tester_address,
+      user_data: data,
+    };
+    write_message(
+      &self.writer,
+      self.version,
+      DoipPayload::DiagnosticMessage(message),
+    )
+    .await
+  }
+}
+
+// Application layer of the ECUs behind a DoIP entity
+pub trait UdsHandler: Send + Sync + 'static {
+  // Handle one request and return the final response, or None to stay silent (e.g.
+  // suppressed positive response). Intermediate responses such as 0x78 response
+  // pending are sent through `responder` before returning.
+  fn handle(
+    &self,
+    request: UdsRequest,
+    responder: Responder,
+  ) -> impl Future<Output = Option<Vec<u8>>> + Send;
+}
+
+// A TCP_DATA socket of the entity
+#[derive(Clone)]
+struct DataSocket {
+  peer: SocketAddr,
+  // Source address routing was activated for
+  tester_address: Option<u16>,
+  writer: Arc<Mutex<OwnedWriteHalf>>,
+  // Signalled when the tester answers an alive check request
+  alive: Arc<Notify>,
+  // Signalled to make the socket's task close it
+  close: Arc<Notify>,
+}
+
+struct Shared<H> {
+  config: DoipEntityConfig,
+  handler: H,
+  sockets: std::sync::Mutex<HashMap<u64, DataSocket>>,
+  next_socket_id: AtomicU64,
+}
+
+// Encode and send one message on a socket
+async fn write_message(
+  writer: &Mutex<OwnedWriteHalf>,
+  version: u8,
+  payload: DoipPayload,
+) -> Result<(), Error> {
+  let message = DoipMessage::new(version, payload).encode();
+  writer.lock().await.write_all(&message).await
+}
+
+impl<H: UdsHandler> Shared<H> {
+  async fn send(&self, writer: &Mutex<OwnedWriteHalf>, payload: DoipPayload) -> Result<(), Error> {
+    write_message(writer, self.config.protocol_version, payload).await
+  }
+
+  fn socket(&self, socket_id: u64) -> Option<DataSocket> {
+    self.sockets.lock().unwrap().get(&socket_id).cloned()
+  }
+
+  // Ask the tester on `socket` whether it is still there
+  async fn alive_check(&self, socket: &DataSocket) -> bool {
+    let answered = socket.alive.notified();
+    tokio::pin!(answered);
+    answered.as_mut().enable();
+
+    if let Err(e) = self
+      .send(&socket.writer, DoipPayload::AliveCheckRequest)
+      .await
+    {
+      warn!("DoipEntity: Alive check to {} failed: {}", socket.peer, e);
+      return false;
+    }
+    timeout(self.config.alive_check_timeout, answered)
+      .await
+      .is_ok()
+  }
+
+  // Deregister the socket and have its task close it
+  fn close_socket(&self, socket_id: u64) {
+    if let Some(socket) = self.sockets.lock().unwrap().get_mut(&socket_id) {
+      info!("DoipEntity: Closing socket of {}", socket.peer);
+      socket.tester_address = None;
+      socket.close.notify_one();
+    }
+  }
+
+  // Sockets with activated routing other than `socket_id`
+  fn activated_sockets(&self, socket_id: u64) -> Vec<(u64, DataSocket)> {
+    self
+      .sockets
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|(id, socket)| **id != socket_id && socket.tester_address.is_some())
+      .map(|(id, socket)| (*id, socket.clone()))
+      .collect()
+  }
+
+  // ISO 13400-2 routing activation handler, returns the response code
+  async fn activate_routing(&self, socket_id: u64, request: &RoutingActivationRequest) -> u8 {
+    let config = &self.config;
+    let source_address = request.source_address;
+    if !config.accepted_testers.is_empty() && !config.accepted_testers.contains(&source_address) {
+      return ROUTING_ACTIVATION_UNKNOWN_SOURCE_ADDRESS;
+    }
+    if !config.activation_types.contains(&request.activation_type) {
+      return ROUTING_ACTIVATION_UNSUPPORTED_TYPE;
+    }
+
+    match self
+      .socket(socket_id)
+      .and_then(|socket| socket.tester_address)
+    {
+      Some(registered) if registered == source_address => return ROUTING_ACTIVATION_SUCCESS,
+      Some(_) => return ROUTING_ACTIVATION_DIFFERENT_SOURCE_ADDRESS,
+      None => {}
+    }
+
+    // The tester may have reconnected without closing its old socket, the old one
+    // only keeps the registration if it still answers
+    let same_tester = self
+      .activated_sockets(socket_id)
+      .into_iter()
+      .find(|(_, socket)| socket.tester_address == Some(source_address));
+    if let Some((other_id, other)) = same_tester {
+      if self.alive_check(&other).await {
+        return ROUTING_ACTIVATION_SOURCE_ADDRESS_ACTIVE;
+      }
+      self.close_socket(other_id);
+    }
+
+    let active = self.activated_sockets(socket_id);
+    if active.len() >= config.max_sockets as usize {
+      for (other_id, other) in active {
+        if !self.alive_check(&other).await {
+          self.close_socket(other_id);
+        }
+      }
+      if self.activated_sockets(socket_id).len() >= config.max_sockets as usize {
+        return ROUTING_ACTIVATION_ALL_SOCKETS_ACTIVE;
+      }
+    }
+
+    if let Some(socket) = self.sockets.lock().unwrap().get_mut(&socket_id) {
+      socket.tester_address = Some(source_address);
+    }
+    ROUTING_ACTIVATION_SUCCESS
+  }
+
+  // Acknowledge a diagnostic message and hand it to the UDS handler. Returns false when
+  // the socket has to be closed.
+  async fn handle_diagnostic(
+    self: &Arc<Self>,
+    socket: &DataSocket,
+    message: DiagnosticMessage,
+  ) -> Result<bool, Error> {
+    let nack = |code: u8| {
+      DoipPayload::DiagnosticNegativeAck(DiagnosticAck {
+        source_address: message.target_address,
+        target_address: message.source_address,
+        code,
+        previous_message: Vec::new(),
+      })
+    };
+
+    if socket.tester_address != Some(message.source_address) {
+      warn!(
+        "DoipEntity: Diagnostic message from 0x{:04X} without routing activation",
+        message.source_address
+      );
+      self
+        .send(&socket.writer, nack(DIAGNOSTIC_NACK_INVALID_SOURCE_ADDRESS))
+        .await?;
+      return Ok(false);
+    }
+    if !self
+      .config
+      .target_addresses
+      .contains(&message.target_address)
+    {
+      warn!(
+        "DoipEntity: Diagnostic message for unknown target 0x{:04X}",
+        message.target_address
+      );
+      self
+        .send(&socket.writer, nack(DIAGNOSTIC_NACK_UNKNOWN_TARGET_ADDRESS))
+        .await?;
+      return Ok(true);
+    }
+
+    let ack = DoipPayload::DiagnosticPositiveAck(DiagnosticAck {
+      source_address: message.target_address,
+      target_address: message.source_address,
+      code: 0x00,
+      previous_message: Vec::new(),
+    });
+    self.send(&socket.writer, ack).await?;
+
+    let request = UdsRequest {
+      tester_address: message.source_address,
+      target_address: message.target_address,
+      data: message.user_data,
+    };
+    let responder = Responder {
+      writer: socket.writer.clone(),
+      version: self.config.protocol_version,
+      tester_address: request.tester_address,
+      target_address: request.target_address,
+    };
+    // Requests to different ECUs are served concurrently, like a gateway does
+    let shared = self.clone();
+    tokio::spawn(async move {
+      if let Some(response) = shared.handler.handle(request, responder.clone()).await
+        && let Err(e) = responder.send(response).await
+      {
+        warn!("DoipEntity: Failed to send diagnostic response: {}", e);
+      }
+    });
+    Ok(true)
+  }
+
+  // Serve one TCP_DATA socket until the tester closes it, it times out or is rejected
+  async fn serve_socket(self: Arc<Self>, stream: TcpStream, peer: SocketAddr) {
+    if let Err(e) = stream.set_nodelay(true) {
+      warn!("DoipEntity: Failed to set TCP_NODELAY: {}", e);
+    }
+    let (mut reader, writer) = stream.into_split();
+    let socket_id = self.next_socket_id.fetch_add(1, Ordering::SeqCst);
+    let socket = DataSocket {
+      peer,
+      tester_address: None,
+      writer: Arc::new(Mutex::new(writer)),
+      alive: Arc::new(Notify::new()),
+      close: Arc::new(Notify::new()),
+    };
+    self
+      .sockets
+      .lock()
+      .unwrap()
+      .insert(socket_id, socket.clone());
+    info!("DoipEntity: Tester connected from {}", peer);
+
+    let mut codec =
+      DoipCodec::with_version(self.config.protocol_version, self.config.max_data_size);
+    let mut buffer = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    let mut deadline = Instant::now() + self.config.initial_inactivity;
+
+    loop {
+      let message = match codec.decode(&mut buffer) {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+          tokio::select! {
+            read = reader.read_buf(&mut buffer) => match read {
+              Ok(0) => {
+                info!("DoipEntity: Tester {} closed the connection", peer);
+                break;
+              }
+              Ok(_) => continue,
+              Err(e) => {
+                warn!("DoipEntity: Failed to read from {}: {}", peer, e);
+                break;
+              }
+            },
+            _ = sleep_until(deadline) => {
+              info!("DoipEntity: Closing inactive socket of {}", peer);
+              break;
+            }
+            _ = socket.close.notified() => break,
+          }
+        }
+        Err(DoipCodecError::Nack(code)) => {
+          warn!(
+            "DoipEntity: Rejecting frame from {}: {}",
+            peer,
+            generic_nack_reason(code)
+          );
+          if self
+            .send(&socket.writer, DoipPayload::GenericNack(code))
+            .await
+            .is_err()
+            || nack_closes_socket(code)
+          {
+            break;
+          }
+          continue;
+        }
+        Err(DoipCodecError::Io(e)) => {
+          warn!("DoipEntity: Failed to read from {}: {}", peer, e);
+          break;
+        }
+      };
+
+      let Some(current) = self.socket(socket_id) else {
+        break;
+      };
+      if current.tester_address.is_some() {
+        deadline = Instant::now() + self.config.general_inactivity;
+      }
+
+      let result = match message.payload {
+        DoipPayload::RoutingActivationRequest(request) => {
+          let code = self.activate_routing(socket_id, &request).await;
+          info!(
+            "DoipEntity: Routing activation for 0x{:04X} from {}: {}",
+            request.source_address,
+            peer,
+            RoutingActivationResponse::reason(code)
+          );
+          let response = DoipPayload::RoutingActivationResponse(RoutingActivationResponse {
+            tester_address: request.source_address,
+            entity_address: self.config.logical_address,
+            response_code: code,
+            oem_data: None,
+          });
+          match self.send(&socket.writer, response).await {
+            Ok(()) if code == ROUTING_ACTIVATION_SUCCESS => {
+              deadline = Instant::now() + self.config.general_inactivity;
+              Ok(true)
+            }
+            Ok(()) => Ok(false),
+            Err(e) => Err(e),
+          }
+        }
+        DoipPayload::AliveCheckRequest => self
+          .send(
+            &socket.writer,
+            DoipPayload::AliveCheckResponse(self.config.logical_address),
+          )
+          .await
+          .map(|_| true),
+        DoipPayload::AliveCheckResponse(_) => {
+          socket.alive.notify_waiters();
+          Ok(true)
+        }
+        DoipPayload::DiagnosticMessage(message) => self.handle_diagnostic(&current, message).await,
+        DoipPayload::GenericNack(code) => {
+          warn!(
+            "DoipEntity: Generic NACK 0x{:02X} ({}) from {}",
+            code,
+            generic_nack_reason(code),
+            peer
+          );
+          Ok(true)
+        }
+        other => {
+          warn!(
+            "DoipEntity: Payload type 0x{:04X} is not handled on TCP",
+            other.payload_type()
+          );
+          self
+            .send(
+              &socket.writer,
+              DoipPayload::GenericNack(NACK_UNKNOWN_PAYLOAD_TYPE),
+            )
+            .await
+            .map(|_| true)
+        }
+      };
+
+      match result {
+        Ok(true) => {}
+        Ok(false) => break,
+        Err(e) => {
+          warn!("DoipEntity: Failed to write to {}: {}", peer, e);
+          break;
+        }
+      }
+    }
+
+    self.sockets.lock().unwrap().remove(&socket_id);
+    let _ = socket.writer.lock().await.shutdown().await;
+  }
+
+  // Parse one UDP datagram, returning the generic NACK code for invalid ones
+  fn parse_datagram(&self, datagram: &[u8]) -> Result<DoipMessage, u8> {
+    if datagram.len() < DOIP_HEADER_LEN || datagram[1] != !datagram[0] {
+      return Err(NACK_INCORRECT_PATTERN_FORMAT);
+    }
+    let version = datagram[0];
+    let payload_type = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let payload_len = u32::from_be_bytes([datagram[4], datagram[5], datagram[6], datagram[7]]);
+
+    // The default version is only valid for vehicle identification requests
+    let vehicle_id_request = payload_type >= VehicleConnectionPayloadType::VehicleIdRequest as u16
+      && payload_type <= VehicleConnectionPayloadType::VehicleIdRequestWithVIN as u16;
+    if version != self.config.protocol_version
+      && !(version == DOIP_VERSION_DEFAULT && vehicle_id_request)
+    {
+      return Err(NACK_INCORRECT_PATTERN_FORMAT);
+    }
+
+    match valid_payload_length(payload_type, payload_len) {
+      None => return Err(NACK_UNKNOWN_PAYLOAD_TYPE),
+      Some(false) => return Err(NACK_INVALID_PAYLOAD_LENGTH),
+      Some(true) => {}
+    }
+    let payload = datagram
+      .get(DOIP_HEADER_LEN..DOIP_HEADER_LEN + payload_len as usize)
+      .and_then(|payload| DoipPayload::parse(payload_type, payload))
+      .ok_or(NACK_INVALID_PAYLOAD_LENGTH)?;
+    Ok(DoipMessage::new(version, payload))
+  }
+
+  fn announcement(&self) -> DoipPayload {
+    DoipPayload::VehicleAnnouncement(VehicleAnnouncement {
+      vin: self.config.vin.clone(),
+      logical_address: self.config.logical_address,
+      eid: self.config.eid,
+      gid: self.config.gid,
+      further_action: 0x00,
+      sync_status: None,
+    })
+  }
+
+  // Answer vehicle identification, entity status and power mode requests
+  async fn serve_udp(&self, socket: UdpSocket) -> Result<(), Error> {
+    let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+    loop {
+      // Errors such as an ICMP port unreachable for an earlier answer only concern one
+      // peer, keep serving the others
+      let (len, peer) = match socket.recv_from(&mut buffer).await {
+        Ok(received) => received,
+        Err(e) => {
+          warn!("DoipEntity: Failed to receive datagram: {}", e);
+          continue;
+        }
+      };
+      let response = match self.parse_datagram(&buffer[..len]) {
+        Ok(message) => match message.payload {
+          DoipPayload::VehicleIdRequest => Some(self.announcement()),
+          DoipPayload::VehicleIdRequestWithEid(eid) if eid == self.config.eid => {
+            Some(self.announcement())
+          }
+          DoipPayload::VehicleIdRequestWithVin(vin) if vin == self.config.vin.as_bytes() => {
+            Some(self.announcement())
+          }
+          DoipPayload::VehicleIdRequestWithEid(_) | DoipPayload::VehicleIdRequestWithVin(_) => None,
+          DoipPayload::EntityStatusRequest => {
+            Some(DoipPayload::EntityStatusResponse(EntityStatus {
+              node_type: self.config.node_type,
+              max_concurrent_sockets: self.config.max_sockets,
+              // Only sockets with routing activated are registered to a tester
+              open_sockets: self
+                .sockets
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|socket| socket.tester_address.is_some())
+                .count() as u8,
+              max_data_size: Some(self.config.max_data_size),
+            }))
+          }
+          DoipPayload::PowerModeInfoRequest => {
+            Some(DoipPayload::PowerModeInfoResponse(self.config.power_mode))
+          }
+          other => {
+            warn!(
+              "DoipEntity: Payload type 0x{:04X} is not handled on UDP",
+              other.payload_type()
+            );
+            Some(DoipPayload::GenericNack(NACK_UNKNOWN_PAYLOAD_TYPE))
+          }
+        },
+        Err(code) => {
+          warn!(
+            "DoipEntity: Rejecting datagram from {}: {}",
+            peer,
+            generic_nack_reason(code)
+          );
+          Some(DoipPayload::GenericNack(code))
+        }
+      };
+
+      if let Some(response) = response {
+        let message = DoipMessage::new(self.config.protocol_version, response).encode();
+        if let Err(e) = socket.send_to(&message, peer).await {
+          warn!("DoipEntity: Failed to answer {}: {}", peer, e);
+        }
+      }
+    }
+  }
+}
+
+// Server side of ISO 13400-2: accepts testers on TCP, activates routing, acknowledges
+// diagnostic messages and hands their UDS data to a handler, and answers UDP vehicle
+// identification, entity status and power mode requests
+pub struct DoipEntity<H: UdsHandler> {
+  shared: Arc<Shared<H>>,
+  tcp: TcpListener,
+  udp: UdpSocket,
+}
+
+impl<H: UdsHandler> DoipEntity<H> {
+  pub async fn bind(config: DoipEntityConfig, handler: H) -> Result<Self, Error> {
+    let tcp = TcpListener::bind(SocketAddr::new(config.address, config.tcp_port)).await?;
+    let udp = UdpSocket::bind(SocketAddr::new(config.address, config.udp_port)).await?;
+    info!(
+      "DoipEntity: 0x{:04X} (VIN {}) listening on {}",
+      config.logical_address,
+      config.vin,
+      tcp.local_addr()?
+    );
+
+    Ok(Self {
+      shared: Arc::new(Shared {
+        config,
+        handler,
+        sockets: std::sync::Mutex::new(HashMap::new()),
+        next_socket_id: AtomicU64::new(0),
+      }),
+      tcp,
+      udp,
+    })
+  }
+
+  pub fn tcp_address(&self) -> Result<SocketAddr, Error> {
+    self.tcp.local_addr()
+  }
+
+  pub fn udp_address(&self) -> Result<SocketAddr, Error> {
+    self.udp.local_addr()
+  }
+
+  // Serve testers until an accept or UDP receive fails
+  pub async fn run(self) -> Result<(), Error> {
+    let Self { shared, tcp, udp } = self;
+
+    let accept = async {
+      loop {
+        let (stream, peer) = tcp.accept().await?;
+        tokio::spawn(shared.clone().serve_socket(stream, peer));
+      }
+    };
+
+    let result: Result<(), Error> = tokio::select! {
+      result = accept => result,
+      result = shared.serve_udp(udp) => result,
+    };
+    if let Err(e) = &result {
+      error!("DoipEntity: Stopped serving: {}", e);
+    }
+    result
+  }
+}
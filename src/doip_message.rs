@@ -0,0 +1,487 @@
+// ISO 13400-2 DoIP messages: payload types and their typed payloads
+
+// Generic header protocol versions, the inverse version byte is the bitwise complement
+pub static DOIP_VERSION_ISO_13400_2010: u8 = 0x01;
+pub static DOIP_VERSION_ISO_13400_2012: u8 = 0x02;
+pub static DOIP_VERSION_ISO_13400_2019: u8 = 0x03;
+// Default version, only valid in vehicle identification requests
+pub static DOIP_VERSION_DEFAULT: u8 = 0xFF;
+pub static DEFAULT_PROTOCOL_VERSION: u8 = 0x02;
+
+pub fn is_supported_protocol_version(version: u8) -> bool {
+  version == DOIP_VERSION_ISO_13400_2010
+    || version == DOIP_VERSION_ISO_13400_2012
+    || version == DOIP_VERSION_ISO_13400_2019
+}
+
+#[repr(u16)]
+pub enum VehicleConnectionPayloadType {
+  GenericNegativeAck = 0x0000,
+  VehicleIdRequest = 0x0001,
+  VehicleIdRequestWithEID = 0x0002,
+  VehicleIdRequestWithVIN = 0x0003,
+  VehicleAnnouncement = 0x0004,
+  RoutingActivationRequest = 0x0005,
+  RoutingActivationResponse = 0x0006,
+  AliveCheckRequest = 0x0007,
+  AliveCheckResponse = 0x0008,
+}
+
+#[repr(u16)]
+pub enum EntityStatusPayloadType {
+  EntityStatusRequest = 0x4001,
+  EntityStatusResponse = 0x4002,
+  PowerModeInfoRequest = 0x4003,
+  PowerModeInfoResponse = 0x4004,
+}
+
+#[repr(u16)]
+#[allow(clippy::enum_variant_names)]
+pub enum DiagnosticPayloadType {
+  DiagnosticMessage = 0x8001,
+  DiagnosticPositiveAck = 0x8002,
+  DiagnosticNegativeAck = 0x8003,
+}
+
+pub static ROUTING_ACTIVATION_SUCCESS: u8 = 0x10;
+
+pub static VIN_LEN: usize = 17;
+static EID_LEN: usize = 6;
+static GID_LEN: usize = 6;
+// VIN + logical address + EID + GID + further action, the sync status byte is optional
+static ANNOUNCEMENT_MIN_LEN: usize = 32;
+
+fn read_u16(bytes: &[u8]) -> u16 {
+  u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+  u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// RoutingActivationRequest (0x0005) payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingActivationRequest {
+  pub source_address: u16,
+  pub activation_type: u8,
+  // OEM specific part after the 4 ISO reserved bytes
+  pub oem_data: Option<[u8; 4]>,
+}
+
+impl RoutingActivationRequest {
+  pub fn parse(payload: &[u8]) -> Option<Self> {
+    if payload.len() != 7 && payload.len() != 11 {
+      return None;
+    }
+
+    Some(Self {
+      source_address: read_u16(payload),
+      activation_type: payload[2],
+      oem_data: payload
+        .get(7..11)
+        .map(|oem| [oem[0], oem[1], oem[2], oem[3]]),
+    })
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut payload = self.source_address.to_be_bytes().to_vec();
+    payload.push(self.activation_type);
+    payload.extend_from_slice(&[0; 4]);
+    if let Some(oem_data) = &self.oem_data {
+      payload.extend_from_slice(oem_data);
+    }
+    payload
+  }
+}
+
+// RoutingActivationResponse (0x0006) payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingActivationResponse {
+  pub tester_address: u16,
+  pub entity_address: u16,
+  pub response_code: u8,
+  pub oem_data: Option<u32>,
+}
+
+impl RoutingActivationResponse {
+  pub fn parse(payload: &[u8]) -> Option<Self> {
+    // tester address, entity address, response code and the 4 reserved bytes
+    if payload.len() < 9 {
+      return None;
+    }
+
+    Some(Self {
+      tester_address: read_u16(&payload[0..]),
+      entity_address: read_u16(&payload[2..]),
+      response_code: payload[4],
+      oem_data: payload.get(9..13).map(read_u32),
+    })
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut payload = self.tester_address.to_be_bytes().to_vec();
+    payload.extend_from_slice(&self.entity_address.to_be_bytes());
+    payload.push(self.response_code);
+    payload.extend_from_slice(&[0; 4]);
+    if let Some(oem_data) = self.oem_data {
+      payload.extend_from_slice(&oem_data.to_be_bytes());
+    }
+    payload
+  }
+
+  pub fn is_success(&self) -> bool {
+    self.response_code == ROUTING_ACTIVATION_SUCCESS
+  }
+
+  // ISO 13400-2 routing activation response codes
+  pub fn reason(code: u8) -> &'static str {
+    match code {
+      0x00 => "unknown source address",
+      0x01 => "all TCP_DATA sockets are registered and active",
+      0x02 => "source address differs from the one registered on this socket",
+      0x03 => "source address is already registered on a different socket",
+      0x04 => "missing authentication",
+      0x05 => "rejected confirmation",
+      0x06 => "unsupported routing activation type",
+      0x07 => "TLS connection required",
+      0x10 => "routing successfully activated",
+      0x11 => "routing will be activated, confirmation required",
+      0xE0..=0xFE => "vehicle manufacturer specific",
+      _ => "reserved",
+    }
+  }
+}
+
+// VehicleAnnouncement / VehicleIdResponse (0x0004) payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VehicleAnnouncement {
+  pub vin: String,
+  pub logical_address: u16,
+  pub eid: [u8; 6],
+  pub gid: [u8; 6],
+  pub further_action: u8,
+  pub sync_status: Option<u8>,
+}
+
+impl VehicleAnnouncement {
+  pub fn parse(payload: &[u8]) -> Option<Self> {
+    if payload.len() < ANNOUNCEMENT_MIN_LEN {
+      return None;
+    }
+
+    let mut offset = 0;
+    let vin = String::from_utf8_lossy(&payload[offset..offset + VIN_LEN]).to_string();
+    offset += VIN_LEN;
+    let logical_address = read_u16(&payload[offset..]);
+    offset += 2;
+    let mut eid = [0u8; 6];
+    eid.copy_from_slice(&payload[offset..offset + EID_LEN]);
+    offset += EID_LEN;
+    let mut gid = [0u8; 6];
+    gid.copy_from_slice(&payload[offset..offset + GID_LEN]);
+    offset += GID_LEN;
+    let further_action = payload[offset];
+    offset += 1;
+    let sync_status = payload.get(offset).copied();
+
+    Some(Self {
+      vin,
+      logical_address,
+      eid,
+      gid,
+      further_action,
+      sync_status,
+    })
+  }
+
+  // The VIN is padded with 0x00 or cut to 17 bytes
+  pub fn encode(&self) -> Vec<u8> {
+    let mut vin = self.vin.as_bytes().to_vec();
+    vin.resize(VIN_LEN, 0x00);
+
+    let mut payload = vin;
+    payload.extend_from_slice(&self.logical_address.to_be_bytes());
+    payload.extend_from_slice(&self.eid);
+    payload.extend_from_slice(&self.gid);
+    payload.push(self.further_action);
+    if let Some(sync_status) = self.sync_status {
+      payload.push(sync_status);
+    }
+    payload
+  }
+}
+
+// EntityStatusResponse (0x4002) payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityStatus {
+  // 0x00 DoIP gateway, 0x01 DoIP node
+  pub node_type: u8,
+  pub max_concurrent_sockets: u8,
+  pub open_sockets: u8,
+  pub max_data_size: Option<u32>,
+}
+
+impl EntityStatus {
+  pub fn parse(payload: &[u8]) -> Option<Self> {
+    if payload.len() < 3 {
+      return None;
+    }
+
+    Some(Self {
+      node_type: payload[0],
+      max_concurrent_sockets: payload[1],
+      open_sockets: payload[2],
+      max_data_size: payload.get(3..7).map(read_u32),
+    })
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut payload = vec![
+      self.node_type,
+      self.max_concurrent_sockets,
+      self.open_sockets,
+    ];
+    if let Some(max_data_size) = self.max_data_size {
+      payload.extend_from_slice(&max_data_size.to_be_bytes());
+    }
+    payload
+  }
+
+  pub fn node_type_name(&self) -> &'static str {
+    match self.node_type {
+      0x00 => "gateway",
+      0x01 => "node",
+      _ => "reserved",
+    }
+  }
+}
+
+// DiagnosticMessage (0x8001) payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticMessage {
+  pub source_address: u16,
+  pub target_address: u16,
+  // The UDS message
+  pub user_data: Vec<u8>,
+}
+
+impl DiagnosticMessage {
+  pub fn parse(payload: &[u8]) -> Option<Self> {
+    if payload.len() < 5 {
+      return None;
+    }
+
+    Some(Self {
+      source_address: read_u16(&payload[0..]),
+      target_address: read_u16(&payload[2..]),
+      user_data: payload[4..].to_vec(),
+    })
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut payload = self.source_address.to_be_bytes().to_vec();
+    payload.extend_from_slice(&self.target_address.to_be_bytes());
+    payload.extend_from_slice(&self.user_data);
+    payload
+  }
+}
+
+// DiagnosticPositiveAck (0x8002) / DiagnosticNegativeAck (0x8003) payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticAck {
+  pub source_address: u16,
+  pub target_address: u16,
+  // 0x00 for a positive ACK, the NACK code otherwise
+  pub code: u8,
+  // Optional copy of the acknowledged diagnostic message
+  pub previous_message: Vec<u8>,
+}
+
+impl DiagnosticAck {
+  pub fn parse(payload: &[u8]) -> Option<Self> {
+    if payload.len() < 5 {
+      return None;
+    }
+
+    Some(Self {
+      source_address: read_u16(&payload[0..]),
+      target_address: read_u16(&payload[2..]),
+      code: payload[4],
+      previous_message: payload[5..].to_vec(),
+    })
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut payload = self.source_address.to_be_bytes().to_vec();
+    payload.extend_from_slice(&self.target_address.to_be_bytes());
+    payload.push(self.code);
+    payload.extend_from_slice(&self.previous_message);
+    payload
+  }
+}
+
+// Payload of every DoIP message type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DoipPayload {
+  GenericNack(u8),
+  VehicleIdRequest,
+  VehicleIdRequestWithEid([u8; 6]),
+  VehicleIdRequestWithVin([u8; 17]),
+  VehicleAnnouncement(VehicleAnnouncement),
+  RoutingActivationRequest(RoutingActivationRequest),
+  RoutingActivationResponse(RoutingActivationResponse),
+  AliveCheckRequest,
+  // Source address of the tester answering
+  AliveCheckResponse(u16),
+  EntityStatusRequest,
+  EntityStatusResponse(EntityStatus),
+  PowerModeInfoRequest,
+  PowerModeInfoResponse(u8),
+  DiagnosticMessage(DiagnosticMessage),
+  DiagnosticPositiveAck(DiagnosticAck),
+  DiagnosticNegativeAck(DiagnosticAck),
+}
+
+// Whether `payload_len` is allowed for `payload_type`, None for unknown payload types
+pub fn valid_payload_length(payload_type: u16, payload_len: u32) -> Option<bool> {
+  let valid = match payload_type {
+    0x0000 => payload_len == 1,
+    0x0001 => payload_len == 0,
+    0x0002 => payload_len == EID_LEN as u32,
+    0x0003 => payload_len == VIN_LEN as u32,
+    0x0004 => payload_len == 32 || payload_len == 33,
+    0x0005 => payload_len == 7 || payload_len == 11,
+    0x0006 => payload_len == 9 || payload_len == 13,
+    0x0007 => payload_len == 0,
+    0x0008 => payload_len == 2,
+    0x4001 => payload_len == 0,
+    0x4002 => payload_len == 3 || payload_len == 7,
+    0x4003 => payload_len == 0,
+    0x4004 => payload_len == 1,
+    0x8001..=0x8003 => payload_len >= 5,
+    _ => return None,
+  };
+  Some(valid)
+}
+
+impl DoipPayload {
+  // Parse the payload of a message with `payload_type`, None if the type is unknown or
+  // the payload malformed
+  pub fn parse(payload_type: u16, payload: &[u8]) -> Option<Self> {
+    if !valid_payload_length(payload_type, payload.len() as u32)? {
+      return None;
+    }
+
+    let parsed = match payload_type {
+      0x0000 => DoipPayload::GenericNack(payload[0]),
+      0x0001 => DoipPayload::VehicleIdRequest,
+      0x0002 => DoipPayload::VehicleIdRequestWithEid(payload.try_into().ok()?),
+      0x0003 => DoipPayload::VehicleIdRequestWithVin(payload.try_into().ok()?),
+      0x0004 => DoipPayload::VehicleAnnouncement(VehicleAnnouncement::parse(payload)?),
+      0x0005 => DoipPayload::RoutingActivationRequest(RoutingActivationRequest::parse(payload)?),
+      0x0006 => DoipPayload::RoutingActivationResponse(RoutingActivationResponse::parse(payload)?),
+      0x0007 => DoipPayload::AliveCheckRequest,
+      0x0008 => DoipPayload::AliveCheckResponse(read_u16(payload)),
+      0x4001 => DoipPayload::EntityStatusRequest,
+      0x4002 => DoipPayload::EntityStatusResponse(EntityStatus::parse(payload)?),
+      0x4003 => DoipPayload::PowerModeInfoRequest,
+      0x4004 => DoipPayload::PowerModeInfoResponse(payload[0]),
+      0x8001 => DoipPayload::DiagnosticMessage(DiagnosticMessage::parse(payload)?),
+      0x8002 => DoipPayload::DiagnosticPositiveAck(DiagnosticAck::parse(payload)?),
+      0x8003 => DoipPayload::DiagnosticNegativeAck(DiagnosticAck::parse(payload)?),
+      _ => return None,
+    };
+    Some(parsed)
+  }
+
+  pub fn payload_type(&self) -> u16 {
+    match self {
+      DoipPayload::GenericNack(_) => VehicleConnectionPayloadType::GenericNegativeAck as u16,
+      DoipPayload::VehicleIdRequest => VehicleConnectionPayloadType::VehicleIdRequest as u16,
+      DoipPayload::VehicleIdRequestWithEid(_) => {
+        VehicleConnectionPayloadType::VehicleIdRequestWithEID as u16
+      }
+      DoipPayload::VehicleIdRequestWithVin(_) => {
+        VehicleConnectionPayloadType::VehicleIdRequestWithVIN as u16
+      }
+      DoipPayload::VehicleAnnouncement(_) => {
+        VehicleConnectionPayloadType::VehicleAnnouncement as u16
+      }
+      DoipPayload::RoutingActivationRequest(_) => {
+        VehicleConnectionPayloadType::RoutingActivationRequest as u16
+      }
+      DoipPayload::RoutingActivationResponse(_) => {
+        VehicleConnectionPayloadType::RoutingActivationResponse as u16
+      }
+      DoipPayload::AliveCheckRequest => VehicleConnectionPayloadType::AliveCheckRequest as u16,
+      DoipPayload::AliveCheckResponse(_) => VehicleConnectionPayloadType::AliveCheckResponse as u16,
+      DoipPayload::EntityStatusRequest => EntityStatusPayloadType::EntityStatusRequest as u16,
+      DoipPayload::EntityStatusResponse(_) => EntityStatusPayloadType::EntityStatusResponse as u16,
+      DoipPayload::PowerModeInfoRequest => EntityStatusPayloadType::PowerModeInfoRequest as u16,
+      DoipPayload::PowerModeInfoResponse(_) => {
+        EntityStatusPayloadType::PowerModeInfoResponse as u16
+      }
+      DoipPayload::DiagnosticMessage(_) => DiagnosticPayloadType::DiagnosticMessage as u16,
+      DoipPayload::DiagnosticPositiveAck(_) => DiagnosticPayloadType::DiagnosticPositiveAck as u16,
+      DoipPayload::DiagnosticNegativeAck(_) => DiagnosticPayloadType::DiagnosticNegativeAck as u16,
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    match self {
+      DoipPayload::GenericNack(code) => vec![*code],
+      DoipPayload::VehicleIdRequest
+      | DoipPayload::AliveCheckRequest
+      | DoipPayload::EntityStatusRequest
+      | DoipPayload::PowerModeInfoRequest => Vec::new(),
+      DoipPayload::VehicleIdRequestWithEid(eid) => eid.to_vec(),
+      DoipPayload::VehicleIdRequestWithVin(vin) => vin.to_vec(),
+      DoipPayload::VehicleAnnouncement(announcement) => announcement.encode(),
+      DoipPayload::RoutingActivationRequest(request) => request.encode(),
+      DoipPayload::RoutingActivationResponse(response) => response.encode(),
+      DoipPayload::AliveCheckResponse(source_address) => source_address.to_be_bytes().to_vec(),
+      DoipPayload::EntityStatusResponse(status) => status.encode(),
+      DoipPayload::PowerModeInfoResponse(power_mode) => vec![*power_mode],
+      DoipPayload::DiagnosticMessage(message) => message.encode(),
+      DoipPayload::DiagnosticPositiveAck(ack) | DoipPayload::DiagnosticNegativeAck(ack) => {
+        ack.encode()
+      }
+    }
+  }
+}
+
+// A complete DoIP message: generic header protocol version and payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoipMessage {
+  pub version: u8,
+  pub payload: DoipPayload,
+}
+
+impl DoipMessage {
+  pub fn new(version: u8, payload: DoipPayload) -> Self {
+    Self { version, payload }
+  }
+
+  // Generic header followed by the payload
+  pub fn encode(&self) -> Vec<u8> {
+    encode_doip_message(
+      self.version,
+      self.payload.payload_type(),
+      Some(&self.payload.encode()),
+    )
+  }
+}
+
+pub fn encode_doip_message(version: u8, payload_type: u16, uds_msg: Option<&[u8]>) -> Vec<u8> {
+  let mut message = vec![version, !version];
+
+  message.extend_from_slice(&payload_type.to_be_bytes());
+
+  let payload = uds_msg.unwrap_or(&[]);
+  let payload_len = payload.len() as u32;
+  message.extend_from_slice(&payload_len.to_be_bytes());
+
+  message.extend_from_slice(payload);
+
+  message
+}
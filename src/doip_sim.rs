@@ -0,0 +1,761 @@
+use log::info;
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+use crate::common::serde_hex::{hex_bytes, hex_number, hex_numbers, optional_hex_number};
+use crate::common::unity::parse_hex_string_to_bytes;
+use crate::doip_entity::{DoipEntityConfig, Responder, UdsHandler, UdsRequest};
+use crate::uds_client::{
+  DEFAULT_SESSION, NEGATIVE_RESPONSE_SID, POSITIVE_RESPONSE_OFFSET, SUPPRESS_POSITIVE_RESPONSE,
+};
+use crate::uds_nrc::Nrc;
+use crate::uds_service::ALL_DTC_GROUPS;
+
+// DTCStatusAvailabilityMask: every status bit is supported
+static DTC_STATUS_AVAILABILITY_MASK: u8 = 0xFF;
+// DTCFormatIdentifier ISO 14229-1
+static DTC_FORMAT_ISO_14229_1: u8 = 0x01;
+
+// Services with a sub-function whose positive response can be suppressed
+static SUPPRESSIBLE_SERVICES: [u8; 5] = [0x10, 0x11, 0x28, 0x3E, 0x85];
+// Services only available outside the default session
+static NON_DEFAULT_SESSION_SERVICES: [u8; 4] = [0x27, 0x28, 0x2E, 0x85];
+
+pub static DEFAULT_SIM_P2_MS: u16 = 50;
+pub static DEFAULT_SIM_P2_STAR_MS: u32 = 5000;
+// P2* is reported in units of 10ms, a shorter one would be sent as 0
+pub static MIN_SIM_P2_STAR_MS: u32 = 10;
+pub static MAX_SIM_P2_STAR_MS: u32 = u16::MAX as u32 * 10;
+// S3 server timer: a non-default session falls back to the default session after this
+// long without a request
+pub static DEFAULT_S3_MS: u64 = 5000;
+pub static DEFAULT_SECURITY_MAX_ATTEMPTS: u32 = 3;
+pub static DEFAULT_SECURITY_LOCKOUT_MS: u64 = 10_000;
+
+fn optional_hex_id<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Option<[u8; 6]>, D::Error> {
+  let Some(hex_str) = Option::<String>::deserialize(deserializer)? else {
+    return Ok(None);
+  };
+  let bytes = parse_hex_string_to_bytes(&hex_str).map_err(de::Error::custom)?;
+  <[u8; 6]>::try_from(bytes.as_slice())
+    .map(Some)
+    .map_err(|_| de::Error::custom(format!("{} must be 6 bytes", hex_str)))
+}
+
+fn default_address() -> String {
+  "127.0.0.1".to_string()
+}
+
+fn default_sessions() -> Vec<u8> {
+  vec![0x01, 0x02, 0x03]
+}
+
+fn default_security_sessions() -> Vec<u8> {
+  vec![0x03]
+}
+
+fn default_max_attempts() -> u32 {
+  DEFAULT_SECURITY_MAX_ATTEMPTS
+}
+
+fn default_lockout_ms() -> u64 {
+  DEFAULT_SECURITY_LOCKOUT_MS
+}
+
+fn default_s3_ms() -> u64 {
+  DEFAULT_S3_MS
+}
+
+fn default_p2_ms() -> u16 {
+  DEFAULT_SIM_P2_MS
+}
+
+fn default_p2_star_ms() -> u32 {
+  DEFAULT_SIM_P2_STAR_MS
+}
+
+// Vehicle description file: a gateway DoIP entity and the ECUs behind it. Numbers and
+// byte strings are hex strings such as "0x1001", like in the HTTP API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VehicleDescription {
+  // Address the entity listens on
+  #[serde(default = "default_address")]
+  pub address: String,
+  pub vin: String,
+  #[serde(default, deserialize_with = "optional_hex_id")]
+  pub eid: Option<[u8; 6]>,
+  #[serde(default, deserialize_with = "optional_hex_id")]
+  pub gid: Option<[u8; 6]>,
+  // Logical address of the gateway, the entity testers activate routing with
+  #[serde(deserialize_with = "hex_number")]
+  pub gateway_address: u16,
+  // Tester addresses routing is activated for, every address when empty
+  #[serde(default, deserialize_with = "hex_numbers")]
+  pub accepted_testers: Vec<u16>,
+  #[serde(default, deserialize_with = "optional_hex_number")]
+  pub protocol_version: Option<u8>,
+  pub ecus: Vec<EcuDescription>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EcuDescription {
+  pub name: String,
+  #[serde(deserialize_with = "hex_number")]
+  pub logical_address: u16,
+  // Diagnostic sessions DiagnosticSessionControl accepts
+  #[serde(default = "default_sessions", deserialize_with = "hex_numbers")]
+  pub sessions: Vec<u8>,
+  // Timing reported in the DiagnosticSessionControl response
+  #[serde(default = "default_p2_ms")]
+  pub p2_ms: u16,
+  #[serde(default = "default_p2_star_ms")]
+  pub p2_star_ms: u32,
+  #[serde(default = "default_s3_ms")]
+  pub s3_ms: u64,
+  #[serde(default)]
+  pub security_levels: Vec<SecurityLevelDescription>,
+  #[serde(default)]
+  pub dids: Vec<DidDescription>,
+  #[serde(default)]
+  pub dtcs: Vec<DtcDescription>,
+  #[serde(default)]
+  pub routines: Vec<RoutineDescription>,
+}
+
+// A SecurityAccess level. The seed is as long as `key_mask` and the expected key is
+// seed XOR key_mask.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityLevelDescription {
+  // Odd requestSeed sub-function, sendKey is level + 1
+  #[serde(deserialize_with = "hex_number")]
+  pub level: u8,
+  #[serde(deserialize_with = "hex_bytes")]
+  pub key_mask: Vec<u8>,
+  // Sessions the level can be unlocked in
+  #[serde(
+    default = "default_security_sessions",
+    deserialize_with = "hex_numbers"
+  )]
+  pub sessions: Vec<u8>,
+  // Invalid keys before the level is locked out
+  #[serde(default = "default_max_attempts")]
+  pub max_attempts: u32,
+  #[serde(default = "default_lockout_ms")]
+  pub lockout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DidDescription {
+  #[serde(deserialize_with = "hex_number")]
+  pub id: u16,
+  #[serde(deserialize_with = "hex_bytes")]
+  pub value: Vec<u8>,
+  #[serde(default)]
+  pub writable: bool,
+  // Sessions the DID is available in, every session when empty
+  #[serde(default, deserialize_with = "hex_numbers")]
+  pub sessions: Vec<u8>,
+  // Security level that has to be unlocked to access the DID
+  #[serde(default, deserialize_with = "optional_hex_number")]
+  pub security_level: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DtcDescription {
+  // 3 byte DTC number
+  #[serde(deserialize_with = "hex_number")]
+  pub code: u32,
+  #[serde(deserialize_with = "hex_number")]
+  pub status: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutineDescription {
+  #[serde(deserialize_with = "hex_number")]
+  pub id: u16,
+  // Time the routine takes to start, answered with 0x78 response pending meanwhile
+  #[serde(default)]
+  pub duration_ms: u64,
+  // routineStatusRecord returned for start and results
+  #[serde(default, deserialize_with = "hex_bytes")]
+  pub result: Vec<u8>,
+  #[serde(default, deserialize_with = "hex_numbers")]
+  pub sessions: Vec<u8>,
+  #[serde(default, deserialize_with = "optional_hex_number")]
+  pub security_level: Option<u8>,
+}
+
+fn invalid_description(message: String) -> Error {
+  Error::new(ErrorKind::InvalidData, message)
+}
+
+impl VehicleDescription {
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+    let path = path.as_ref();
+    let json = std::fs::read_to_string(path)?;
+    Self::from_json(&json).map_err(|e| invalid_description(format!("{}: {}", path.display(), e)))
+  }
+
+  pub fn from_json(json: &str) -> Result<Self, Error> {
+    let description: Self =
+      serde_json::from_str(json).map_err(|e| invalid_description(e.to_string()))?;
+    description.validate()?;
+    Ok(description)
+  }
+
+  fn validate(&self) -> Result<(), Error> {
+    if self.vin.len() != 17 {
+      return Err(invalid_description(format!(
+        "VIN {} must be 17 characters",
+        self.vin
+      )));
+    }
+    if self.ecus.is_empty() {
+      return Err(invalid_description("No ECUs described".to_string()));
+    }
+
+    let mut addresses = HashSet::new();
+    for ecu in &self.ecus {
+      if !addresses.insert(ecu.logical_address) {
+        return Err(invalid_description(format!(
+          "Logical address 0x{:04X} is used by more than one ECU",
+          ecu.logical_address
+        )));
+      }
+      if !ecu.sessions.contains(&DEFAULT_SESSION) {
+        return Err(invalid_description(format!(
+          "ECU {} must support the default session 0x01",
+          ecu.name
+        )));
+      }
+      if !(MIN_SIM_P2_STAR_MS..=MAX_SIM_P2_STAR_MS).contains(&ecu.p2_star_ms) {
+        return Err(invalid_description(format!(
+          "ECU {}: P2* of {}ms must be from {}ms to {}ms",
+          ecu.name, ecu.p2_star_ms, MIN_SIM_P2_STAR_MS, MAX_SIM_P2_STAR_MS
+        )));
+      }
+      for security in &ecu.security_levels {
+        if security.level % 2 == 0 || security.level > 0x7D || security.key_mask.is_empty() {
+          return Err(invalid_description(format!(
+            "ECU {}: security level 0x{:02X} needs an odd level below 0x7E and a key mask",
+            ecu.name, security.level
+          )));
+        }
+      }
+      if let Some(did) = ecu.dids.iter().find(|did| did.value.is_empty()) {
+        return Err(invalid_description(format!(
+          "ECU {}: DID 0x{:04X} has no value",
+          ecu.name, did.id
+        )));
+      }
+      if let Some(dtc) = ecu.dtcs.iter().find(|dtc| dtc.code > ALL_DTC_GROUPS) {
+        return Err(invalid_description(format!(
+          "ECU {}: DTC 0x{:X} is longer than 3 bytes",
+          ecu.name, dtc.code
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  // Entity configuration serving the described gateway and ECUs
+  pub fn entity_config(&self) -> Result<DoipEntityConfig, Error> {
+    let address = self
+      .address
+      .parse::<IpAddr>()
+      .map_err(|_| invalid_description(format!("Invalid address {}", self.address)))?;
+
+    let defaults = DoipEntityConfig::default();
+    Ok(DoipEntityConfig {
+      address,
+      protocol_version: self.protocol_version.unwrap_or(defaults.protocol_version),
+      logical_address: self.gateway_address,
+      vin: self.vin.clone(),
+      eid: self.eid.unwrap_or(defaults.eid),
+      gid: self.gid.unwrap_or(defaults.gid),
+      target_addresses: self.ecus.iter().map(|ecu| ecu.logical_address).collect(),
+      accepted_testers: self.accepted_testers.clone(),
+      ..defaults
+    })
+  }
+}
+
+// A positive reply of a simulated service
+enum Reply {
+  Positive(Vec<u8>),
+  // Answered with 0x78 response pending until the duration has elapsed
+  Delayed(Duration, Vec<u8>),
+}
+
+type ServiceResult = Result<Reply, Nrc>;
+
+// Mutable UDS state of one ECU
+struct EcuState {
+  session: u8,
+  last_request: Instant,
+  // Unlocked security level
+  security_level: Option<u8>,
+  // Level and seed of the last requestSeed, consumed by sendKey
+  seed: Option<(u8, Vec<u8>)>,
+  failed_attempts: u32,
+  locked_until: Option<Instant>,
+  dids: HashMap<u16, Vec<u8>>,
+  dtcs: Vec<(u32, u8)>,
+  started_routines: HashSet<u16>,
+}
+
+impl EcuState {
+  fn new(description: &EcuDescription) -> Self {
+    Self {
+      session: DEFAULT_SESSION,
+      last_request: Instant::now(),
+      security_level: None,
+      seed: None,
+      failed_attempts: 0,
+      locked_until: None,
+      dids: description
+        .dids
+        .iter()
+        .map(|did| (did.id, did.value.clone()))
+        .collect(),
+      dtcs: description
+        .dtcs
+        .iter()
+        .map(|dtc| (dtc.code, dtc.status))
+        .collect(),
+      started_routines: HashSet::new(),
+    }
+  }
+
+  // Back to the default session, which locks every security level
+  fn enter_default_session(&mut self) {
+    self.session = DEFAULT_SESSION;
+    self.security_level = None;
+    self.seed = None;
+  }
+
+  fn check_access(&self, sessions: &[u8], security_level: Option<u8>) -> Result<(), Nrc> {
+    if !sessions.is_empty() && !sessions.contains(&self.session) {
+      return Err(Nrc::RequestOutOfRange);
+    }
+    if security_level.is_some() && self.security_level != security_level {
+      return Err(Nrc::SecurityAccessDenied);
+    }
+    Ok(())
+  }
+}
+
+// Seed bytes that are never zero, an all zero seed means the level is already unlocked
+fn generate_seed(len: usize) -> Vec<u8> {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_nanos() as u64;
+  let mut state = nanos | 1;
+  (0..len)
+    .map(|_| {
+      // xorshift64
+      state ^= state << 13;
+      state ^= state >> 7;
+      state ^= state << 17;
+      (state as u8) | 0x01
+    })
+    .collect()
+}
+
+struct SimulatedEcu {
+  description: EcuDescription,
+  state: Mutex<EcuState>,
+}
+
+impl SimulatedEcu {
+  fn process(&self, data: &[u8]) -> ServiceResult {
+    let mut state = self.state.lock().unwrap();
+    let now = Instant::now();
+    if state.session != DEFAULT_SESSION
+      && now.duration_since(state.last_request) > Duration::from_millis(self.description.s3_ms)
+    {
+      info!(
+        "DoipSim: [{}] S3 timeout, back to the default session",
+        self.description.name
+      );
+      state.enter_default_session();
+    }
+    state.last_request = now;
+
+    let service_id = data[0];
+    if state.session == DEFAULT_SESSION && NON_DEFAULT_SESSION_SERVICES.contains(&service_id) {
+      return Err(Nrc::ServiceNotSupportedInActiveSession);
+    }
+
+    match service_id {
+      0x10 => self.diagnostic_session_control(&mut state, data),
+      0x11 => self.ecu_reset(&mut state, data),
+      0x14 => self.clear_diagnostic_information(&mut state, data),
+      0x19 => self.read_dtc_information(&state, data),
+      0x22 => self.read_data_by_identifier(&state, data),
+      0x27 => self.security_access(&mut state, data),
+      0x28 => sub_function_reply(data, 0x00..=0x03, 3),
+      0x2E => self.write_data_by_identifier(&mut state, data),
+      0x31 => self.routine_control(&mut state, data),
+      0x3E => sub_function_reply(data, 0x00..=0x00, 2),
+      0x85 => sub_function_reply(data, 0x01..=0x02, 2),
+      _ => Err(Nrc::ServiceNotSupported),
+    }
+  }
+
+  fn diagnostic_session_control(&self, state: &mut EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() != 2 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let session = data[1] & !SUPPRESS_POSITIVE_RESPONSE;
+    if !self.description.sessions.contains(&session) {
+      return Err(Nrc::SubFunctionNotSupported);
+    }
+    if session != state.session {
+      info!(
+        "DoipSim: [{}] session 0x{:02X} -> 0x{:02X}",
+        self.description.name, state.session, session
+      );
+      state.enter_default_session();
+      state.session = session;
+    }
+
+    // P2*server is reported in units of 10ms
+    let mut response = vec![0x50, session];
+    response.extend_from_slice(&self.description.p2_ms.to_be_bytes());
+    response.extend_from_slice(&((self.description.p2_star_ms / 10) as u16).to_be_bytes());
+    Ok(Reply::Positive(response))
+  }
+
+  fn ecu_reset(&self, state: &mut EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() != 2 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let reset_type = data[1] & !SUPPRESS_POSITIVE_RESPONSE;
+    if !(0x01..=0x03).contains(&reset_type) {
+      return Err(Nrc::SubFunctionNotSupported);
+    }
+    info!(
+      "DoipSim: [{}] reset 0x{:02X}",
+      self.description.name, reset_type
+    );
+    state.enter_default_session();
+    state.started_routines.clear();
+    Ok(Reply::Positive(vec![0x51, reset_type]))
+  }
+
+  fn clear_diagnostic_information(&self, state: &mut EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() != 4 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let group = u32::from_be_bytes([0, data[1], data[2], data[3]]);
+    let mut cleared = false;
+    for (code, status) in state.dtcs.iter_mut() {
+      if group == ALL_DTC_GROUPS || group == *code {
+        *status = 0x00;
+        cleared = true;
+      }
+    }
+    if !cleared && group != ALL_DTC_GROUPS {
+      return Err(Nrc::RequestOutOfRange);
+    }
+    Ok(Reply::Positive(vec![0x54]))
+  }
+
+  fn read_dtc_information(&self, state: &EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() < 2 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let report_type = data[1] & !SUPPRESS_POSITIVE_RESPONSE;
+    let by_mask = |mask: u8| {
+      state
+        .dtcs
+        .iter()
+        .filter(move |(_, status)| status & mask != 0)
+    };
+
+    let mut response = vec![0x59, report_type, DTC_STATUS_AVAILABILITY_MASK];
+    match report_type {
+      // reportNumberOfDTCByStatusMask
+      0x01 if data.len() == 3 => {
+        let count = by_mask(data[2]).count() as u16;
+        response.push(DTC_FORMAT_ISO_14229_1);
+        response.extend_from_slice(&count.to_be_bytes());
+      }
+      // reportDTCByStatusMask
+      0x02 if data.len() == 3 => {
+        for (code, status) in by_mask(data[2]) {
+          response.extend_from_slice(&code.to_be_bytes()[1..]);
+          response.push(*status);
+        }
+      }
+      // reportSupportedDTC
+      0x0A if data.len() == 2 => {
+        for (code, status) in &state.dtcs {
+          response.extend_from_slice(&code.to_be_bytes()[1..]);
+          response.push(*status);
+        }
+      }
+      0x01 | 0x02 | 0x0A => return Err(Nrc::IncorrectMessageLengthOrInvalidFormat),
+      _ => return Err(Nrc::SubFunctionNotSupported),
+    }
+    Ok(Reply::Positive(response))
+  }
+
+  fn read_data_by_identifier(&self, state: &EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() < 3 || data.len().is_multiple_of(2) {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let mut response = vec![0x62];
+    for id in data[1..].chunks(2) {
+      let id = u16::from_be_bytes([id[0], id[1]]);
+      let did = self
+        .description
+        .dids
+        .iter()
+        .find(|did| did.id == id)
+        .ok_or(Nrc::RequestOutOfRange)?;
+      state.check_access(&did.sessions, did.security_level)?;
+      response.extend_from_slice(&id.to_be_bytes());
+      response.extend_from_slice(&state.dids[&id]);
+    }
+    Ok(Reply::Positive(response))
+  }
+
+  fn write_data_by_identifier(&self, state: &mut EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() < 4 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let id = u16::from_be_bytes([data[1], data[2]]);
+    let did = self
+      .description
+      .dids
+      .iter()
+      .find(|did| did.id == id && did.writable)
+      .ok_or(Nrc::RequestOutOfRange)?;
+    state.check_access(&did.sessions, did.security_level)?;
+
+    let value = &data[3..];
+    if value.len() != did.value.len() {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    state.dids.insert(id, value.to_vec());
+    Ok(Reply::Positive(vec![0x6E, data[1], data[2]]))
+  }
+
+  fn security_access(&self, state: &mut EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() < 2 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let sub_function = data[1] & !SUPPRESS_POSITIVE_RESPONSE;
+    let request_seed = sub_function % 2 == 1;
+    // Sub-function 0x00 is reserved, it would underflow to a level
+    let level = if request_seed {
+      sub_function
+    } else {
+      sub_function
+        .checked_sub(1)
+        .ok_or(Nrc::SubFunctionNotSupported)?
+    };
+    let security = self
+      .description
+      .security_levels
+      .iter()
+      .find(|security| security.level == level)
+      .ok_or(Nrc::SubFunctionNotSupported)?;
+    if !security.sessions.contains(&state.session) {
+      return Err(Nrc::SubFunctionNotSupportedInActiveSession);
+    }
+    if let Some(locked_until) = state.locked_until {
+      if Instant::now() < locked_until {
+        return Err(Nrc::RequiredTimeDelayNotExpired);
+      }
+      state.locked_until = None;
+    }
+
+    let mut response = vec![0x67, sub_function];
+    if request_seed {
+      if data.len() != 2 {
+        return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+      }
+      if state.security_level == Some(level) {
+        response.resize(2 + security.key_mask.len(), 0x00);
+      } else {
+        let seed = generate_seed(security.key_mask.len());
+        response.extend_from_slice(&seed);
+        state.seed = Some((level, seed));
+      }
+      return Ok(Reply::Positive(response));
+    }
+
+    let seed = match state.seed.take() {
+      Some((seed_level, seed)) if seed_level == level => seed,
+      _ => return Err(Nrc::RequestSequenceError),
+    };
+    let expected: Vec<u8> = seed
+      .iter()
+      .zip(&security.key_mask)
+      .map(|(seed, mask)| seed ^ mask)
+      .collect();
+    if data[2..] != expected[..] {
+      state.failed_attempts += 1;
+      if state.failed_attempts >= security.max_attempts {
+        info!(
+          "DoipSim: [{}] security level 0x{:02X} locked for {}ms",
+          self.description.name, level, security.lockout_ms
+        );
+        state.failed_attempts = 0;
+        state.locked_until = Some(Instant::now() + Duration::from_millis(security.lockout_ms));
+        return Err(Nrc::ExceededNumberOfAttempts);
+      }
+      return Err(Nrc::InvalidKey);
+    }
+
+    info!(
+      "DoipSim: [{}] security level 0x{:02X} unlocked",
+      self.description.name, level
+    );
+    state.failed_attempts = 0;
+    state.security_level = Some(level);
+    Ok(Reply::Positive(response))
+  }
+
+  fn routine_control(&self, state: &mut EcuState, data: &[u8]) -> ServiceResult {
+    if data.len() < 4 {
+      return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+    }
+    let control_type = data[1] & !SUPPRESS_POSITIVE_RESPONSE;
+    if !(0x01..=0x03).contains(&control_type) {
+      return Err(Nrc::SubFunctionNotSupported);
+    }
+    let id = u16::from_be_bytes([data[2], data[3]]);
+    let routine = self
+      .description
+      .routines
+      .iter()
+      .find(|routine| routine.id == id)
+      .ok_or(Nrc::RequestOutOfRange)?;
+    state.check_access(&routine.sessions, routine.security_level)?;
+
+    let mut response = vec![0x71, control_type, data[2], data[3]];
+    match control_type {
+      // startRoutine
+      0x01 => {
+        state.started_routines.insert(id);
+        response.extend_from_slice(&routine.result);
+        if routine.duration_ms > 0 {
+          return Ok(Reply::Delayed(
+            Duration::from_millis(routine.duration_ms),
+            response,
+          ));
+        }
+      }
+      // stopRoutine and requestRoutineResults
+      _ => {
+        if !state.started_routines.contains(&id) {
+          return Err(Nrc::RequestSequenceError);
+        }
+        if control_type == 0x02 {
+          state.started_routines.remove(&id);
+        } else {
+          response.extend_from_slice(&routine.result);
+        }
+      }
+    }
+    Ok(Reply::Positive(response))
+  }
+}
+
+// Positive response of a service that only echoes its sub-function
+fn sub_function_reply(
+  data: &[u8],
+  sub_functions: std::ops::RangeInclusive<u8>,
+  len: usize,
+) -> ServiceResult {
+  if data.len() != len {
+    return Err(Nrc::IncorrectMessageLengthOrInvalidFormat);
+  }
+  let sub_function = data[1] & !SUPPRESS_POSITIVE_RESPONSE;
+  if !sub_functions.contains(&sub_function) {
+    return Err(Nrc::SubFunctionNotSupported);
+  }
+  Ok(Reply::Positive(vec![
+    data[0] + POSITIVE_RESPONSE_OFFSET,
+    sub_function,
+  ]))
+}
+
+// UDS handler simulating the ECUs of a vehicle description
+pub struct VirtualVehicle {
+  ecus: HashMap<u16, SimulatedEcu>,
+}
+
+impl VirtualVehicle {
+  pub fn new(description: &VehicleDescription) -> Self {
+    let ecus = description
+      .ecus
+      .iter()
+      .map(|ecu| {
+        let simulated = SimulatedEcu {
+          description: ecu.clone(),
+          state: Mutex::new(EcuState::new(ecu)),
+        };
+        (ecu.logical_address, simulated)
+      })
+      .collect();
+    Self { ecus }
+  }
+}
+
+impl UdsHandler for VirtualVehicle {
+  async fn handle(&self, request: UdsRequest, responder: Responder) -> Option<Vec<u8>> {
+    // The entity only forwards requests for the described ECUs
+    let ecu = self.ecus.get(&request.target_address)?;
+    let service_id = *request.data.first()?;
+
+    let reply = match ecu.process(&request.data) {
+      Ok(reply) => reply,
+      Err(nrc) => return Some(vec![NEGATIVE_RESPONSE_SID, service_id, nrc.code()]),
+    };
+
+    match reply {
+      Reply::Positive(response) => {
+        let suppress = SUPPRESSIBLE_SERVICES.contains(&service_id)
+          && request.data[1] & SUPPRESS_POSITIVE_RESPONSE != 0;
+        (!suppress).then_some(response)
+      }
+      Reply::Delayed(duration, response) => {
+        // Repeat the response pending well within the tester's P2* timeout
+        let interval = Duration::from_millis((ecu.description.p2_star_ms as u64 / 2).max(1));
+        let done = Instant::now() + duration;
+        loop {
+          let pending = vec![
+            NEGATIVE_RESPONSE_SID,
+            service_id,
+            Nrc::RequestCorrectlyReceivedResponsePending.code(),
+          ];
+          responder.send(pending).await.ok()?;
+          let remaining = done.saturating_duration_since(Instant::now());
+          if remaining.is_zero() {
+            break;
+          }
+          sleep(remaining.min(interval)).await;
+          if Instant::now() >= done {
+            break;
+          }
+        }
+        Some(response)
+      }
+    }
+  }
+}
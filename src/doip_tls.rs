@@ -0,0 +1,130 @@
+use log::{info, warn};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use serde::Deserialize;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+// ISO 13400-2:2019 port for TLS secured diagnostic connections
+pub static DOIP_TLS_PORT: u16 = 3496;
+
+// TLS settings of a secured DoIP connection, file paths point to PEM files
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DoipTlsConfig {
+  // CA bundle used to verify the DoIP entity's certificate
+  pub ca_file: String,
+  // Client certificate chain and key for mutual authentication
+  #[serde(default)]
+  pub cert_file: Option<String>,
+  #[serde(default)]
+  pub key_file: Option<String>,
+  // Allowed cipher suites by IANA name (e.g. TLS13_AES_128_GCM_SHA256), all
+  // suites supported by rustls when empty
+  #[serde(default)]
+  pub cipher_suites: Vec<String>,
+  // Name to verify the entity certificate against, the ECU address by default
+  #[serde(default)]
+  pub server_name: Option<String>,
+}
+
+fn invalid_input(message: String) -> Error {
+  Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+  let certificates = CertificateDer::pem_file_iter(path)
+    .map_err(|e| invalid_input(format!("Failed to read certificates from {}: {}", path, e)))?
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| invalid_input(format!("Invalid certificate in {}: {}", path, e)))?;
+
+  if certificates.is_empty() {
+    return Err(invalid_input(format!("No certificates found in {}", path)));
+  }
+  Ok(certificates)
+}
+
+fn crypto_provider(cipher_suites: &[String]) -> Result<CryptoProvider, Error> {
+  let mut provider = ring::default_provider();
+  if cipher_suites.is_empty() {
+    return Ok(provider);
+  }
+
+  let mut selected = Vec::with_capacity(cipher_suites.len());
+  for name in cipher_suites {
+    match provider
+      .cipher_suites
+      .iter()
+      .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+    {
+      Some(suite) => selected.push(*suite),
+      None => return Err(invalid_input(format!("Unsupported cipher suite: {}", name))),
+    }
+  }
+  provider.cipher_suites = selected;
+  Ok(provider)
+}
+
+pub fn build_client_config(config: &DoipTlsConfig) -> Result<Arc<ClientConfig>, Error> {
+  let mut roots = RootCertStore::empty();
+  for certificate in load_certificates(&config.ca_file)? {
+    roots
+      .add(certificate)
+      .map_err(|e| invalid_input(format!("Invalid CA certificate: {}", e)))?;
+  }
+
+  let builder =
+    ClientConfig::builder_with_provider(Arc::new(crypto_provider(&config.cipher_suites)?))
+      .with_safe_default_protocol_versions()
+      .map_err(|e| invalid_input(format!("Invalid TLS configuration: {}", e)))?
+      .with_root_certificates(roots);
+
+  let client_config = match (&config.cert_file, &config.key_file) {
+    (Some(cert_file), Some(key_file)) => {
+      let certificates = load_certificates(cert_file)?;
+      let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| invalid_input(format!("Failed to read key from {}: {}", key_file, e)))?;
+      builder
+        .with_client_auth_cert(certificates, key)
+        .map_err(|e| invalid_input(format!("Invalid client certificate: {}", e)))?
+    }
+    (None, None) => builder.with_no_client_auth(),
+    _ => {
+      return Err(invalid_input(
+        "Client certificate and key must be given together".to_string(),
+      ));
+    }
+  };
+
+  Ok(Arc::new(client_config))
+}
+
+// Run the TLS handshake on an established TCP connection to the entity
+pub async fn connect_tls(
+  stream: TcpStream,
+  ecu_ip: &str,
+  config: &DoipTlsConfig,
+) -> Result<TlsStream<TcpStream>, Error> {
+  let client_config = build_client_config(config)?;
+  let name = config.server_name.as_deref().unwrap_or(ecu_ip).to_string();
+  let server_name = ServerName::try_from(name.clone())
+    .map_err(|e| invalid_input(format!("Invalid TLS server name {}: {}", name, e)))?;
+
+  let stream = TlsConnector::from(client_config)
+    .connect(server_name, stream)
+    .await
+    .inspect_err(|e| warn!("DoipTls: Handshake with {} failed: {}", name, e))?;
+
+  let (_, session) = stream.get_ref();
+  info!(
+    "DoipTls: Secured connection to {} using {:?} {:?}",
+    name,
+    session.protocol_version(),
+    session.negotiated_cipher_suite().map(|suite| suite.suite())
+  );
+  Ok(stream)
+}
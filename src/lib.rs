@@ -0,0 +1,15 @@
+pub mod common;
+pub mod connection;
+pub mod doip2http;
+pub mod doip_client;
+pub mod doip_codec;
+pub mod doip_discovery;
+pub mod doip_entity;
+pub mod doip_message;
+pub mod doip_sim;
+pub mod doip_tls;
+pub mod uds_client;
+pub mod uds_key_provider;
+pub mod uds_nrc;
+pub mod uds_security;
+pub mod uds_service;
@@ -1,9 +1,8 @@
-mod common;
-mod doip2http;
-mod doip_client;
-mod uds_client;
-
+use ::doip2http::common::net::LocalBinding;
+use ::doip2http::doip2http::{self, ServerConfig};
+use ::doip2http::uds_security::KeyAlgorithms;
 use std::env;
+use std::time::Duration;
 
 static DEFAULT_PORT: u16 = 8080;
 
@@ -15,8 +14,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     .parse::<u16>()
     .unwrap_or(DEFAULT_PORT);
 
+  // Default local address and interface for DoIP traffic, /connect and the UDP
+  // endpoints may choose their own
+  let local_ip = env::var("DOIP_LOCAL_IP").ok().filter(|ip| !ip.is_empty());
+  let interface = env::var("DOIP_INTERFACE")
+    .ok()
+    .filter(|name| !name.is_empty());
+  let local_binding = LocalBinding::default()
+    .with_overrides(local_ip.as_deref(), interface.as_deref())
+    .await?;
+
+  // Close connections without requests for this long, 0 or unset keeps them open
+  let idle_timeout = env::var("DOIP_IDLE_TIMEOUT_MS")
+    .ok()
+    .and_then(|idle_timeout| idle_timeout.parse::<u64>().ok())
+    .filter(|idle_timeout| *idle_timeout > 0)
+    .map(Duration::from_millis);
+
+  // Seed/key algorithms of the ECUs for /security_access
+  let key_algorithms = match env::var("DOIP_SECURITY_CONFIG") {
+    Ok(path) if !path.is_empty() => KeyAlgorithms::load(path)?,
+    _ => KeyAlgorithms::default(),
+  };
+
   // Start the HTTP server
-  doip2http::run_server(port).await?;
+  doip2http::run_server(
+    port,
+    ServerConfig {
+      local_binding,
+      idle_timeout,
+      key_algorithms,
+    },
+  )
+  .await?;
 
   Ok(())
 }
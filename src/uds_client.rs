@@ -1,13 +1,33 @@
-use crate::doip_client::DEFAULT_ACTIVATION_RESERVED;
-use crate::doip_client::DEFAULT_ACTIVATION_TYPE;
-use crate::doip_client::DiagnosticPayloadType;
 use crate::doip_client::DoipClient;
-use crate::doip_client::VehicleConnectionPayloadType;
-use log::{debug, error, info, trace, warn};
+use crate::doip_client::DoipConfig;
+use crate::doip_client::DoipError;
+use crate::doip_message::{DiagnosticMessage, RoutingActivationResponse};
+use crate::uds_nrc::Nrc;
+use log::{debug, info, warn};
 use once_cell::sync::Lazy;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Error, ErrorKind};
-use tokio::io::AsyncWriteExt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+// Default P2 client timeout: wait for the first response after the DoIP ACK
+pub static DEFAULT_P2_TIMEOUT_MS: u64 = 2000;
+// Default P2* client timeout: wait after each 0x78 response pending
+pub static DEFAULT_P2_STAR_TIMEOUT_MS: u64 = 5000;
+
+// Default delay before the first reconnection attempt, doubled after each failure
+pub static DEFAULT_RECONNECT_INITIAL_DELAY_MS: u64 = 1000;
+// Default upper bound of the delay between reconnection attempts
+pub static DEFAULT_RECONNECT_MAX_DELAY_MS: u64 = 30000;
+
+pub static NEGATIVE_RESPONSE_SID: u8 = 0x7F;
+pub static POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+pub static DEFAULT_SESSION: u8 = 0x01;
+pub static SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+// Connection events kept for /status, older ones are dropped
+static MAX_CONNECTION_EVENTS: usize = 20;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -62,69 +82,593 @@ pub static UDS_SERVICE_SET: Lazy<HashSet<u8>> = Lazy::new(|| {
   ])
 });
 
+// Name of a UDS service for logs and the HTTP API
+pub fn service_name(service_id: u8) -> &'static str {
+  match service_id {
+    0x10 => "DiagnosticSessionControl",
+    0x11 => "ECUReset",
+    0x14 => "ClearDiagnosticInformation",
+    0x19 => "ReadDTCInformation",
+    0x22 => "ReadDataByIdentifier",
+    0x23 => "ReadMemoryByAddress",
+    0x27 => "SecurityAccess",
+    0x28 => "CommunicationControl",
+    0x2E => "WriteDataByIdentifier",
+    0x31 => "RoutineControl",
+    0x34 => "RequestDownload",
+    0x35 => "RequestUpload",
+    0x36 => "TransferData",
+    0x37 => "RequestTransferExit",
+    0x3D => "WriteMemoryByAddress",
+    0x3E => "TesterPresent",
+    0x83 => "AccessTimingParameter",
+    0x84 => "SecuredDataTransmission",
+    0x85 => "ControlDTCSetting",
+    0x86 => "ResponseOnEvent",
+    0x87 => "LinkControl",
+    _ => "Unknown",
+  }
+}
+
+// A 7F response: the service the ECU rejected and why
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegativeResponse {
+  pub service_id: u8,
+  pub nrc: Nrc,
+}
+
+impl NegativeResponse {
+  pub fn parse(response: &[u8]) -> Option<Self> {
+    match response {
+      [sid, service_id, nrc, ..] if *sid == NEGATIVE_RESPONSE_SID => Some(Self {
+        service_id: *service_id,
+        nrc: Nrc::from(*nrc),
+      }),
+      _ => None,
+    }
+  }
+}
+
+impl std::fmt::Display for NegativeResponse {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{} (0x{:02X}) rejected: {}",
+      service_name(self.service_id),
+      self.service_id,
+      self.nrc
+    )
+  }
+}
+
+// UDS application layer timing of one connection
+#[derive(Debug, Clone, Copy)]
+pub struct UdsTiming {
+  pub p2: Duration,
+  pub p2_star: Duration,
+}
+
+impl Default for UdsTiming {
+  fn default() -> Self {
+    Self {
+      p2: Duration::from_millis(DEFAULT_P2_TIMEOUT_MS),
+      p2_star: Duration::from_millis(DEFAULT_P2_STAR_TIMEOUT_MS),
+    }
+  }
+}
+
+// What to do when the entity closes the socket, e.g. after an ECU reset
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+  // Reconnect and activate routing again, the connection stays down otherwise
+  pub enabled: bool,
+  // Give up after this many failed attempts, retry until disconnected when None
+  pub max_attempts: Option<u32>,
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+  // Enter the diagnostic session each target ECU was in before the connection was lost
+  pub restore_session: bool,
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      max_attempts: None,
+      initial_delay: Duration::from_millis(DEFAULT_RECONNECT_INITIAL_DELAY_MS),
+      max_delay: Duration::from_millis(DEFAULT_RECONNECT_MAX_DELAY_MS),
+      restore_session: true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+  Connected,
+  // The socket was lost, the supervisor is trying to connect again
+  Reconnecting,
+  // The socket was lost and won't be reconnected
+  Disconnected,
+}
+
+impl ConnectionState {
+  pub fn name(&self) -> &'static str {
+    match self {
+      ConnectionState::Connected => "connected",
+      ConnectionState::Reconnecting => "reconnecting",
+      ConnectionState::Disconnected => "disconnected",
+    }
+  }
+}
+
+// Something that happened to the connection, reported by /status
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent {
+  // Milliseconds since the Unix epoch
+  pub timestamp_ms: u64,
+  // connected, disconnected, reconnect_failed, reconnected, session_restored,
+  // session_restore_failed, gave_up or closed
+  pub event: &'static str,
+  pub message: String,
+}
+
+// Connection state shared between the client and its supervisor task
+struct Link {
+  doip_client: Arc<DoipClient>,
+  routing_activation: RoutingActivationResponse,
+  state: ConnectionState,
+  // Non-default diagnostic session last entered per target ECU
+  sessions: HashMap<u16, u8>,
+  events: VecDeque<ConnectionEvent>,
+  // Start of the last request sent on behalf of a client of the connection
+  last_used: Instant,
+}
+
+impl Link {
+  fn record(&mut self, event: &'static str, message: String) {
+    info!("UdsClient: {}: {}", event, message);
+    if self.events.len() == MAX_CONNECTION_EVENTS {
+      self.events.pop_front();
+    }
+    self.events.push_back(ConnectionEvent {
+      timestamp_ms: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64),
+      event,
+      message,
+    });
+  }
+}
+
+struct Shared {
+  ecu_ip: String,
+  source_address: u16,
+  timing: UdsTiming,
+  doip_config: DoipConfig,
+  reconnect: ReconnectPolicy,
+  link: Mutex<Link>,
+}
+
+impl Shared {
+  fn link(&self) -> MutexGuard<'_, Link> {
+    self.link.lock().unwrap()
+  }
+}
+
 pub struct UdsClient {
-  doip_client: Option<DoipClient>,
+  shared: Arc<Shared>,
+  // Watches the socket and reconnects according to the policy
+  supervisor: JoinHandle<()>,
+}
+
+// Connect to the entity and activate routing
+async fn open(
+  ecu_ip: &str,
   source_address: u16,
+  doip_config: &DoipConfig,
+) -> Result<(DoipClient, RoutingActivationResponse), DoipError> {
+  let doip_client =
+    DoipClient::new(ecu_ip.to_string(), source_address, doip_config.clone()).await?;
+  let routing_activation = doip_client.activate_routing().await?;
+  Ok((doip_client, routing_activation))
 }
 
-impl UdsClient {
-  pub fn new(ecu_ip: String, source_address: u16) -> Self {
-    let mut doip_client = DoipClient::new(ecu_ip);
-    if doip_client.is_connected() {
-      let mut uds_msg = source_address.to_be_bytes().to_vec();
-      uds_msg.push(DEFAULT_ACTIVATION_TYPE);
-      uds_msg.extend_from_slice(&DEFAULT_ACTIVATION_RESERVED.to_be_bytes());
-
-      match doip_client.send_and_receive(
-        VehicleConnectionPayloadType::RoutingActivationRequest as u16,
-        Some(&uds_msg),
-      ) {
-        Ok(response) => {
-          info!("Routing activation response: {:x?}", response);
+// Send one diagnostic message and wait for the final response, P2 applies to the
+// first response and every 0x78 re-arms the wait with P2*
+async fn exchange(
+  doip_client: &DoipClient,
+  timing: &UdsTiming,
+  message: DiagnosticMessage,
+) -> Result<Vec<u8>, DoipError> {
+  let source_address = message.source_address;
+  let service_id = message.user_data[0];
+  let channel = doip_client.target_channel(message.target_address);
+  let mut channel = channel.lock().await;
+  doip_client.send_diagnostic(&mut channel, message).await?;
+
+  let mut deadline = Instant::now() + timing.p2;
+  loop {
+    let wait = deadline.saturating_duration_since(Instant::now());
+    let response = doip_client
+      .receive_diagnostic(&mut channel, source_address, wait)
+      .await?;
+
+    // Late answers to an earlier request, e.g. a negative response to a suppressed
+    // TesterPresent, share the channel and are not this request's response
+    let negative = NegativeResponse::parse(&response);
+    let answers_request = match &negative {
+      Some(negative) => negative.service_id == service_id,
+      None => response.first() == Some(&(service_id + POSITIVE_RESPONSE_OFFSET)),
+    };
+    if !answers_request {
+      debug!(
+        "UdsClient: Dropping response {:02X?} not answering service 0x{:02X}",
+        response, service_id
+      );
+      continue;
+    }
+
+    if let Some(negative) = negative
+      && negative.nrc == Nrc::RequestCorrectlyReceivedResponsePending
+    {
+      info!(
+        "UdsClient: Response pending for service 0x{:02X}, waiting up to {}ms",
+        service_id,
+        timing.p2_star.as_millis()
+      );
+      deadline = Instant::now() + timing.p2_star;
+      continue;
+    }
+
+    return Ok(response);
+  }
+}
+
+// Enter the sessions the target ECUs were in on a freshly connected client
+async fn restore_sessions(shared: &Shared, doip_client: &DoipClient) {
+  let sessions: Vec<(u16, u8)> = shared
+    .link()
+    .sessions
+    .iter()
+    .map(|(target, session)| (*target, *session))
+    .collect();
+
+  for (target_address, session) in sessions {
+    let message = DiagnosticMessage {
+      source_address: shared.source_address,
+      target_address,
+      user_data: vec![UdsServiceType::DiagnosticSessionControl as u8, session],
+    };
+    let result = exchange(doip_client, &shared.timing, message).await;
+
+    let mut link = shared.link();
+    match result {
+      Ok(response)
+        if response.first()
+          == Some(&(UdsServiceType::DiagnosticSessionControl as u8 + POSITIVE_RESPONSE_OFFSET)) =>
+      {
+        link.record(
+          "session_restored",
+          format!(
+            "Target 0x{:04X} is back in session 0x{:02X}",
+            target_address, session
+          ),
+        );
+      }
+      Ok(response) => {
+        link.sessions.remove(&target_address);
+        link.record(
+          "session_restore_failed",
+          format!(
+            "Target 0x{:04X} rejected session 0x{:02X}: {:02X?}",
+            target_address, session, response
+          ),
+        );
+      }
+      Err(e) => {
+        link.sessions.remove(&target_address);
+        link.record(
+          "session_restore_failed",
+          format!(
+            "Target 0x{:04X} session 0x{:02X}: {}",
+            target_address, session, e
+          ),
+        );
+      }
+    }
+  }
+}
+
+// Wait for the connection to drop and reconnect with exponential backoff, activating
+// routing and restoring the diagnostic sessions before requests may use it again
+async fn supervise(shared: Arc<Shared>) {
+  let policy = shared.reconnect.clone();
+  loop {
+    let doip_client = shared.link().doip_client.clone();
+    doip_client.closed().await;
+    drop(doip_client);
+
+    {
+      let mut link = shared.link();
+      link.state = if policy.enabled {
+        ConnectionState::Reconnecting
+      } else {
+        ConnectionState::Disconnected
+      };
+      link.record(
+        "disconnected",
+        format!("Lost the connection to {}", shared.ecu_ip),
+      );
+    }
+    if !policy.enabled {
+      return;
+    }
+
+    let mut delay = policy.initial_delay;
+    let mut attempt: u32 = 0;
+    loop {
+      if policy.max_attempts.is_some_and(|max| attempt >= max) {
+        let mut link = shared.link();
+        link.state = ConnectionState::Disconnected;
+        link.record(
+          "gave_up",
+          format!(
+            "No connection to {} after {} attempts",
+            shared.ecu_ip, attempt
+          ),
+        );
+        return;
+      }
+
+      sleep(delay).await;
+      attempt += 1;
+      match open(&shared.ecu_ip, shared.source_address, &shared.doip_config).await {
+        Ok((doip_client, routing_activation)) => {
+          if policy.restore_session {
+            restore_sessions(&shared, &doip_client).await;
+          } else {
+            shared.link().sessions.clear();
+          }
+
+          let mut link = shared.link();
+          link.doip_client = Arc::new(doip_client);
+          link.routing_activation = routing_activation;
+          link.state = ConnectionState::Connected;
+          link.record(
+            "reconnected",
+            format!("Connected to {} after {} attempts", shared.ecu_ip, attempt),
+          );
+          break;
         }
         Err(e) => {
-          error!("Error: {}", e);
-          return Self {
-            doip_client: None,
-            source_address: source_address,
-          };
+          shared.link().record(
+            "reconnect_failed",
+            format!("Attempt {} to reach {}: {}", attempt, shared.ecu_ip, e),
+          );
+          delay = (delay * 2).min(policy.max_delay);
         }
       }
     }
-    Self {
-      doip_client: Some(doip_client),
-      source_address: source_address,
-    }
+  }
+}
+
+impl UdsClient {
+  pub async fn new(
+    ecu_ip: String,
+    source_address: u16,
+    timing: UdsTiming,
+    doip_config: DoipConfig,
+    reconnect: ReconnectPolicy,
+  ) -> Result<Self, DoipError> {
+    let (doip_client, routing_activation) = open(&ecu_ip, source_address, &doip_config).await?;
+
+    let mut link = Link {
+      doip_client: Arc::new(doip_client),
+      routing_activation,
+      state: ConnectionState::Connected,
+      sessions: HashMap::new(),
+      events: VecDeque::new(),
+      last_used: Instant::now(),
+    };
+    link.record("connected", format!("Connected to {}", ecu_ip));
+
+    let shared = Arc::new(Shared {
+      ecu_ip,
+      source_address,
+      timing,
+      doip_config,
+      reconnect,
+      link: Mutex::new(link),
+    });
+    let supervisor = tokio::spawn(supervise(shared.clone()));
+
+    Ok(Self { shared, supervisor })
+  }
+
+  fn doip_client(&self) -> Arc<DoipClient> {
+    self.shared.link().doip_client.clone()
   }
 
   pub fn is_connected(&self) -> bool {
-    match &self.doip_client {
-      Some(client) => client.is_connected(),
-      None => false,
-    }
+    self.doip_client().is_connected()
   }
 
-  pub fn doip(&mut self, uds_data: Option<&[u8]>) -> Result<Vec<u8>, Error> {
-    if !self.doip_client.as_ref().unwrap().is_connected() {
-      return Err(Error::new(ErrorKind::NotConnected, "Not connected to ECU"));
-    }
+  pub fn state(&self) -> ConnectionState {
+    self.shared.link().state
+  }
+
+  // Time since the last diagnostic request or alive check
+  pub fn idle_time(&self) -> Duration {
+    self.shared.link().last_used.elapsed()
+  }
+
+  fn touch(&self) {
+    self.shared.link().last_used = Instant::now();
+  }
+
+  // Stop reconnecting and close the socket. Requests still running fail with
+  // NotConnected.
+  pub async fn shutdown(&self) {
+    self.supervisor.abort();
+    let doip_client = {
+      let mut link = self.shared.link();
+      link.state = ConnectionState::Disconnected;
+      link.record(
+        "closed",
+        format!("Closed the connection to {}", self.shared.ecu_ip),
+      );
+      link.doip_client.clone()
+    };
+    doip_client.shutdown().await;
+  }
+
+  // Latest connection events, oldest first
+  pub fn events(&self) -> Vec<ConnectionEvent> {
+    self.shared.link().events.iter().cloned().collect()
+  }
+
+  // Confirm with a DoIP alive check that the entity still serves this connection
+  pub async fn alive_check(&self) -> Result<u16, DoipError> {
+    self.touch();
+    self.doip_client().alive_check().await
+  }
+
+  // DoIP protocol version used on the connection
+  pub fn protocol_version(&self) -> u8 {
+    self.doip_client().protocol_version()
+  }
+
+  // Logical address of the DoIP entity that activated routing for this connection
+  pub fn entity_address(&self) -> u16 {
+    self.shared.link().routing_activation.entity_address
+  }
 
+  // Send one UDS request given as source and target address followed by the UDS data,
+  // and return the final response
+  pub async fn doip(&self, uds_data: Option<&[u8]>) -> Result<Vec<u8>, DoipError> {
     let uds =
       uds_data.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No UDS data provided"))?;
+    let message = DiagnosticMessage::parse(uds).ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        "Diagnostic message must contain source and target address",
+      )
+    })?;
+    self
+      .request(message.target_address, &message.user_data)
+      .await
+  }
+
+  // Send one UDS request to `target_address` and return the final response, negative
+  // responses included. Requests to different target ECUs run concurrently over the
+  // shared socket, requests to the same ECU queue up.
+  pub async fn request(&self, target_address: u16, uds: &[u8]) -> Result<Vec<u8>, DoipError> {
+    // A long exchange (response pending) doesn't count as idle time
+    self.touch();
+    let result = self.send_request(target_address, uds, false).await;
+    self.touch();
+    result.map(|response| response.unwrap_or_default())
+  }
+
+  // Send a request with the suppressPosRspMsgIndicationBit set, only the DoIP ACK is
+  // awaited. A negative response the ECU may still send is not read here, the next
+  // request to the same target drops it as not answering its own service.
+  pub async fn request_suppressed(&self, target_address: u16, uds: &[u8]) -> Result<(), DoipError> {
+    self.touch();
+    let result = self.send_request(target_address, uds, true).await;
+    self.touch();
+    result.map(|_| ())
+  }
 
-    let service_id = uds[4];
+  // TesterPresent 3E 80 of the keep-alive task. Unlike requests on behalf of a client
+  // of the connection it is not activity, so idle connections still time out.
+  pub async fn keep_alive(&self, target_address: u16) -> Result<(), DoipError> {
+    let request = [
+      UdsServiceType::TesterPresent as u8,
+      SUPPRESS_POSITIVE_RESPONSE,
+    ];
+    self
+      .send_request(target_address, &request, true)
+      .await
+      .map(|_| ())
+  }
+
+  async fn send_request(
+    &self,
+    target_address: u16,
+    uds: &[u8],
+    suppressed: bool,
+  ) -> Result<Option<Vec<u8>>, DoipError> {
+    let doip_client = self.doip_client();
+    if !doip_client.is_connected() {
+      let message = match self.state() {
+        ConnectionState::Reconnecting => "Reconnecting to ECU",
+        _ => "Not connected to ECU",
+      };
+      return Err(Error::new(ErrorKind::NotConnected, message).into());
+    }
+
+    let Some(&service_id) = uds.first() else {
+      return Err(Error::new(ErrorKind::InvalidInput, "No UDS service ID provided").into());
+    };
     if !UDS_SERVICE_SET.contains(&service_id) {
       warn!("UdsClient: Unknown UDS service ID: 0x{:02X}", service_id);
-      return Err(Error::new(
-        ErrorKind::InvalidData,
-        format!("Unknown UDS service ID: 0x{:02X}", service_id),
-      ));
+      return Err(
+        Error::new(
+          ErrorKind::InvalidData,
+          format!("Unknown UDS service ID: 0x{:02X}", service_id),
+        )
+        .into(),
+      );
+    }
+
+    debug!(
+      "UdsClient: [0x{:04X}] sending UDS service 0x{:02X} to 0x{:04X}",
+      self.shared.source_address, service_id, target_address
+    );
+
+    let message = DiagnosticMessage {
+      source_address: self.shared.source_address,
+      target_address,
+      user_data: uds.to_vec(),
+    };
+    let sub_function = uds.get(1).copied();
+    if suppressed {
+      let channel = doip_client.target_channel(target_address);
+      let mut channel = channel.lock().await;
+      doip_client.send_diagnostic(&mut channel, message).await?;
+      self.track_session(target_address, service_id, sub_function);
+      return Ok(None);
+    }
+
+    let response = exchange(&doip_client, &self.shared.timing, message).await?;
+    if response.first() == Some(&(service_id + POSITIVE_RESPONSE_OFFSET)) {
+      self.track_session(target_address, service_id, sub_function);
     }
+    Ok(Some(response))
+  }
 
-    if let Some(doip_client) = self.doip_client.as_mut() {
-      doip_client.send_and_receive(DiagnosticPayloadType::DiagnosticMessage as u16, Some(uds))
-    } else {
-      Err(Error::new(ErrorKind::NotConnected, "Not connected to ECU"))
+  // Remember the session a target ECU entered so it can be restored after a reconnect,
+  // a reset brings the ECU back to the default session
+  fn track_session(&self, target_address: u16, service_id: u8, sub_function: Option<u8>) {
+    let mut link = self.shared.link();
+    if service_id == UdsServiceType::ECUReset as u8 {
+      link.sessions.remove(&target_address);
+    } else if service_id == UdsServiceType::DiagnosticSessionControl as u8 {
+      match sub_function.map(|session| session & !SUPPRESS_POSITIVE_RESPONSE) {
+        Some(session) if session != DEFAULT_SESSION => {
+          link.sessions.insert(target_address, session);
+        }
+        _ => {
+          link.sessions.remove(&target_address);
+        }
+      }
     }
   }
 }
+
+impl Drop for UdsClient {
+  fn drop(&mut self) {
+    self.supervisor.abort();
+  }
+}
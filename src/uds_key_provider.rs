@@ -0,0 +1,366 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use crate::common::unity::{format_bytes_as_hex_string, parse_hex_string_to_bytes};
+use crate::doip_tls::{DoipTlsConfig, connect_tls};
+use crate::uds_security::{KeyFuture, SeedKeyAlgorithm};
+
+pub static DEFAULT_KEY_PROVIDER_TIMEOUT_MS: u64 = 5000;
+// Largest response accepted from a key server
+pub static MAX_KEY_SERVER_RESPONSE_SIZE: u64 = 64 * 1024;
+// Log target of the key request audit trail, e.g. DOIP2HTTP_LOG_LEVEL=doip2http::audit=info
+pub static AUDIT_TARGET: &str = "doip2http::audit";
+
+// Sent to key commands on stdin and to key servers as the POST body
+#[derive(Debug, Serialize)]
+pub struct KeyRequest {
+  // Logical address of the ECU, e.g. "0x1010"
+  pub ecu: String,
+  // requestSeed level, e.g. "0x01"
+  pub level: String,
+  pub seed: String,
+}
+
+impl KeyRequest {
+  fn new(target_address: u16, level: u8, seed: &[u8]) -> Self {
+    Self {
+      ecu: format!("0x{:04X}", target_address),
+      level: format!("0x{:02X}", level),
+      seed: format_bytes_as_hex_string(seed),
+    }
+  }
+}
+
+// Answer of a key command or server, {"key": "0x..."} or {"error": "..."}
+#[derive(Debug, Deserialize)]
+pub struct KeyResponse {
+  #[serde(default)]
+  pub key: Option<String>,
+  #[serde(default)]
+  pub error: Option<String>,
+}
+
+fn parse_key_response(body: &[u8]) -> Result<Vec<u8>, Error> {
+  let response: KeyResponse = serde_json::from_slice(body).map_err(|e| {
+    Error::new(
+      ErrorKind::InvalidData,
+      format!("Invalid key response: {}", e),
+    )
+  })?;
+  match (response.key, response.error) {
+    (_, Some(error)) => Err(Error::other(format!("Key provider error: {}", error))),
+    (Some(key), None) => parse_hex_string_to_bytes(&key)
+      .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid key: {}", e))),
+    (None, None) => Err(Error::new(
+      ErrorKind::InvalidData,
+      "Key response without key".to_string(),
+    )),
+  }
+}
+
+// Compute the key with `provider`'s request and write the audit record. Seeds are
+// logged, keys never are.
+async fn audited(
+  provider: &str,
+  request: &KeyRequest,
+  duration: Duration,
+  compute: impl Future<Output = Result<Vec<u8>, Error>>,
+) -> Result<Vec<u8>, Error> {
+  let started = Instant::now();
+  let result = match timeout(duration, compute).await {
+    Ok(result) => result,
+    Err(_) => Err(Error::new(
+      ErrorKind::TimedOut,
+      format!("{} did not answer within {:?}", provider, duration),
+    )),
+  };
+  match &result {
+    Ok(key) => info!(
+      target: AUDIT_TARGET,
+      "KeyAudit: {} computed a {} byte key for ECU {} level {} seed {} in {}ms",
+      provider,
+      key.len(),
+      request.ecu,
+      request.level,
+      request.seed,
+      started.elapsed().as_millis()
+    ),
+    Err(e) => warn!(
+      target: AUDIT_TARGET,
+      "KeyAudit: {} failed for ECU {} level {} seed {} after {}ms: {}",
+      provider,
+      request.ecu,
+      request.level,
+      request.seed,
+      started.elapsed().as_millis(),
+      e
+    ),
+  }
+  result
+}
+
+// Runs `program` per seed, writes the KeyRequest as one line of JSON to its stdin and
+// reads the KeyResponse from stdout. The process is killed when it runs longer than
+// `timeout`.
+#[derive(Debug, Clone)]
+pub struct KeyCommand {
+  pub program: String,
+  pub args: Vec<String>,
+  pub timeout: Duration,
+}
+
+impl KeyCommand {
+  async fn run(&self, request: &KeyRequest) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new(&self.program)
+      .args(&self.args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|e| Error::new(e.kind(), format!("Failed to run {}: {}", self.program, e)))?;
+
+    let mut input = serde_json::to_vec(request)?;
+    input.push(b'\n');
+    // A program that exits without reading its input is judged by its exit status
+    if let Some(mut stdin) = child.stdin.take()
+      && let Err(e) = stdin.write_all(&input).await
+      && e.kind() != ErrorKind::BrokenPipe
+    {
+      return Err(e);
+    }
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+      return Err(Error::other(format!(
+        "{} exited with {}: {}",
+        self.program,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+    parse_key_response(&output.stdout)
+  }
+}
+
+impl SeedKeyAlgorithm for KeyCommand {
+  fn compute_key<'a>(&'a self, target_address: u16, level: u8, seed: &'a [u8]) -> KeyFuture<'a> {
+    Box::pin(async move {
+      let request = KeyRequest::new(target_address, level, seed);
+      let provider = format!("Key command {}", self.program);
+      audited(&provider, &request, self.timeout, self.run(&request)).await
+    })
+  }
+}
+
+// Parts of an http:// or https:// URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyServerUrl {
+  pub tls: bool,
+  pub host: String,
+  pub port: u16,
+  pub path: String,
+}
+
+impl KeyServerUrl {
+  pub fn parse(url: &str) -> Result<Self, Error> {
+    let invalid = || {
+      Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid key server URL: {}", url),
+      )
+    };
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+      (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+      (false, rest)
+    } else {
+      return Err(invalid());
+    };
+    let (authority, path) = match rest.find('/') {
+      Some(index) => rest.split_at(index),
+      None => (rest, "/"),
+    };
+    let default_port = if tls { 443 } else { 80 };
+    // IPv6 addresses are written in brackets, e.g. http://[::1]:8081/key
+    let (host, port) = match authority.strip_prefix('[') {
+      Some(bracketed) => {
+        let (host, port) = bracketed.split_once(']').ok_or_else(invalid)?;
+        (host, port.strip_prefix(':'))
+      }
+      None => match authority.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+      },
+    };
+    if host.is_empty() {
+      return Err(invalid());
+    }
+    let port = match port {
+      Some(port) => port.parse().map_err(|_| invalid())?,
+      None => default_port,
+    };
+    Ok(Self {
+      tls,
+      host: host.to_string(),
+      port,
+      path: path.to_string(),
+    })
+  }
+}
+
+// POSTs the KeyRequest as JSON to `url` and reads the KeyResponse from the body.
+// https:// URLs are verified against `tls.ca_file` and may use a client certificate.
+#[derive(Debug, Clone)]
+pub struct KeyServer {
+  pub url: KeyServerUrl,
+  pub tls: Option<DoipTlsConfig>,
+  pub timeout: Duration,
+}
+
+impl KeyServer {
+  async fn post(&self, request: &KeyRequest) -> Result<Vec<u8>, Error> {
+    let stream = TcpStream::connect((self.url.host.as_str(), self.url.port)).await?;
+    if !self.url.tls {
+      return self.exchange(stream, request).await;
+    }
+    let tls = self.tls.as_ref().ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        "https:// key servers need a tls configuration".to_string(),
+      )
+    })?;
+    let stream = connect_tls(stream, &self.url.host, tls).await?;
+    self.exchange(stream, request).await
+  }
+
+  async fn exchange(
+    &self,
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &KeyRequest,
+  ) -> Result<Vec<u8>, Error> {
+    let body = serde_json::to_vec(request)?;
+    let host = if self.url.host.contains(':') {
+      format!("[{}]:{}", self.url.host, self.url.port)
+    } else {
+      format!("{}:{}", self.url.host, self.url.port)
+    };
+    let head = format!(
+      "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nAccept: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+      self.url.path,
+      host,
+      body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+
+    // The server closes the connection after the response
+    let mut response = Vec::new();
+    (&mut stream)
+      .take(MAX_KEY_SERVER_RESPONSE_SIZE)
+      .read_to_end(&mut response)
+      .await?;
+    let body = parse_http_response(&response)?;
+    parse_key_response(&body)
+  }
+}
+
+impl SeedKeyAlgorithm for KeyServer {
+  fn compute_key<'a>(&'a self, target_address: u16, level: u8, seed: &'a [u8]) -> KeyFuture<'a> {
+    Box::pin(async move {
+      let request = KeyRequest::new(target_address, level, seed);
+      let provider = format!("Key server {}:{}", self.url.host, self.url.port);
+      audited(&provider, &request, self.timeout, self.post(&request)).await
+    })
+  }
+}
+
+fn invalid_http(message: &str) -> Error {
+  Error::new(
+    ErrorKind::InvalidData,
+    format!("Invalid key server response: {}", message),
+  )
+}
+
+// Body of a complete HTTP/1.1 response, error statuses fail unless the body carries a
+// KeyResponse error
+fn parse_http_response(response: &[u8]) -> Result<Vec<u8>, Error> {
+  let header_end = response
+    .windows(4)
+    .position(|window| window == b"\r\n\r\n")
+    .ok_or_else(|| invalid_http("incomplete header"))?;
+  let head = std::str::from_utf8(&response[..header_end]).map_err(|_| invalid_http("header"))?;
+  let body = &response[header_end + 4..];
+
+  let mut lines = head.split("\r\n");
+  let status: u16 = lines
+    .next()
+    .and_then(|status_line| status_line.split(' ').nth(1))
+    .and_then(|status| status.parse().ok())
+    .ok_or_else(|| invalid_http("status line"))?;
+  let mut chunked = false;
+  let mut content_length = None;
+  for line in lines {
+    let Some((name, value)) = line.split_once(':') else {
+      continue;
+    };
+    let value = value.trim();
+    if name.eq_ignore_ascii_case("transfer-encoding") {
+      chunked = value.eq_ignore_ascii_case("chunked");
+    } else if name.eq_ignore_ascii_case("content-length") {
+      content_length = Some(
+        value
+          .parse::<usize>()
+          .map_err(|_| invalid_http("content length"))?,
+      );
+    }
+  }
+
+  let body = if chunked {
+    decode_chunked(body)?
+  } else {
+    match content_length {
+      Some(len) => body
+        .get(..len)
+        .ok_or_else(|| invalid_http("truncated body"))?
+        .to_vec(),
+      None => body.to_vec(),
+    }
+  };
+  let has_error =
+    || serde_json::from_slice::<KeyResponse>(&body).is_ok_and(|response| response.error.is_some());
+  if !(200..300).contains(&status) && !has_error() {
+    return Err(Error::other(format!("Key server answered HTTP {}", status)));
+  }
+  Ok(body)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+  let mut decoded = Vec::new();
+  loop {
+    let line_end = body
+      .windows(2)
+      .position(|window| window == b"\r\n")
+      .ok_or_else(|| invalid_http("chunk size"))?;
+    let size = std::str::from_utf8(&body[..line_end])
+      .ok()
+      .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+      .ok_or_else(|| invalid_http("chunk size"))?;
+    if size == 0 {
+      return Ok(decoded);
+    }
+    let chunk = body
+      .get(line_end + 2..line_end + 2 + size)
+      .ok_or_else(|| invalid_http("truncated chunk"))?;
+    decoded.extend_from_slice(chunk);
+    body = body.get(line_end + 4 + size..).unwrap_or_default();
+  }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+
+// Declares the Nrc enum and its code, name and description lookups from one table
+macro_rules! negative_response_codes {
+  ($($code:literal => $variant:ident, $name:literal, $description:literal;)*) => {
+    // Negative response codes of ISO 14229-1 (7F <service> <nrc>)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Nrc {
+      $($variant,)*
+      // 0xF0-0xFE, conditions defined by the vehicle manufacturer
+      ManufacturerSpecific(u8),
+      // Reserved by ISO, including the extended data link security range 0x38-0x4F
+      Reserved(u8),
+    }
+
+    impl Nrc {
+      pub fn code(&self) -> u8 {
+        match self {
+          $(Nrc::$variant => $code,)*
+          Nrc::ManufacturerSpecific(code) | Nrc::Reserved(code) => *code,
+        }
+      }
+
+      pub fn name(&self) -> &'static str {
+        match self {
+          $(Nrc::$variant => $name,)*
+          Nrc::ManufacturerSpecific(_) => "vehicleManufacturerSpecificConditionsNotCorrect",
+          Nrc::Reserved(_) => "ISOSAEReserved",
+        }
+      }
+
+      pub fn description(&self) -> &'static str {
+        match self {
+          $(Nrc::$variant => $description,)*
+          Nrc::ManufacturerSpecific(_) => {
+            "A condition defined by the vehicle manufacturer is not met"
+          }
+          Nrc::Reserved(_) => "Reserved negative response code",
+        }
+      }
+    }
+
+    impl From<u8> for Nrc {
+      fn from(code: u8) -> Self {
+        match code {
+          $($code => Nrc::$variant,)*
+          0xF0..=0xFE => Nrc::ManufacturerSpecific(code),
+          _ => Nrc::Reserved(code),
+        }
+      }
+    }
+  };
+}
+
+negative_response_codes! {
+  0x10 => GeneralReject, "generalReject",
+    "The request was rejected and no more specific code applies";
+  0x11 => ServiceNotSupported, "serviceNotSupported",
+    "The service is not supported by the ECU";
+  0x12 => SubFunctionNotSupported, "subFunctionNotSupported",
+    "The sub-function is not supported by the service";
+  0x13 => IncorrectMessageLengthOrInvalidFormat, "incorrectMessageLengthOrInvalidFormat",
+    "The request length or format does not match the service";
+  0x14 => ResponseTooLong, "responseTooLong",
+    "The response would exceed the transport protocol's maximum length";
+  0x21 => BusyRepeatRequest, "busyRepeatRequest",
+    "The ECU is busy, repeat the request later";
+  0x22 => ConditionsNotCorrect, "conditionsNotCorrect",
+    "The ECU's current state does not allow the request";
+  0x24 => RequestSequenceError, "requestSequenceError",
+    "The request was received out of the expected order";
+  0x25 => NoResponseFromSubnetComponent, "noResponseFromSubnetComponent",
+    "A sub-network component needed for the request did not answer";
+  0x26 => FailurePreventsExecutionOfRequestedAction, "failurePreventsExecutionOfRequestedAction",
+    "A failure condition prevents the requested action";
+  0x31 => RequestOutOfRange, "requestOutOfRange",
+    "A parameter such as a DID or routine is not supported or out of range";
+  0x33 => SecurityAccessDenied, "securityAccessDenied",
+    "The request needs an unlocked security level";
+  0x34 => AuthenticationRequired, "authenticationRequired",
+    "The request needs an authenticated tester";
+  0x35 => InvalidKey, "invalidKey",
+    "The SecurityAccess key does not match the seed";
+  0x36 => ExceededNumberOfAttempts, "exceededNumberOfAttempts",
+    "Too many invalid keys, security access is locked for a delay";
+  0x37 => RequiredTimeDelayNotExpired, "requiredTimeDelayNotExpired",
+    "The security access delay has not expired yet";
+  0x50 => CertificateVerificationFailedInvalidTimePeriod,
+    "certificateVerificationFailedInvalidTimePeriod",
+    "The certificate is expired or not yet valid";
+  0x51 => CertificateVerificationFailedInvalidSignature,
+    "certificateVerificationFailedInvalidSignature",
+    "The certificate signature could not be verified";
+  0x52 => CertificateVerificationFailedInvalidChainOfTrust,
+    "certificateVerificationFailedInvalidChainOfTrust",
+    "The certificate is not signed by a trusted authority";
+  0x53 => CertificateVerificationFailedInvalidType, "certificateVerificationFailedInvalidType",
+    "The certificate type does not match the request";
+  0x54 => CertificateVerificationFailedInvalidFormat,
+    "certificateVerificationFailedInvalidFormat",
+    "The certificate could not be parsed";
+  0x55 => CertificateVerificationFailedInvalidContent,
+    "certificateVerificationFailedInvalidContent",
+    "The certificate content is not valid for the ECU";
+  0x56 => CertificateVerificationFailedInvalidScope, "certificateVerificationFailedInvalidScope",
+    "The certificate scope does not cover the request";
+  0x57 => CertificateVerificationFailedInvalidCertificate,
+    "certificateVerificationFailedInvalidCertificate",
+    "The certificate has been revoked";
+  0x58 => OwnershipVerificationFailed, "ownershipVerificationFailed",
+    "The proof of ownership could not be verified";
+  0x59 => ChallengeCalculationFailed, "challengeCalculationFailed",
+    "The ECU failed to create an authentication challenge";
+  0x5A => SettingAccessRightsFailed, "settingAccessRightsFailed",
+    "The ECU failed to set the access rights";
+  0x5B => SessionKeyCreationDerivationFailed, "sessionKeyCreationDerivationFailed",
+    "The ECU failed to create or derive a session key";
+  0x5C => ConfigurationDataUsageFailed, "configurationDataUsageFailed",
+    "The ECU failed to use the authentication configuration data";
+  0x5D => DeAuthenticationFailed, "deAuthenticationFailed",
+    "The tester could not be deauthenticated";
+  0x70 => UploadDownloadNotAccepted, "uploadDownloadNotAccepted",
+    "The upload or download cannot be started";
+  0x71 => TransferDataSuspended, "transferDataSuspended",
+    "The data transfer was stopped because of a fault";
+  0x72 => GeneralProgrammingFailure, "generalProgrammingFailure",
+    "Erasing or programming the memory failed";
+  0x73 => WrongBlockSequenceCounter, "wrongBlockSequenceCounter",
+    "The TransferData block sequence counter is wrong";
+  0x78 => RequestCorrectlyReceivedResponsePending, "requestCorrectlyReceivedResponsePending",
+    "The request was received, the final response follows later";
+  0x7E => SubFunctionNotSupportedInActiveSession, "subFunctionNotSupportedInActiveSession",
+    "The sub-function is not supported in the current session";
+  0x7F => ServiceNotSupportedInActiveSession, "serviceNotSupportedInActiveSession",
+    "The service is not supported in the current session";
+  0x81 => RpmTooHigh, "rpmTooHigh", "The engine speed is too high";
+  0x82 => RpmTooLow, "rpmTooLow", "The engine speed is too low";
+  0x83 => EngineIsRunning, "engineIsRunning", "The engine has to be stopped";
+  0x84 => EngineIsNotRunning, "engineIsNotRunning", "The engine has to be running";
+  0x85 => EngineRunTimeTooLow, "engineRunTimeTooLow",
+    "The engine has not been running long enough";
+  0x86 => TemperatureTooHigh, "temperatureTooHigh", "The temperature is too high";
+  0x87 => TemperatureTooLow, "temperatureTooLow", "The temperature is too low";
+  0x88 => VehicleSpeedTooHigh, "vehicleSpeedTooHigh", "The vehicle speed is too high";
+  0x89 => VehicleSpeedTooLow, "vehicleSpeedTooLow", "The vehicle speed is too low";
+  0x8A => ThrottlePedalTooHigh, "throttle/PedalTooHigh",
+    "The throttle or pedal position is too high";
+  0x8B => ThrottlePedalTooLow, "throttle/PedalTooLow",
+    "The throttle or pedal position is too low";
+  0x8C => TransmissionRangeNotInNeutral, "transmissionRangeNotInNeutral",
+    "The transmission has to be in neutral";
+  0x8D => TransmissionRangeNotInGear, "transmissionRangeNotInGear",
+    "The transmission has to be in gear";
+  0x8F => BrakeSwitchesNotClosed, "brakeSwitch(es)NotClosed",
+    "The brake pedal has to be pressed";
+  0x90 => ShifterLeverNotInPark, "shifterLeverNotInPark",
+    "The shifter lever has to be in park";
+  0x91 => TorqueConverterClutchLocked, "torqueConverterClutchLocked",
+    "The torque converter clutch is locked";
+  0x92 => VoltageTooHigh, "voltageTooHigh", "The supply voltage is too high";
+  0x93 => VoltageTooLow, "voltageTooLow", "The supply voltage is too low";
+  0x94 => ResourceTemporarilyNotAvailable, "resourceTemporarilyNotAvailable",
+    "A resource needed for the request is temporarily not available";
+}
+
+impl fmt::Display for Nrc {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} (0x{:02X})", self.name(), self.code())
+  }
+}
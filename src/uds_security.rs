@@ -0,0 +1,359 @@
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::common::serde_hex::{hex_bytes, hex_number, optional_hex_number};
+use crate::doip_tls::DoipTlsConfig;
+use crate::uds_client::UdsClient;
+use crate::uds_key_provider::{
+  DEFAULT_KEY_PROVIDER_TIMEOUT_MS, KeyCommand, KeyServer, KeyServerUrl,
+};
+use crate::uds_nrc::Nrc;
+use crate::uds_service::{MAX_REQUEST_SEED_LEVEL, UdsError, is_request_seed_level};
+
+pub type KeyFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, Error>> + Send + 'a>>;
+
+// Computes the SecurityAccess key for a seed. The key may come from somewhere slow, so
+// the result is a future.
+pub trait SeedKeyAlgorithm: fmt::Debug + Send + Sync {
+  // `level` is the requestSeed sub-function the seed was sent for
+  fn compute_key<'a>(&'a self, target_address: u16, level: u8, seed: &'a [u8]) -> KeyFuture<'a>;
+}
+
+// key = seed XOR mask, the mask repeats for seeds longer than it. This is the
+// algorithm of doip-sim.
+#[derive(Debug, Clone)]
+pub struct XorMask {
+  pub mask: Vec<u8>,
+}
+
+impl SeedKeyAlgorithm for XorMask {
+  fn compute_key<'a>(&'a self, _target_address: u16, _level: u8, seed: &'a [u8]) -> KeyFuture<'a> {
+    let key = seed
+      .iter()
+      .zip(self.mask.iter().cycle())
+      .map(|(seed, mask)| seed ^ mask)
+      .collect();
+    Box::pin(async move { Ok(key) })
+  }
+}
+
+// The same key for every seed, as accepted by some ECUs in development
+#[derive(Debug, Clone)]
+pub struct FixedKey {
+  pub key: Vec<u8>,
+}
+
+impl SeedKeyAlgorithm for FixedKey {
+  fn compute_key<'a>(&'a self, _target_address: u16, _level: u8, _seed: &'a [u8]) -> KeyFuture<'a> {
+    Box::pin(async move { Ok(self.key.clone()) })
+  }
+}
+
+// Algorithm of one ECU in the security configuration file, e.g.
+// {"target_address": "0x1010", "level": "0x01", "algorithm": "xor_mask", "mask": "0x5A5A5A5A"}
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyAlgorithmDescription {
+  #[serde(deserialize_with = "hex_number")]
+  pub target_address: u16,
+  // Used for every level of the ECU without an entry of its own if omitted
+  #[serde(default, deserialize_with = "optional_hex_number")]
+  pub level: Option<u8>,
+  #[serde(flatten)]
+  pub algorithm: AlgorithmDescription,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum AlgorithmDescription {
+  XorMask {
+    #[serde(deserialize_with = "hex_bytes")]
+    mask: Vec<u8>,
+  },
+  FixedKey {
+    #[serde(deserialize_with = "hex_bytes")]
+    key: Vec<u8>,
+  },
+  // External program speaking JSON on stdin/stdout
+  Command {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default = "default_key_provider_timeout_ms")]
+    timeout_ms: u64,
+  },
+  // HTTP(S) key server the seed is POSTed to
+  KeyServer {
+    url: String,
+    #[serde(default)]
+    tls: Option<DoipTlsConfig>,
+    #[serde(default = "default_key_provider_timeout_ms")]
+    timeout_ms: u64,
+  },
+}
+
+fn default_key_provider_timeout_ms() -> u64 {
+  DEFAULT_KEY_PROVIDER_TIMEOUT_MS
+}
+
+impl AlgorithmDescription {
+  fn build(&self) -> Result<Arc<dyn SeedKeyAlgorithm>, Error> {
+    let algorithm: Arc<dyn SeedKeyAlgorithm> = match self {
+      AlgorithmDescription::XorMask { mask } => Arc::new(XorMask { mask: mask.clone() }),
+      AlgorithmDescription::FixedKey { key } => Arc::new(FixedKey { key: key.clone() }),
+      AlgorithmDescription::Command {
+        command,
+        args,
+        timeout_ms,
+      } => Arc::new(KeyCommand {
+        program: command.clone(),
+        args: args.clone(),
+        timeout: Duration::from_millis(*timeout_ms),
+      }),
+      AlgorithmDescription::KeyServer {
+        url,
+        tls,
+        timeout_ms,
+      } => {
+        let url = KeyServerUrl::parse(url)?;
+        if url.tls && tls.is_none() {
+          return Err(invalid_config(format!(
+            "Key server {} needs a tls configuration with the CA file",
+            url.host
+          )));
+        }
+        Arc::new(KeyServer {
+          url,
+          tls: tls.clone(),
+          timeout: Duration::from_millis(*timeout_ms),
+        })
+      }
+    };
+    Ok(algorithm)
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct SecurityConfigFile {
+  ecus: Vec<KeyAlgorithmDescription>,
+}
+
+#[derive(Debug, Clone)]
+struct KeyAlgorithmEntry {
+  target_address: u16,
+  level: Option<u8>,
+  algorithm: Arc<dyn SeedKeyAlgorithm>,
+}
+
+// Seed/key algorithms by ECU and security level
+#[derive(Debug, Clone, Default)]
+pub struct KeyAlgorithms {
+  entries: Vec<KeyAlgorithmEntry>,
+}
+
+fn invalid_config(message: String) -> Error {
+  Error::new(ErrorKind::InvalidData, message)
+}
+
+impl KeyAlgorithms {
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+    let path = path.as_ref();
+    let json = std::fs::read_to_string(path)?;
+    Self::from_json(&json).map_err(|e| invalid_config(format!("{}: {}", path.display(), e)))
+  }
+
+  pub fn from_json(json: &str) -> Result<Self, Error> {
+    let config: SecurityConfigFile =
+      serde_json::from_str(json).map_err(|e| invalid_config(e.to_string()))?;
+    let mut algorithms = Self::default();
+    for ecu in config.ecus {
+      let algorithm = ecu
+        .algorithm
+        .build()
+        .map_err(|e| invalid_config(format!("ECU 0x{:04X}: {}", ecu.target_address, e)))?;
+      algorithms.insert(ecu.target_address, ecu.level, algorithm)?;
+    }
+    Ok(algorithms)
+  }
+
+  // Use `algorithm` for `level` of the ECU, or for all its levels without an entry of
+  // their own when `level` is None
+  pub fn insert(
+    &mut self,
+    target_address: u16,
+    level: Option<u8>,
+    algorithm: Arc<dyn SeedKeyAlgorithm>,
+  ) -> Result<(), Error> {
+    if let Some(level) = level
+      && !is_request_seed_level(level)
+    {
+      return Err(invalid_config(format!(
+        "ECU 0x{:04X}: security level 0x{:02X} must be odd and at most 0x{:02X}",
+        target_address, level, MAX_REQUEST_SEED_LEVEL
+      )));
+    }
+    if self
+      .entries
+      .iter()
+      .any(|entry| entry.target_address == target_address && entry.level == level)
+    {
+      return Err(invalid_config(format!(
+        "ECU 0x{:04X}: more than one algorithm for the same security level",
+        target_address
+      )));
+    }
+    self.entries.push(KeyAlgorithmEntry {
+      target_address,
+      level,
+      algorithm,
+    });
+    Ok(())
+  }
+
+  pub fn get(&self, target_address: u16, level: u8) -> Option<Arc<dyn SeedKeyAlgorithm>> {
+    let find = |level: Option<u8>| {
+      self
+        .entries
+        .iter()
+        .find(|entry| entry.target_address == target_address && entry.level == level)
+    };
+    find(Some(level))
+      .or_else(|| find(None))
+      .map(|entry| entry.algorithm.clone())
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+}
+
+// Outcome of a security_unlock attempt. The negative responses about the attempt
+// counter and delay are results, not errors, callers are expected to handle them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityAccessStatus {
+  Unlocked,
+  // The ECU sent an all zero seed, the level was unlocked before
+  AlreadyUnlocked,
+  // 0x35, the attempt counter of the ECU was increased
+  InvalidKey,
+  // 0x36, the level is locked until the ECU's delay has expired
+  ExceededNumberOfAttempts,
+  // 0x37, the seed was requested before the delay expired
+  RequiredTimeDelayNotExpired,
+}
+
+impl SecurityAccessStatus {
+  pub fn name(&self) -> &'static str {
+    match self {
+      SecurityAccessStatus::Unlocked => "unlocked",
+      SecurityAccessStatus::AlreadyUnlocked => "already_unlocked",
+      SecurityAccessStatus::InvalidKey => "invalid_key",
+      SecurityAccessStatus::ExceededNumberOfAttempts => "exceeded_number_of_attempts",
+      SecurityAccessStatus::RequiredTimeDelayNotExpired => "required_time_delay_not_expired",
+    }
+  }
+
+  pub fn is_unlocked(&self) -> bool {
+    matches!(
+      self,
+      SecurityAccessStatus::Unlocked | SecurityAccessStatus::AlreadyUnlocked
+    )
+  }
+
+  // The negative response code behind a failed attempt
+  pub fn nrc(&self) -> Option<Nrc> {
+    match self {
+      SecurityAccessStatus::Unlocked | SecurityAccessStatus::AlreadyUnlocked => None,
+      SecurityAccessStatus::InvalidKey => Some(Nrc::InvalidKey),
+      SecurityAccessStatus::ExceededNumberOfAttempts => Some(Nrc::ExceededNumberOfAttempts),
+      SecurityAccessStatus::RequiredTimeDelayNotExpired => Some(Nrc::RequiredTimeDelayNotExpired),
+    }
+  }
+}
+
+// Turn the attempt counter and delay NRCs into a status, other errors stay errors
+fn attempt_status(error: UdsError) -> Result<SecurityAccessStatus, UdsError> {
+  let status = match error {
+    UdsError::NegativeResponse {
+      nrc: Nrc::InvalidKey,
+      ..
+    } => SecurityAccessStatus::InvalidKey,
+    UdsError::NegativeResponse {
+      nrc: Nrc::ExceededNumberOfAttempts,
+      ..
+    } => SecurityAccessStatus::ExceededNumberOfAttempts,
+    UdsError::NegativeResponse {
+      nrc: Nrc::RequiredTimeDelayNotExpired,
+      ..
+    } => SecurityAccessStatus::RequiredTimeDelayNotExpired,
+    error => return Err(error),
+  };
+  Ok(status)
+}
+
+impl UdsClient {
+  // Unlock security `level` of the ECU: request a seed, compute the key with
+  // `algorithm` and send it
+  pub async fn security_unlock(
+    &self,
+    target_address: u16,
+    level: u8,
+    algorithm: &dyn SeedKeyAlgorithm,
+  ) -> Result<SecurityAccessStatus, UdsError> {
+    let seed = match self
+      .security_access_request_seed(target_address, level)
+      .await
+    {
+      Ok(seed) => seed,
+      Err(e) => return attempt_status(e),
+    };
+    if seed.is_empty() {
+      return Err(UdsError::InvalidResponse(
+        "SecurityAccess response without a seed".to_string(),
+      ));
+    }
+    if seed.iter().all(|byte| *byte == 0) {
+      info!(
+        "UdsClient: Security level 0x{:02X} of 0x{:04X} is already unlocked",
+        level, target_address
+      );
+      return Ok(SecurityAccessStatus::AlreadyUnlocked);
+    }
+
+    let key = algorithm
+      .compute_key(target_address, level, &seed)
+      .await
+      .map_err(UdsError::Key)?;
+    let status = match self
+      .security_access_send_key(target_address, level, &key)
+      .await
+    {
+      Ok(()) => SecurityAccessStatus::Unlocked,
+      Err(e) => attempt_status(e)?,
+    };
+    if status.is_unlocked() {
+      info!(
+        "UdsClient: Security level 0x{:02X} of 0x{:04X} unlocked",
+        level, target_address
+      );
+    } else {
+      warn!(
+        "UdsClient: Unlocking security level 0x{:02X} of 0x{:04X} failed: {}",
+        level,
+        target_address,
+        status.name()
+      );
+    }
+    Ok(status)
+  }
+}
@@ -0,0 +1,467 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::doip_client::DoipError;
+use crate::uds_client::{
+  NegativeResponse, POSITIVE_RESPONSE_OFFSET, SUPPRESS_POSITIVE_RESPONSE, UdsClient,
+  UdsServiceType, service_name,
+};
+use crate::uds_nrc::Nrc;
+
+// Diagnostic sessions defined by ISO 14229-1, OEMs add their own from 0x40
+pub static PROGRAMMING_SESSION: u8 = 0x02;
+pub static EXTENDED_SESSION: u8 = 0x03;
+pub static SAFETY_SYSTEM_SESSION: u8 = 0x04;
+
+// groupOfDTC selecting every DTC for ClearDiagnosticInformation
+pub static ALL_DTC_GROUPS: u32 = 0xFF_FFFF;
+
+// Errors of a typed UDS service call
+#[derive(Debug)]
+pub enum UdsError {
+  Doip(DoipError),
+  // The ECU answered 7F with the rejected service and negative response code
+  NegativeResponse { service_id: u8, nrc: Nrc },
+  // The request can't be built from the given parameters
+  InvalidRequest(String),
+  // The positive response is too short or doesn't match the request
+  InvalidResponse(String),
+  // The seed/key algorithm could not compute a key
+  Key(std::io::Error),
+}
+
+impl fmt::Display for UdsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UdsError::Doip(e) => write!(f, "{}", e),
+      UdsError::NegativeResponse { service_id, nrc } => write!(
+        f,
+        "Negative response to {} (0x{:02X}): {}",
+        service_name(*service_id),
+        service_id,
+        nrc
+      ),
+      UdsError::InvalidRequest(message) => write!(f, "Invalid request: {}", message),
+      UdsError::InvalidResponse(message) => write!(f, "Invalid response: {}", message),
+      UdsError::Key(e) => write!(f, "Key computation failed: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for UdsError {}
+
+impl From<DoipError> for UdsError {
+  fn from(e: DoipError) -> Self {
+    UdsError::Doip(e)
+  }
+}
+
+// Highest requestSeed sub-function, 0x7F + 1 would collide with the suppress bit
+pub static MAX_REQUEST_SEED_LEVEL: u8 = 0x7D;
+
+// requestSeed levels are the odd sub-functions, sendKey is level + 1
+pub fn is_request_seed_level(level: u8) -> bool {
+  level % 2 == 1 && level <= MAX_REQUEST_SEED_LEVEL
+}
+
+fn check_request_seed_level(level: u8) -> Result<(), UdsError> {
+  if is_request_seed_level(level) {
+    return Ok(());
+  }
+  Err(UdsError::InvalidRequest(format!(
+    "Security level 0x{:02X} is not an odd level from 0x01 to 0x{:02X}",
+    level, MAX_REQUEST_SEED_LEVEL
+  )))
+}
+
+// ECUReset (0x11) reset types
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+  HardReset = 0x01,
+  KeyOffOnReset = 0x02,
+  SoftReset = 0x03,
+  EnableRapidPowerShutDown = 0x04,
+  DisableRapidPowerShutDown = 0x05,
+}
+
+// RoutineControl (0x31) control types
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutineControlType {
+  StartRoutine = 0x01,
+  StopRoutine = 0x02,
+  RequestRoutineResults = 0x03,
+}
+
+// ControlDTCSetting (0x85) setting types
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtcSettingType {
+  On = 0x01,
+  Off = 0x02,
+}
+
+// DiagnosticSessionControl (0x10) response: the session entered and its timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionTiming {
+  pub session: u8,
+  pub p2: Duration,
+  pub p2_star: Duration,
+}
+
+// ECUReset (0x11) response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcuResetResponse {
+  pub reset_type: u8,
+  // Seconds until power down, only sent for EnableRapidPowerShutDown
+  pub power_down_time: Option<u8>,
+}
+
+// A data identifier and its value from ReadDataByIdentifier (0x22)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataRecord {
+  pub did: u16,
+  pub data: Vec<u8>,
+}
+
+// A 3 byte DTC and its status byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DtcRecord {
+  pub code: u32,
+  pub status: u8,
+}
+
+// ReadDTCInformation (0x19) report of DTCs and their status
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtcReport {
+  // Status bits the ECU supports
+  pub availability_mask: u8,
+  pub dtcs: Vec<DtcRecord>,
+}
+
+// ReadDTCInformation (0x19) reportNumberOfDTCByStatusMask response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DtcCount {
+  pub availability_mask: u8,
+  pub format: u8,
+  pub count: u16,
+}
+
+// RoutineControl (0x31) response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineResponse {
+  pub control_type: u8,
+  pub routine_id: u16,
+  // routineInfo and routineStatusRecord, layout defined by the routine
+  pub status_record: Vec<u8>,
+}
+
+// Return `response` if it is the positive response to `service_id` with at least
+// `min_len` bytes, negative responses become UdsError::NegativeResponse
+pub fn check_response(
+  service_id: u8,
+  response: Vec<u8>,
+  min_len: usize,
+) -> Result<Vec<u8>, UdsError> {
+  if let Some(negative) = NegativeResponse::parse(&response) {
+    return Err(UdsError::NegativeResponse {
+      service_id: negative.service_id,
+      nrc: negative.nrc,
+    });
+  }
+  if response.first() == Some(&(service_id + POSITIVE_RESPONSE_OFFSET)) && response.len() >= min_len
+  {
+    return Ok(response);
+  }
+  Err(UdsError::InvalidResponse(format!(
+    "Unexpected response {:02X?} to service 0x{:02X}",
+    response, service_id
+  )))
+}
+
+// Check the response echoes the request parameter at `index`, e.g. the sub-function
+fn check_echo(response: &[u8], index: usize, expected: &[u8]) -> Result<(), UdsError> {
+  if response.get(index..index + expected.len()) != Some(expected) {
+    return Err(UdsError::InvalidResponse(format!(
+      "Response {:02X?} does not echo {:02X?}",
+      response, expected
+    )));
+  }
+  Ok(())
+}
+
+// Split a ReadDataByIdentifier response into the requested DIDs. The response has no
+// record lengths, they come from the caller's data dictionary.
+fn parse_data_records(
+  dids: &[(u16, usize)],
+  mut payload: &[u8],
+) -> Result<Vec<DataRecord>, UdsError> {
+  let mut records = Vec::with_capacity(dids.len());
+  for &(did, len) in dids {
+    let Some(rest) = payload.strip_prefix(&did.to_be_bytes()) else {
+      return Err(UdsError::InvalidResponse(format!(
+        "Response does not contain DID 0x{:04X}",
+        did
+      )));
+    };
+    if rest.len() < len {
+      return Err(UdsError::InvalidResponse(format!(
+        "DID 0x{:04X} has {} bytes of data, expected {}",
+        did,
+        rest.len(),
+        len
+      )));
+    }
+    records.push(DataRecord {
+      did,
+      data: rest[..len].to_vec(),
+    });
+    payload = &rest[len..];
+  }
+  if !payload.is_empty() {
+    return Err(UdsError::InvalidResponse(format!(
+      "{} bytes after the requested DIDs",
+      payload.len()
+    )));
+  }
+  Ok(records)
+}
+
+fn parse_dtc_records(records: &[u8]) -> Vec<DtcRecord> {
+  records
+    .chunks_exact(4)
+    .map(|record| DtcRecord {
+      code: u32::from_be_bytes([0, record[0], record[1], record[2]]),
+      status: record[3],
+    })
+    .collect()
+}
+
+// Typed UDS services, each sends one request to `target_address` and parses the
+// positive response
+impl UdsClient {
+  async fn positive_response(
+    &self,
+    target_address: u16,
+    request: &[u8],
+    min_len: usize,
+  ) -> Result<Vec<u8>, UdsError> {
+    let response = self.request(target_address, request).await?;
+    check_response(request[0], response, min_len)
+  }
+
+  pub async fn diagnostic_session_control(
+    &self,
+    target_address: u16,
+    session: u8,
+  ) -> Result<SessionTiming, UdsError> {
+    let request = [UdsServiceType::DiagnosticSessionControl as u8, session];
+    let response = self.positive_response(target_address, &request, 6).await?;
+    check_echo(&response, 1, &[session])?;
+
+    // P2*server is sent in units of 10ms
+    Ok(SessionTiming {
+      session,
+      p2: Duration::from_millis(u16::from_be_bytes([response[2], response[3]]) as u64),
+      p2_star: Duration::from_millis(u16::from_be_bytes([response[4], response[5]]) as u64 * 10),
+    })
+  }
+
+  pub async fn ecu_reset(
+    &self,
+    target_address: u16,
+    reset_type: ResetType,
+  ) -> Result<EcuResetResponse, UdsError> {
+    let request = [UdsServiceType::ECUReset as u8, reset_type as u8];
+    let response = self.positive_response(target_address, &request, 2).await?;
+    check_echo(&response, 1, &[reset_type as u8])?;
+    Ok(EcuResetResponse {
+      reset_type: response[1],
+      power_down_time: response.get(2).copied(),
+    })
+  }
+
+  // Clear the DTCs of `group`, ALL_DTC_GROUPS for every DTC
+  pub async fn clear_diagnostic_information(
+    &self,
+    target_address: u16,
+    group: u32,
+  ) -> Result<(), UdsError> {
+    let mut request = vec![UdsServiceType::ClearDiagnosticInformation as u8];
+    request.extend_from_slice(&group.to_be_bytes()[1..]);
+    self.positive_response(target_address, &request, 1).await?;
+    Ok(())
+  }
+
+  // reportNumberOfDTCByStatusMask (19 01)
+  pub async fn read_number_of_dtc_by_status_mask(
+    &self,
+    target_address: u16,
+    status_mask: u8,
+  ) -> Result<DtcCount, UdsError> {
+    let request = [UdsServiceType::ReadDTCInformation as u8, 0x01, status_mask];
+    let response = self.positive_response(target_address, &request, 6).await?;
+    check_echo(&response, 1, &[0x01])?;
+    Ok(DtcCount {
+      availability_mask: response[2],
+      format: response[3],
+      count: u16::from_be_bytes([response[4], response[5]]),
+    })
+  }
+
+  // reportDTCByStatusMask (19 02)
+  pub async fn read_dtc_by_status_mask(
+    &self,
+    target_address: u16,
+    status_mask: u8,
+  ) -> Result<DtcReport, UdsError> {
+    let request = [UdsServiceType::ReadDTCInformation as u8, 0x02, status_mask];
+    let response = self.positive_response(target_address, &request, 3).await?;
+    check_echo(&response, 1, &[0x02])?;
+    Ok(DtcReport {
+      availability_mask: response[2],
+      dtcs: parse_dtc_records(&response[3..]),
+    })
+  }
+
+  // reportSupportedDTC (19 0A)
+  pub async fn read_supported_dtcs(&self, target_address: u16) -> Result<DtcReport, UdsError> {
+    let request = [UdsServiceType::ReadDTCInformation as u8, 0x0A];
+    let response = self.positive_response(target_address, &request, 3).await?;
+    check_echo(&response, 1, &[0x0A])?;
+    Ok(DtcReport {
+      availability_mask: response[2],
+      dtcs: parse_dtc_records(&response[3..]),
+    })
+  }
+
+  // Read a single DID, its data is the rest of the response
+  pub async fn read_data_by_identifier(
+    &self,
+    target_address: u16,
+    did: u16,
+  ) -> Result<DataRecord, UdsError> {
+    let mut request = vec![UdsServiceType::ReadDataByIdentifier as u8];
+    request.extend_from_slice(&did.to_be_bytes());
+    let response = self.positive_response(target_address, &request, 3).await?;
+    check_echo(&response, 1, &did.to_be_bytes())?;
+    Ok(DataRecord {
+      did,
+      data: response[3..].to_vec(),
+    })
+  }
+
+  // Read several DIDs in one request, each given with the length of its data
+  pub async fn read_data_by_identifiers(
+    &self,
+    target_address: u16,
+    dids: &[(u16, usize)],
+  ) -> Result<Vec<DataRecord>, UdsError> {
+    let mut request = vec![UdsServiceType::ReadDataByIdentifier as u8];
+    for (did, _) in dids {
+      request.extend_from_slice(&did.to_be_bytes());
+    }
+    let response = self.positive_response(target_address, &request, 3).await?;
+    parse_data_records(dids, &response[1..])
+  }
+
+  pub async fn write_data_by_identifier(
+    &self,
+    target_address: u16,
+    did: u16,
+    data: &[u8],
+  ) -> Result<(), UdsError> {
+    let mut request = vec![UdsServiceType::WriteDataByIdentifier as u8];
+    request.extend_from_slice(&did.to_be_bytes());
+    request.extend_from_slice(data);
+    let response = self.positive_response(target_address, &request, 3).await?;
+    check_echo(&response, 1, &did.to_be_bytes())
+  }
+
+  // SecurityAccess requestSeed for the odd `level`, an all zero seed means the level is
+  // already unlocked
+  pub async fn security_access_request_seed(
+    &self,
+    target_address: u16,
+    level: u8,
+  ) -> Result<Vec<u8>, UdsError> {
+    check_request_seed_level(level)?;
+    let request = [UdsServiceType::SecurityAccess as u8, level];
+    let response = self.positive_response(target_address, &request, 2).await?;
+    check_echo(&response, 1, &[level])?;
+    Ok(response[2..].to_vec())
+  }
+
+  // SecurityAccess sendKey for the odd `level` the seed was requested for
+  pub async fn security_access_send_key(
+    &self,
+    target_address: u16,
+    level: u8,
+    key: &[u8],
+  ) -> Result<(), UdsError> {
+    check_request_seed_level(level)?;
+    let mut request = vec![UdsServiceType::SecurityAccess as u8, level + 1];
+    request.extend_from_slice(key);
+    let response = self.positive_response(target_address, &request, 2).await?;
+    check_echo(&response, 1, &[level + 1])
+  }
+
+  pub async fn communication_control(
+    &self,
+    target_address: u16,
+    control_type: u8,
+    communication_type: u8,
+  ) -> Result<(), UdsError> {
+    let request = [
+      UdsServiceType::CommunicationControl as u8,
+      control_type,
+      communication_type,
+    ];
+    let response = self.positive_response(target_address, &request, 2).await?;
+    check_echo(&response, 1, &[control_type])
+  }
+
+  pub async fn routine_control(
+    &self,
+    target_address: u16,
+    control_type: RoutineControlType,
+    routine_id: u16,
+    option_record: &[u8],
+  ) -> Result<RoutineResponse, UdsError> {
+    let mut request = vec![UdsServiceType::RoutineControl as u8, control_type as u8];
+    request.extend_from_slice(&routine_id.to_be_bytes());
+    request.extend_from_slice(option_record);
+    let response = self.positive_response(target_address, &request, 4).await?;
+    check_echo(&response, 1, &request[1..4])?;
+    Ok(RoutineResponse {
+      control_type: response[1],
+      routine_id,
+      status_record: response[4..].to_vec(),
+    })
+  }
+
+  // TesterPresent, with `suppress` only the DoIP ACK is awaited (3E 80)
+  pub async fn tester_present(&self, target_address: u16, suppress: bool) -> Result<(), UdsError> {
+    if suppress {
+      let request = [
+        UdsServiceType::TesterPresent as u8,
+        SUPPRESS_POSITIVE_RESPONSE,
+      ];
+      return Ok(self.request_suppressed(target_address, &request).await?);
+    }
+    let request = [UdsServiceType::TesterPresent as u8, 0x00];
+    let response = self.positive_response(target_address, &request, 2).await?;
+    check_echo(&response, 1, &[0x00])
+  }
+
+  pub async fn control_dtc_setting(
+    &self,
+    target_address: u16,
+    setting_type: DtcSettingType,
+  ) -> Result<(), UdsError> {
+    let request = [UdsServiceType::ControlDTCSetting as u8, setting_type as u8];
+    let response = self.positive_response(target_address, &request, 2).await?;
+    check_echo(&response, 1, &[setting_type as u8])
+  }
+}
@@ -0,0 +1,91 @@
+// Fixtures shared by the integration tests, each test crate uses a subset of them
+#![allow(dead_code)]
+
+use doip2http::doip_client::DoipConfig;
+use doip2http::doip_entity::DoipEntity;
+use doip2http::doip_sim::{VehicleDescription, VirtualVehicle};
+use doip2http::doip2http::{AppState, create_router};
+use doip2http::uds_client::{ReconnectPolicy, UdsClient, UdsTiming};
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+
+pub static TESTER: u16 = 0x0E00;
+
+// The demo vehicle served on `address`, each test uses its own loopback address as
+// the DoIP ports are fixed
+pub fn demo_vehicle(address: &str) -> VehicleDescription {
+  let mut description =
+    VehicleDescription::load(concat!(env!("CARGO_MANIFEST_DIR"), "/vehicles/demo.json")).unwrap();
+  description.address = address.to_string();
+  description
+}
+
+pub async fn start_vehicle(description: &VehicleDescription) {
+  let entity = DoipEntity::bind(
+    description.entity_config().unwrap(),
+    VirtualVehicle::new(description),
+  )
+  .await
+  .unwrap();
+  tokio::spawn(entity.run());
+}
+
+// TCP proxy on the DoIP port of `address` in front of the entity on `entity_address`.
+// Aborting the task drops the listener and every proxied connection with it.
+pub async fn start_proxy(address: &str, entity_address: &str) -> JoinHandle<()> {
+  let listener = TcpListener::bind((address, 13400)).await.unwrap();
+  let entity_address = format!("{}:13400", entity_address);
+  tokio::spawn(async move {
+    let mut connections = JoinSet::new();
+    loop {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let entity_address = entity_address.clone();
+      connections.spawn(async move {
+        let mut entity = TcpStream::connect(entity_address).await.unwrap();
+        let _ = copy_bidirectional(&mut stream, &mut entity).await;
+      });
+    }
+  })
+}
+
+// Connect as TESTER with the default timing and without reconnecting
+pub async fn connect(address: &str) -> UdsClient {
+  UdsClient::new(
+    address.to_string(),
+    TESTER,
+    UdsTiming::default(),
+    DoipConfig::default(),
+    ReconnectPolicy::default(),
+  )
+  .await
+  .unwrap()
+}
+
+pub async fn start_server(state: AppState) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let server = listener.local_addr().unwrap();
+  tokio::spawn(async move { axum::serve(listener, create_router(state)).await.unwrap() });
+  server
+}
+
+// Minimal HTTP/1.1 client for the JSON API
+pub async fn post(server: SocketAddr, path: &str, body: Value) -> (u16, Value) {
+  let body = body.to_string();
+  let mut stream = TcpStream::connect(server).await.unwrap();
+  let request = format!(
+    "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    path,
+    body.len(),
+    body
+  );
+  stream.write_all(request.as_bytes()).await.unwrap();
+
+  let mut response = String::new();
+  stream.read_to_string(&mut response).await.unwrap();
+  let status = response[9..12].parse().unwrap();
+  let (_, body) = response.split_once("\r\n\r\n").unwrap();
+  (status, serde_json::from_str(body).unwrap())
+}
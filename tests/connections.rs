@@ -0,0 +1,158 @@
+mod common;
+
+use common::{demo_vehicle, post, start_proxy, start_server, start_vehicle};
+use doip2http::doip_entity::{DoipEntity, DoipEntityConfig, Responder, UdsHandler, UdsRequest};
+use doip2http::doip2http::{AppState, reap_connections};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+
+static ENGINE: u16 = 0x1010;
+
+// ECU answering RoutineControl after 400ms of response pending, the rest at once
+struct SlowRoutines;
+
+impl UdsHandler for SlowRoutines {
+  async fn handle(&self, request: UdsRequest, responder: Responder) -> Option<Vec<u8>> {
+    let service_id = request.data[0];
+    if service_id == 0x31 {
+      responder.send(vec![0x7F, service_id, 0x78]).await.ok()?;
+      sleep(Duration::from_millis(400)).await;
+    }
+    let mut response = request.data;
+    response[0] = service_id + 0x40;
+    Some(response)
+  }
+}
+
+async fn start_entity(address: &str) {
+  let config = DoipEntityConfig {
+    address: address.parse().unwrap(),
+    target_addresses: vec![ENGINE],
+    ..DoipEntityConfig::default()
+  };
+  let entity = DoipEntity::bind(config, SlowRoutines).await.unwrap();
+  tokio::spawn(entity.run());
+}
+
+#[tokio::test]
+async fn validates_connect_options_before_connecting() {
+  let server = start_server(AppState::new()).await;
+
+  // An entity that never answers routing activation, the request must fail before
+  // connecting to it
+  let _silent = TcpListener::bind("127.0.0.71:13400").await.unwrap();
+  let started = Instant::now();
+  let (status, body) = post(
+    server,
+    "/connect",
+    json!({
+      "ecu_ip": "127.0.0.71",
+      "doip_source_address": "0x0E00",
+      "request_queue_size": 0,
+    }),
+  )
+  .await;
+  assert_eq!(status, 400);
+  assert_eq!(body["message"], "Request queue size must be at least 1");
+  assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+async fn connection_ids(state: &AppState) -> Vec<String> {
+  let mut ids: Vec<String> = state.connections.lock().await.keys().cloned().collect();
+  ids.sort();
+  ids
+}
+
+#[tokio::test]
+async fn reaps_idle_connections_but_spares_busy_ones() {
+  start_entity("127.0.0.78").await;
+  let state = AppState::new();
+  let server = start_server(state.clone()).await;
+  for source in ["0x0E00", "0x0E01"] {
+    let (status, body) = post(
+      server,
+      "/connect",
+      json!({"ecu_ip": "127.0.0.78", "doip_source_address": source, "idle_timeout_ms": 100}),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+  }
+
+  // A routine on 0x0E01 keeps its connection busy for longer than the idle timeout
+  let routine = tokio::spawn(post(
+    server,
+    "/diagnostic",
+    json!({
+      "ecu_ip": "127.0.0.78",
+      "doip_source_address": "0x0E01",
+      "doip_target_address": "0x1010",
+      "uds_data": "0x310101FF00",
+    }),
+  ));
+  sleep(Duration::from_millis(200)).await;
+  reap_connections(&state).await;
+  assert_eq!(connection_ids(&state).await, ["127.0.0.78:0x0E01"]);
+
+  let (status, body) = routine.await.unwrap();
+  assert_eq!(status, 200, "{}", body);
+  reap_connections(&state).await;
+  assert_eq!(connection_ids(&state).await, ["127.0.0.78:0x0E01"]);
+  sleep(Duration::from_millis(150)).await;
+  reap_connections(&state).await;
+  assert!(connection_ids(&state).await.is_empty());
+}
+
+#[tokio::test]
+async fn disconnect_closes_the_connection() {
+  start_entity("127.0.0.79").await;
+  let server = start_server(AppState::new()).await;
+  let connection = json!({"ecu_ip": "127.0.0.79", "doip_source_address": "0x0E00"});
+  let (status, body) = post(server, "/connect", connection.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+
+  let (status, body) = post(server, "/disconnect", connection.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["connection_id"], "127.0.0.79:0x0E00");
+  let (_, body) = post(server, "/status", connection.clone()).await;
+  assert_eq!(body["active_connections"], 0);
+  let (status, body) = post(server, "/disconnect", connection.clone()).await;
+  assert_eq!(status, 400);
+  assert_eq!(body["message"], "Connection not found");
+
+  // The socket was closed, so the entity accepts the source address again
+  let (status, body) = post(server, "/connect", connection).await;
+  assert_eq!(status, 200, "{}", body);
+}
+
+#[tokio::test]
+async fn invalid_requests_leave_a_disconnected_connection_alone() {
+  start_vehicle(&demo_vehicle("127.0.0.81")).await;
+  let proxy = start_proxy("127.0.0.80", "127.0.0.81").await;
+  let state = AppState::new();
+  let server = start_server(state.clone()).await;
+  let connection = json!({"ecu_ip": "127.0.0.80", "doip_source_address": "0x0E00"});
+  let (status, body) = post(server, "/connect", connection.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+
+  // Without a reconnect policy the connection stays down
+  proxy.abort();
+  timeout(Duration::from_secs(5), async {
+    loop {
+      let (_, body) = post(server, "/status", connection.clone()).await;
+      if body["connections"][0]["state"] == "disconnected" {
+        break;
+      }
+      sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .unwrap();
+
+  let mut invalid = connection.clone();
+  invalid["request_queue_size"] = 0.into();
+  let (status, _) = post(server, "/connect", invalid).await;
+  assert_eq!(status, 400);
+  assert_eq!(connection_ids(&state).await, ["127.0.0.80:0x0E00"]);
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use common::{TESTER, demo_vehicle, post, start_server, start_vehicle};
+use doip2http::common::net::LocalBinding;
+use doip2http::doip_client::DoipConfig;
+use doip2http::doip_discovery::{VehicleIdFilter, detect_protocol_version, discover_vehicles};
+use doip2http::doip_message::{VehicleAnnouncement, encode_doip_message};
+use doip2http::doip2http::AppState;
+use doip2http::uds_client::{ReconnectPolicy, UdsClient, UdsTiming};
+use serde_json::{Value, json};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+static WAIT: Duration = Duration::from_millis(500);
+
+#[tokio::test]
+async fn queries_entity_status_and_power_mode_with_the_connection_version() {
+  let mut description = demo_vehicle("127.0.0.82");
+  description.protocol_version = Some(0x03);
+  start_vehicle(&description).await;
+  let server = start_server(AppState::new()).await;
+  let entity = json!({"ecu_ip": "127.0.0.82", "timeout_ms": 500});
+
+  // Without a connection the default version is sent, which the entity rejects
+  let (status, body) = post(server, "/entity_status", entity.clone()).await;
+  assert_eq!(status, 400);
+  assert!(
+    body["message"].as_str().unwrap().contains("generic NACK"),
+    "{}",
+    body
+  );
+  let (status, body) = post(server, "/power_mode", entity.clone()).await;
+  assert_eq!(status, 400);
+  assert!(
+    body["message"].as_str().unwrap().contains("generic NACK"),
+    "{}",
+    body
+  );
+
+  let (status, body) = post(
+    server,
+    "/connect",
+    json!({"ecu_ip": "127.0.0.82", "doip_source_address": "0x0E00", "protocol_version": "0x03"}),
+  )
+  .await;
+  assert_eq!(status, 200, "{}", body);
+
+  let (status, body) = post(server, "/entity_status", entity.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["node_type"], "gateway");
+  assert_eq!(body["open_sockets"], 1);
+  let (status, body) = post(server, "/power_mode", entity).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["power_mode"], "ready");
+}
+
+#[tokio::test]
+async fn follows_the_protocol_version_of_the_entity() {
+  let mut description = demo_vehicle("127.0.0.83");
+  description.protocol_version = Some(0x03);
+  start_vehicle(&description).await;
+  let ecu = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 83)), 13400);
+
+  let version = detect_protocol_version(ecu, &LocalBinding::default(), WAIT)
+    .await
+    .unwrap();
+  assert_eq!(version, 0x03);
+
+  let doip_config = DoipConfig {
+    auto_detect_version: true,
+    ..DoipConfig::default()
+  };
+  let client = UdsClient::new(
+    "127.0.0.83".to_string(),
+    TESTER,
+    UdsTiming::default(),
+    doip_config,
+    ReconnectPolicy::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(client.protocol_version(), 0x03);
+  let response = client.request(0x1010, &[0x22, 0xF1, 0x90]).await.unwrap();
+  assert_eq!(response[..3], [0x62, 0xF1, 0x90]);
+}
+
+async fn discover(server: SocketAddr, filter: Value) -> Vec<Value> {
+  let mut request = json!({"target_ip": "127.0.0.84", "timeout_ms": 300});
+  request
+    .as_object_mut()
+    .unwrap()
+    .extend(filter.as_object().unwrap().clone());
+  let (status, body) = post(server, "/discover", request).await;
+  assert_eq!(status, 200, "{}", body);
+  body["entities"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn discovers_vehicles_by_vin_and_eid() {
+  start_vehicle(&demo_vehicle("127.0.0.84")).await;
+  let server = start_server(AppState::new()).await;
+
+  let entities = discover(server, json!({})).await;
+  assert_eq!(entities.len(), 1);
+  assert_eq!(entities[0]["ecu_ip"], "127.0.0.84");
+  assert_eq!(entities[0]["vin"], "DOIP2HTTPDEMO0001");
+  assert_eq!(entities[0]["eid"], "0x02AABBCCDD01");
+
+  assert_eq!(
+    discover(server, json!({"vin": "DOIP2HTTPDEMO0001"}))
+      .await
+      .len(),
+    1
+  );
+  assert!(
+    discover(server, json!({"vin": "DOIP2HTTPDEMO0002"}))
+      .await
+      .is_empty()
+  );
+  assert_eq!(
+    discover(server, json!({"eid": "0x02AABBCCDD01"}))
+      .await
+      .len(),
+    1
+  );
+  assert!(
+    discover(server, json!({"eid": "0x02AABBCCDD02"}))
+      .await
+      .is_empty()
+  );
+}
+
+#[tokio::test]
+async fn reports_repeated_announcements_once() {
+  // Entity that announces itself twice on every request
+  let socket = UdpSocket::bind("127.0.0.85:13400").await.unwrap();
+  tokio::spawn(async move {
+    let announcement = VehicleAnnouncement {
+      vin: "DOIP2HTTPDEMO0001".to_string(),
+      logical_address: 0x1001,
+      eid: [0x02, 0xAA, 0xBB, 0xCC, 0xDD, 0x01],
+      gid: [0x02, 0xAA, 0xBB, 0xCC, 0xDD, 0x01],
+      further_action: 0x00,
+      sync_status: None,
+    };
+    let message = encode_doip_message(0x02, 0x0004, Some(&announcement.encode()));
+    let mut buffer = [0u8; 64];
+    loop {
+      let (_, peer) = socket.recv_from(&mut buffer).await.unwrap();
+      for _ in 0..2 {
+        socket.send_to(&message, peer).await.unwrap();
+      }
+    }
+  });
+
+  let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 85)), 13400);
+  let entities = discover_vehicles(target, VehicleIdFilter::All, &LocalBinding::default(), WAIT)
+    .await
+    .unwrap();
+  assert_eq!(entities.len(), 1);
+  assert_eq!(entities[0].announcement.logical_address, 0x1001);
+}
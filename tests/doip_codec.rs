@@ -0,0 +1,215 @@
+use bytes::BytesMut;
+use doip2http::doip_codec::{
+  DoipCodec, DoipCodecError, NACK_INCORRECT_PATTERN_FORMAT, NACK_MESSAGE_TOO_LARGE,
+  NACK_UNKNOWN_PAYLOAD_TYPE,
+};
+use doip2http::doip_message::{
+  DiagnosticAck, DiagnosticMessage, DoipMessage, DoipPayload, EntityStatus,
+  RoutingActivationRequest, RoutingActivationResponse, VehicleAnnouncement, encode_doip_message,
+};
+use proptest::prelude::*;
+use tokio_util::codec::{Decoder, Encoder};
+
+fn version() -> impl Strategy<Value = u8> {
+  prop_oneof![Just(0x01u8), Just(0x02u8), Just(0x03u8)]
+}
+
+fn payload() -> impl Strategy<Value = DoipPayload> {
+  let user_data = prop::collection::vec(any::<u8>(), 1..64);
+  prop_oneof![
+    any::<u8>().prop_map(DoipPayload::GenericNack),
+    Just(DoipPayload::VehicleIdRequest),
+    any::<[u8; 6]>().prop_map(DoipPayload::VehicleIdRequestWithEid),
+    any::<[u8; 17]>().prop_map(DoipPayload::VehicleIdRequestWithVin),
+    (
+      "[A-Z0-9]{17}",
+      any::<u16>(),
+      any::<[u8; 6]>(),
+      any::<[u8; 6]>(),
+      any::<u8>(),
+      any::<Option<u8>>()
+    )
+      .prop_map(
+        |(vin, logical_address, eid, gid, further_action, sync_status)| {
+          DoipPayload::VehicleAnnouncement(VehicleAnnouncement {
+            vin,
+            logical_address,
+            eid,
+            gid,
+            further_action,
+            sync_status,
+          })
+        }
+      ),
+    (any::<u16>(), any::<u8>(), any::<Option<[u8; 4]>>()).prop_map(
+      |(source_address, activation_type, oem_data)| {
+        DoipPayload::RoutingActivationRequest(RoutingActivationRequest {
+          source_address,
+          activation_type,
+          oem_data,
+        })
+      }
+    ),
+    (
+      any::<u16>(),
+      any::<u16>(),
+      any::<u8>(),
+      any::<Option<u32>>()
+    )
+      .prop_map(
+        |(tester_address, entity_address, response_code, oem_data)| {
+          DoipPayload::RoutingActivationResponse(RoutingActivationResponse {
+            tester_address,
+            entity_address,
+            response_code,
+            oem_data,
+          })
+        }
+      ),
+    Just(DoipPayload::AliveCheckRequest),
+    any::<u16>().prop_map(DoipPayload::AliveCheckResponse),
+    Just(DoipPayload::EntityStatusRequest),
+    (any::<u8>(), any::<u8>(), any::<u8>(), any::<Option<u32>>()).prop_map(
+      |(node_type, max_concurrent_sockets, open_sockets, max_data_size)| {
+        DoipPayload::EntityStatusResponse(EntityStatus {
+          node_type,
+          max_concurrent_sockets,
+          open_sockets,
+          max_data_size,
+        })
+      }
+    ),
+    Just(DoipPayload::PowerModeInfoRequest),
+    any::<u8>().prop_map(DoipPayload::PowerModeInfoResponse),
+    (any::<u16>(), any::<u16>(), user_data.clone()).prop_map(
+      |(source_address, target_address, user_data)| {
+        DoipPayload::DiagnosticMessage(DiagnosticMessage {
+          source_address,
+          target_address,
+          user_data,
+        })
+      }
+    ),
+    (
+      any::<bool>(),
+      any::<u16>(),
+      any::<u16>(),
+      any::<u8>(),
+      prop::collection::vec(any::<u8>(), 0..16)
+    )
+      .prop_map(
+        |(positive, source_address, target_address, code, previous_message)| {
+          let ack = DiagnosticAck {
+            source_address,
+            target_address,
+            code,
+            previous_message,
+          };
+          if positive {
+            DoipPayload::DiagnosticPositiveAck(ack)
+          } else {
+            DoipPayload::DiagnosticNegativeAck(ack)
+          }
+        }
+      ),
+  ]
+}
+
+// Decode everything in `chunks`, feeding them one by one as separate reads
+fn decode_chunks(codec: &mut DoipCodec, chunks: &[&[u8]]) -> Vec<DoipMessage> {
+  let mut buffer = BytesMut::new();
+  let mut messages = Vec::new();
+  for chunk in chunks {
+    buffer.extend_from_slice(chunk);
+    while let Some(message) = codec.decode(&mut buffer).unwrap() {
+      messages.push(message);
+    }
+  }
+  assert!(buffer.is_empty());
+  messages
+}
+
+proptest! {
+  #[test]
+  fn message_round_trips(version in version(), payload in payload()) {
+    let message = DoipMessage::new(version, payload);
+    let mut codec = DoipCodec::new(u32::MAX);
+    let mut buffer = BytesMut::new();
+    codec.encode(message.clone(), &mut buffer).unwrap();
+
+    prop_assert_eq!(codec.decode(&mut buffer).unwrap(), Some(message));
+    prop_assert!(buffer.is_empty());
+  }
+
+  #[test]
+  fn frames_split_over_reads_decode(
+    version in version(),
+    payloads in prop::collection::vec(payload(), 1..8),
+    chunk_size in 1usize..64,
+  ) {
+    let messages: Vec<DoipMessage> =
+      payloads.into_iter().map(|payload| DoipMessage::new(version, payload)).collect();
+    let stream: Vec<u8> = messages.iter().flat_map(|message| message.encode()).collect();
+
+    let chunks: Vec<&[u8]> = stream.chunks(chunk_size).collect();
+    prop_assert_eq!(decode_chunks(&mut DoipCodec::new(u32::MAX), &chunks), messages);
+  }
+}
+
+#[test]
+fn multiple_frames_in_one_read() {
+  let first = DoipMessage::new(0x02, DoipPayload::AliveCheckRequest);
+  let second = DoipMessage::new(0x02, DoipPayload::AliveCheckResponse(0x0E00));
+  let mut stream = first.encode();
+  stream.extend_from_slice(&second.encode());
+
+  let messages = decode_chunks(&mut DoipCodec::new(u32::MAX), &[&stream]);
+  assert_eq!(messages, vec![first, second]);
+}
+
+#[test]
+fn unknown_payload_type_is_skipped() {
+  let mut codec = DoipCodec::new(u32::MAX);
+  let mut buffer = BytesMut::from(&encode_doip_message(0x02, 0x1234, Some(&[1, 2, 3]))[..]);
+  let next = DoipMessage::new(0x02, DoipPayload::AliveCheckRequest);
+  buffer.extend_from_slice(&next.encode());
+
+  assert!(matches!(
+    codec.decode(&mut buffer),
+    Err(DoipCodecError::Nack(code)) if code == NACK_UNKNOWN_PAYLOAD_TYPE
+  ));
+  assert_eq!(codec.decode(&mut buffer).unwrap(), Some(next));
+}
+
+#[test]
+fn oversized_payload_is_skipped_across_reads() {
+  let mut codec = DoipCodec::new(4);
+  let big = encode_doip_message(0x02, 0x8001, Some(&[0u8; 32]));
+  let mut buffer = BytesMut::from(&big[..12]);
+
+  assert!(matches!(
+    codec.decode(&mut buffer),
+    Err(DoipCodecError::Nack(code)) if code == NACK_MESSAGE_TOO_LARGE
+  ));
+  assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+  let next = DoipMessage::new(0x02, DoipPayload::AliveCheckRequest);
+  buffer.extend_from_slice(&big[12..]);
+  buffer.extend_from_slice(&next.encode());
+  assert_eq!(codec.decode(&mut buffer).unwrap(), Some(next));
+}
+
+#[test]
+fn version_is_fixed_by_first_frame() {
+  let mut codec = DoipCodec::new(u32::MAX);
+  let mut buffer =
+    BytesMut::from(&DoipMessage::new(0x03, DoipPayload::AliveCheckRequest).encode()[..]);
+  assert!(codec.decode(&mut buffer).unwrap().is_some());
+  assert_eq!(codec.version(), Some(0x03));
+
+  buffer.extend_from_slice(&DoipMessage::new(0x02, DoipPayload::AliveCheckRequest).encode());
+  assert!(matches!(
+    codec.decode(&mut buffer),
+    Err(DoipCodecError::Nack(code)) if code == NACK_INCORRECT_PATTERN_FORMAT
+  ));
+}
@@ -0,0 +1,327 @@
+use doip2http::common::net::LocalBinding;
+use doip2http::doip_client::{DoipClient, DoipConfig, DoipError, RoutingActivation};
+use doip2http::doip_discovery::{VehicleIdFilter, discover_vehicles, entity_status};
+use doip2http::doip_entity::{
+  DoipEntity, DoipEntityConfig, ROUTING_ACTIVATION_SOURCE_ADDRESS_ACTIVE,
+  ROUTING_ACTIVATION_UNKNOWN_SOURCE_ADDRESS, Responder, UdsHandler, UdsRequest,
+};
+use doip2http::doip_message::encode_doip_message;
+use doip2http::uds_client::{ReconnectPolicy, UdsClient, UdsTiming};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+// ECU behind the entity that answers after 200ms
+static SLOW_ECU: u16 = 0x1002;
+
+// Answers every request positively, 0x31 routines after a response pending
+struct EchoHandler;
+
+impl UdsHandler for EchoHandler {
+  async fn handle(&self, request: UdsRequest, responder: Responder) -> Option<Vec<u8>> {
+    let service_id = request.data[0];
+    if request.target_address == SLOW_ECU {
+      tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    if service_id == 0x31 {
+      responder.send(vec![0x7F, service_id, 0x78]).await.ok()?;
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let mut response = request.data;
+    response[0] = service_id + 0x40;
+    Some(response)
+  }
+}
+
+// Each test serves its entity on its own loopback address, the DoIP ports are fixed
+async fn start_entity(host: u8, customize: impl FnOnce(&mut DoipEntityConfig)) -> IpAddr {
+  let address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, host));
+  let mut config = DoipEntityConfig {
+    address,
+    ..DoipEntityConfig::default()
+  };
+  customize(&mut config);
+  let entity = DoipEntity::bind(config, EchoHandler).await.unwrap();
+  tokio::spawn(entity.run());
+  address
+}
+
+async fn connect(address: IpAddr, source_address: u16) -> Result<UdsClient, DoipError> {
+  UdsClient::new(
+    address.to_string(),
+    source_address,
+    UdsTiming::default(),
+    DoipConfig::default(),
+    ReconnectPolicy::default(),
+  )
+  .await
+}
+
+#[tokio::test]
+async fn serves_uds_requests() {
+  let address = start_entity(21, |_| {}).await;
+  let client = connect(address, 0x0E00).await.unwrap();
+  assert_eq!(client.entity_address(), 0x1001);
+
+  let response = client
+    .doip(Some(&[0x0E, 0x00, 0x10, 0x01, 0x22, 0xF1, 0x90]))
+    .await
+    .unwrap();
+  assert_eq!(response, vec![0x62, 0xF1, 0x90]);
+
+  // Response pending is handled by the tester's P2* loop
+  let response = client
+    .doip(Some(&[0x0E, 0x00, 0x10, 0x01, 0x31, 0x01, 0x02, 0x03]))
+    .await
+    .unwrap();
+  assert_eq!(response, vec![0x71, 0x01, 0x02, 0x03]);
+
+  assert_eq!(client.alive_check().await.unwrap(), 0x1001);
+}
+
+#[tokio::test]
+async fn rejects_unknown_targets_and_testers() {
+  let address = start_entity(22, |config| config.accepted_testers = vec![0x0E00]).await;
+
+  match connect(address, 0x0E80).await {
+    Err(DoipError::RoutingActivationDenied(code)) => {
+      assert_eq!(code, ROUTING_ACTIVATION_UNKNOWN_SOURCE_ADDRESS)
+    }
+    other => panic!(
+      "expected routing activation to be denied, got {:?}",
+      other.err()
+    ),
+  }
+
+  let client = connect(address, 0x0E00).await.unwrap();
+  match client
+    .doip(Some(&[0x0E, 0x00, 0x20, 0x01, 0x22, 0xF1, 0x90]))
+    .await
+  {
+    Err(DoipError::DiagnosticNack(0x03)) => {}
+    other => panic!("expected NACK 0x03, got {:?}", other),
+  }
+
+  // A second socket for an active tester address is refused while the first answers
+  match connect(address, 0x0E00).await {
+    Err(DoipError::RoutingActivationDenied(code)) => {
+      assert_eq!(code, ROUTING_ACTIVATION_SOURCE_ADDRESS_ACTIVE)
+    }
+    other => panic!(
+      "expected routing activation to be denied, got {:?}",
+      other.err()
+    ),
+  }
+}
+
+#[tokio::test]
+async fn answers_vehicle_identification() {
+  let address = start_entity(23, |_| {}).await;
+  let ecu = SocketAddr::new(address, 13400);
+  let wait = Duration::from_millis(500);
+
+  let entities = discover_vehicles(ecu, VehicleIdFilter::All, &LocalBinding::default(), wait)
+    .await
+    .unwrap();
+  assert_eq!(entities.len(), 1);
+  assert_eq!(entities[0].announcement.vin, "DOIP2HTTPSIM00001");
+  assert_eq!(entities[0].announcement.logical_address, 0x1001);
+
+  // Only sockets with an activated routing count as open
+  let idle = TcpStream::connect(ecu).await.unwrap();
+  let client = connect(address, 0x0E00).await.unwrap();
+  let status = entity_status(ecu, &LocalBinding::default(), 0x02, wait)
+    .await
+    .unwrap();
+  assert_eq!(status.open_sockets, 1);
+  drop(client);
+  drop(idle);
+}
+
+#[tokio::test]
+async fn closes_sockets_without_routing_activation() {
+  let address = start_entity(24, |config| {
+    config.initial_inactivity = Duration::from_millis(200)
+  })
+  .await;
+
+  let client = DoipClient::new(address.to_string(), 0x0E00, DoipConfig::default())
+    .await
+    .unwrap();
+  tokio::time::timeout(Duration::from_secs(2), client.closed())
+    .await
+    .expect("entity should close the inactive socket");
+}
+
+#[tokio::test]
+async fn routes_concurrent_responses_to_their_targets() {
+  let address = start_entity(25, |config| {
+    config.target_addresses = vec![0x1001, SLOW_ECU];
+  })
+  .await;
+  let client = connect(address, 0x0E00).await.unwrap();
+
+  // The slow ECU is asked first and answers last, both exchanges share the socket
+  let started = std::time::Instant::now();
+  let (slow, fast) = tokio::join!(client.request(SLOW_ECU, &[0x22, 0xF1, 0x90]), async {
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let response = client.request(0x1001, &[0x22, 0xF1, 0x8C]).await;
+    (response, started.elapsed())
+  });
+  let (fast, fast_elapsed) = fast;
+  assert_eq!(slow.unwrap(), [0x62, 0xF1, 0x90]);
+  assert_eq!(fast.unwrap(), [0x62, 0xF1, 0x8C]);
+  assert!(fast_elapsed < Duration::from_millis(150));
+}
+
+async fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+  let mut message = vec![0u8; 8];
+  stream.read_exact(&mut message).await.unwrap();
+  let len = u32::from_be_bytes([message[4], message[5], message[6], message[7]]) as usize;
+  message.resize(8 + len, 0);
+  stream.read_exact(&mut message[8..]).await.unwrap();
+  message
+}
+
+#[tokio::test]
+async fn fails_requests_on_a_closed_connection_at_once() {
+  // Entity that activates routing and closes the socket on the next message without
+  // answering it
+  let listener = TcpListener::bind("127.0.0.26:13400").await.unwrap();
+  tokio::spawn(async move {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let request = read_message(&mut stream).await;
+    let response = [0x0E, 0x00, 0x10, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00];
+    let message = encode_doip_message(request[0], 0x0006, Some(&response));
+    stream.write_all(&message).await.unwrap();
+    read_message(&mut stream).await;
+  });
+  let client = connect("127.0.0.26".parse().unwrap(), 0x0E00)
+    .await
+    .unwrap();
+
+  // The alive check waits on the control channel when the socket goes away
+  let started = Instant::now();
+  match client.alive_check().await {
+    Err(DoipError::Io(e)) if e.kind() == ErrorKind::NotConnected => {}
+    other => panic!("expected NotConnected, got {:?}", other),
+  }
+  assert!(started.elapsed() < Duration::from_secs(1));
+
+  // A target never used before the socket closed
+  match client.request(0x1001, &[0x3E, 0x00]).await {
+    Err(DoipError::Io(e)) if e.kind() == ErrorKind::NotConnected => {}
+    other => panic!("expected NotConnected, got {:?}", other),
+  }
+  assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+// Entity that answers routing activation with each of `codes` in turn and hands over
+// the request it received
+async fn start_scripted_activation(host: u8, codes: Vec<u8>) -> oneshot::Receiver<Vec<u8>> {
+  let listener = TcpListener::bind((Ipv4Addr::new(127, 0, 0, host), 13400))
+    .await
+    .unwrap();
+  let (request_tx, request_rx) = oneshot::channel();
+  tokio::spawn(async move {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let request = read_message(&mut stream).await;
+    for code in codes {
+      tokio::time::sleep(Duration::from_millis(100)).await;
+      let response = [0x0E, 0x00, 0x10, 0x01, code, 0x00, 0x00, 0x00, 0x00];
+      let message = encode_doip_message(request[0], 0x0006, Some(&response));
+      stream.write_all(&message).await.unwrap();
+    }
+    let _ = request_tx.send(request);
+    // Keep the socket open until the tester closes it
+    let _ = stream.read(&mut [0u8; 64]).await;
+  });
+  request_rx
+}
+
+fn activation_config(activation_type: u8, oem_data: Option<[u8; 4]>) -> DoipConfig {
+  DoipConfig {
+    routing_activation: RoutingActivation {
+      activation_type,
+      oem_data,
+    },
+    ..DoipConfig::default()
+  }
+}
+
+#[tokio::test]
+async fn sends_the_activation_type_and_oem_data() {
+  let request = start_scripted_activation(27, vec![0x10]).await;
+  let config = activation_config(0xE0, Some([0xDE, 0xAD, 0xBE, 0xEF]));
+  let client = DoipClient::new("127.0.0.27".to_string(), 0x0E00, config)
+    .await
+    .unwrap();
+  client.activate_routing().await.unwrap();
+
+  let request = request.await.unwrap();
+  assert_eq!(request[2..4], [0x00, 0x05]);
+  assert_eq!(
+    request[8..],
+    [
+      0x0E, 0x00, 0xE0, 0x00, 0x00, 0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF
+    ]
+  );
+}
+
+#[tokio::test]
+async fn waits_for_the_confirmed_routing_activation() {
+  let request = start_scripted_activation(28, vec![0x11, 0x10]).await;
+  let client = DoipClient::new("127.0.0.28".to_string(), 0x0E00, DoipConfig::default())
+    .await
+    .unwrap();
+  let response = client.activate_routing().await.unwrap();
+  assert_eq!(response.response_code, 0x10);
+  assert_eq!(response.entity_address, 0x1001);
+  // Without OEM data the request ends after the ISO reserved bytes
+  assert_eq!(
+    request.await.unwrap()[8..],
+    [0x0E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+  );
+
+  let _request = start_scripted_activation(29, vec![0x11, 0x06]).await;
+  let client = DoipClient::new("127.0.0.29".to_string(), 0x0E00, DoipConfig::default())
+    .await
+    .unwrap();
+  match client.activate_routing().await {
+    Err(DoipError::RoutingActivationDenied(0x06)) => {}
+    other => panic!("expected routing activation to be denied, got {:?}", other),
+  }
+}
+
+#[tokio::test]
+async fn answers_alive_checks_with_the_tester_address() {
+  // Entity that activates routing and then checks whether the idle tester is alive
+  let listener = TcpListener::bind("127.0.0.30:13400").await.unwrap();
+  let (response_tx, response_rx) = oneshot::channel();
+  tokio::spawn(async move {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let request = read_message(&mut stream).await;
+    let mut response = request[8..10].to_vec();
+    response.extend_from_slice(&[0x10, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00]);
+    let message = encode_doip_message(request[0], 0x0006, Some(&response));
+    stream.write_all(&message).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let message = encode_doip_message(request[0], 0x0007, None);
+    stream.write_all(&message).await.unwrap();
+    let _ = response_tx.send(read_message(&mut stream).await);
+  });
+  let _client = connect("127.0.0.30".parse().unwrap(), 0x0E42)
+    .await
+    .unwrap();
+
+  let response = tokio::time::timeout(Duration::from_secs(2), response_rx)
+    .await
+    .expect("tester should answer the alive check")
+    .unwrap();
+  assert_eq!(response[2..4], [0x00, 0x08]);
+  assert_eq!(response[8..], [0x0E, 0x42]);
+}
@@ -0,0 +1,220 @@
+mod common;
+
+use common::{TESTER, connect, demo_vehicle, post, start_server, start_vehicle};
+use doip2http::doip_sim::VehicleDescription;
+use doip2http::doip2http::AppState;
+use doip2http::uds_client::UdsClient;
+use std::time::Duration;
+use tokio::time::sleep;
+
+static ENGINE: u16 = 0x1010;
+
+async fn start_demo(description: VehicleDescription) -> UdsClient {
+  start_vehicle(&description).await;
+  connect(&description.address).await
+}
+
+async fn request(client: &UdsClient, target_address: u16, uds: &[u8]) -> Vec<u8> {
+  let mut data = Vec::from(TESTER.to_be_bytes());
+  data.extend_from_slice(&target_address.to_be_bytes());
+  data.extend_from_slice(uds);
+  client.doip(Some(&data)).await.unwrap()
+}
+
+// Request a seed for `level` and answer it with seed XOR mask
+async fn unlock(client: &UdsClient, level: u8, mask: u8) -> Vec<u8> {
+  let seed = request(client, ENGINE, &[0x27, level]).await;
+  assert_eq!(seed[..2], [0x67, level]);
+  let mut key = vec![0x27, level + 1];
+  key.extend(seed[2..].iter().map(|byte| byte ^ mask));
+  request(client, ENGINE, &key).await
+}
+
+#[tokio::test]
+async fn serves_the_http_api_end_to_end() {
+  start_vehicle(&demo_vehicle("127.0.0.31")).await;
+  let server = start_server(AppState::new()).await;
+
+  let connection = serde_json::json!({
+    "ecu_ip": "127.0.0.31",
+    "doip_source_address": "0x0E00",
+  });
+  let (status, body) = post(server, "/connect", connection.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+
+  for target in ["0x1010", "0x1020"] {
+    let mut diagnostic = connection.clone();
+    diagnostic["doip_target_address"] = target.into();
+    diagnostic["uds_data"] = "0x22F190".into();
+    let (status, body) = post(server, "/diagnostic", diagnostic).await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(
+      body["response_data"]
+        .as_str()
+        .unwrap()
+        .starts_with("0x[62, F1, 90, 44, 4F, 49, 50"),
+      "{}",
+      body
+    );
+  }
+
+  // Negative responses are decoded
+  let mut diagnostic = connection.clone();
+  diagnostic["doip_target_address"] = "0x1010".into();
+  diagnostic["uds_data"] = "0x2701".into();
+  let (status, body) = post(server, "/diagnostic", diagnostic).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["success"], false);
+  assert_eq!(body["nrc"], "0x7F");
+  assert_eq!(body["nrc_name"], "serviceNotSupportedInActiveSession");
+  assert_eq!(body["service"], "0x27");
+  assert_eq!(body["service_name"], "SecurityAccess");
+
+  let mut diagnostic = connection.clone();
+  diagnostic["doip_target_address"] = "0x1030".into();
+  diagnostic["uds_data"] = "0x22F190".into();
+  let (_, body) = post(server, "/diagnostic", diagnostic).await;
+  assert_eq!(body["success"], false);
+}
+
+#[tokio::test]
+async fn models_sessions_and_security_access() {
+  let mut description = demo_vehicle("127.0.0.32");
+  description.ecus[1].security_levels[0].lockout_ms = 300;
+  let client = start_demo(description).await;
+
+  assert_eq!(
+    request(&client, ENGINE, &[0x27, 0x01]).await,
+    [0x7F, 0x27, 0x7F]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x10, 0x03]).await,
+    [0x50, 0x03, 0x00, 0x32, 0x01, 0xF4]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x2E, 0x01, 0x00, 0x11, 0x22]).await,
+    [0x7F, 0x2E, 0x33]
+  );
+  for reserved in [0x00, 0x80] {
+    assert_eq!(
+      request(&client, ENGINE, &[0x27, reserved, 0x00]).await,
+      [0x7F, 0x27, 0x12]
+    );
+  }
+
+  // Three invalid keys lock the level out until the delay has expired
+  for nrc in [0x35, 0x35, 0x36] {
+    assert_eq!(unlock(&client, 0x01, 0x00).await, [0x7F, 0x27, nrc]);
+  }
+  assert_eq!(
+    request(&client, ENGINE, &[0x27, 0x01]).await,
+    [0x7F, 0x27, 0x37]
+  );
+  sleep(Duration::from_millis(350)).await;
+  assert_eq!(unlock(&client, 0x01, 0x5A).await, [0x67, 0x02]);
+
+  assert_eq!(
+    request(&client, ENGINE, &[0x2E, 0x01, 0x00, 0x11, 0x22]).await,
+    [0x6E, 0x01, 0x00]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x22, 0x01, 0x00]).await,
+    [0x62, 0x01, 0x00, 0x11, 0x22]
+  );
+
+  // The DID is only available in the extended session
+  assert_eq!(
+    request(&client, ENGINE, &[0x10, 0x01]).await[..2],
+    [0x50, 0x01]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x22, 0x01, 0x00]).await,
+    [0x7F, 0x22, 0x31]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x10, 0x04]).await,
+    [0x7F, 0x10, 0x12]
+  );
+}
+
+#[tokio::test]
+async fn falls_back_to_the_default_session_after_s3() {
+  let mut description = demo_vehicle("127.0.0.33");
+  description.ecus[1].s3_ms = 300;
+  let client = start_demo(description).await;
+
+  request(&client, ENGINE, &[0x10, 0x03]).await;
+  for _ in 0..3 {
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(request(&client, ENGINE, &[0x3E, 0x00]).await, [0x7E, 0x00]);
+  }
+  assert_eq!(
+    request(&client, ENGINE, &[0x27, 0x01]).await[..2],
+    [0x67, 0x01]
+  );
+
+  sleep(Duration::from_millis(400)).await;
+  assert_eq!(
+    request(&client, ENGINE, &[0x27, 0x01]).await,
+    [0x7F, 0x27, 0x7F]
+  );
+}
+
+#[tokio::test]
+async fn runs_routines_and_reports_dtcs() {
+  let mut description = demo_vehicle("127.0.0.34");
+  description.ecus[1].routines[0].duration_ms = 300;
+  let client = start_demo(description).await;
+
+  // The slow routine is answered with response pending first
+  request(&client, ENGINE, &[0x10, 0x02]).await;
+  assert_eq!(unlock(&client, 0x11, 0xC3).await, [0x67, 0x12]);
+  assert_eq!(
+    request(&client, ENGINE, &[0x31, 0x01, 0xFF, 0x00]).await,
+    [0x71, 0x01, 0xFF, 0x00, 0x00]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x31, 0x03, 0x02, 0x03]).await,
+    [0x7F, 0x31, 0x24]
+  );
+
+  assert_eq!(
+    request(&client, ENGINE, &[0x19, 0x02, 0xFF]).await,
+    [
+      0x59, 0x02, 0xFF, 0x01, 0x23, 0x00, 0x2F, 0x03, 0x01, 0x00, 0x08
+    ]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x14, 0xFF, 0xFF, 0xFF]).await,
+    [0x54]
+  );
+  assert_eq!(
+    request(&client, ENGINE, &[0x19, 0x01, 0xFF]).await,
+    [0x59, 0x01, 0xFF, 0x01, 0x00, 0x00]
+  );
+}
+
+#[test]
+fn rejects_invalid_descriptions() {
+  let error =
+    VehicleDescription::from_json(r#"{"vin": "SHORT", "gateway_address": "0x1001", "ecus": []}"#)
+      .unwrap_err();
+  assert!(error.to_string().contains("17 characters"));
+
+  let error = VehicleDescription::from_json(
+    r#"{"vin": "DOIP2HTTPDEMO0001", "gateway_address": "1001", "ecus": []}"#,
+  )
+  .unwrap_err();
+  assert!(error.to_string().contains("must start with '0x'"));
+
+  for p2_star_ms in [0, 9, 655_351] {
+    let error = VehicleDescription::from_json(&format!(
+      r#"{{"vin": "DOIP2HTTPDEMO0001", "gateway_address": "0x1001", "ecus": [
+        {{"name": "gateway", "logical_address": "0x1001", "p2_star_ms": {}}}
+      ]}}"#,
+      p2_star_ms
+    ))
+    .unwrap_err();
+    assert!(error.to_string().contains("P2*"), "{}", error);
+  }
+}
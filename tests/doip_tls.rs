@@ -0,0 +1,101 @@
+mod common;
+
+use common::{TESTER, demo_vehicle, start_vehicle};
+use doip2http::doip_client::DoipConfig;
+use doip2http::doip_tls::{DOIP_TLS_PORT, DoipTlsConfig};
+use doip2http::uds_client::{ReconnectPolicy, UdsClient, UdsTiming};
+use rcgen::{CertifiedKey, generate_simple_self_signed};
+use rustls_pki_types::PrivateKeyDer;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+
+static ENGINE: u16 = 0x1010;
+
+// Serve the demo vehicle on `address` and secure it with a TLS acceptor on the DoIP
+// TLS port that forwards to the entity's plain TCP port
+async fn start_tls_vehicle(address: &str, certificate: &CertifiedKey) {
+  start_vehicle(&demo_vehicle(address)).await;
+
+  let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(
+      vec![certificate.cert.der().clone()],
+      PrivateKeyDer::Pkcs8(certificate.key_pair.serialize_der().into()),
+    )
+    .unwrap();
+  let acceptor = TlsAcceptor::from(Arc::new(config));
+  let listener = TcpListener::bind((address, DOIP_TLS_PORT)).await.unwrap();
+  let entity_address = format!("{}:13400", address);
+  tokio::spawn(async move {
+    loop {
+      let (stream, _) = listener.accept().await.unwrap();
+      let acceptor = acceptor.clone();
+      let entity_address = entity_address.clone();
+      tokio::spawn(async move {
+        // A failed handshake just closes the connection
+        let Ok(mut tls) = acceptor.accept(stream).await else {
+          return;
+        };
+        let mut entity = TcpStream::connect(entity_address).await.unwrap();
+        let _ = copy_bidirectional(&mut tls, &mut entity).await;
+      });
+    }
+  });
+}
+
+// Write `certificate` as a PEM CA bundle for the client
+fn write_ca_file(name: &str, certificate: &CertifiedKey) -> PathBuf {
+  let path = std::env::temp_dir().join(format!("doip2http-{}-{}.pem", name, std::process::id()));
+  std::fs::write(&path, certificate.cert.pem()).unwrap();
+  path
+}
+
+async fn connect(address: &str, ca_file: &Path) -> Result<UdsClient, String> {
+  let doip_config = DoipConfig {
+    tls: Some(DoipTlsConfig {
+      ca_file: ca_file.to_string_lossy().into_owned(),
+      ..DoipTlsConfig::default()
+    }),
+    ..DoipConfig::default()
+  };
+  UdsClient::new(
+    address.to_string(),
+    TESTER,
+    UdsTiming::default(),
+    doip_config,
+    ReconnectPolicy::default(),
+  )
+  .await
+  .map_err(|e| e.to_string())
+}
+
+#[tokio::test]
+async fn activates_routing_and_exchanges_over_tls() {
+  let certificate = generate_simple_self_signed(vec!["127.0.0.72".to_string()]).unwrap();
+  start_tls_vehicle("127.0.0.72", &certificate).await;
+  let ca_file = write_ca_file("trusted", &certificate);
+
+  let client = connect("127.0.0.72", &ca_file).await.unwrap();
+  let response = client.request(ENGINE, &[0x22, 0xF1, 0x90]).await.unwrap();
+  assert_eq!(response[..3], [0x62, 0xF1, 0x90]);
+  std::fs::remove_file(ca_file).unwrap();
+}
+
+#[tokio::test]
+async fn rejects_an_untrusted_entity_certificate() {
+  let certificate = generate_simple_self_signed(vec!["127.0.0.73".to_string()]).unwrap();
+  start_tls_vehicle("127.0.0.73", &certificate).await;
+  let other = generate_simple_self_signed(vec!["127.0.0.73".to_string()]).unwrap();
+  let ca_file = write_ca_file("untrusted", &other);
+
+  let error = connect("127.0.0.73", &ca_file).await.err().unwrap();
+  assert!(error.contains("certificate"), "{}", error);
+  std::fs::remove_file(ca_file).unwrap();
+}
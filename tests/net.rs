@@ -0,0 +1,81 @@
+use doip2http::common::net::{LocalBinding, format_ip, host_name, resolve_address};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpListener, UdpSocket};
+
+#[tokio::test]
+async fn resolves_ip_literals() {
+  assert_eq!(
+    resolve_address("192.168.1.10", 13400).await.unwrap(),
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 13400)
+  );
+  for address in ["fd00::10", "[fd00::10]"] {
+    assert_eq!(
+      resolve_address(address, 13400).await.unwrap(),
+      SocketAddr::new("fd00::10".parse::<Ipv6Addr>().unwrap().into(), 13400)
+    );
+  }
+}
+
+#[tokio::test]
+async fn resolves_scoped_ipv6() {
+  let SocketAddr::V6(address) = resolve_address("[fe80::1%1]", 3496).await.unwrap() else {
+    panic!("expected an IPv6 address");
+  };
+  assert_eq!(address.ip(), &"fe80::1".parse::<Ipv6Addr>().unwrap());
+  assert_eq!(address.scope_id(), 1);
+  assert_eq!(address.port(), 3496);
+
+  let by_name = resolve_address("fe80::1%lo", 13400).await.unwrap();
+  assert_eq!(format_ip(&by_name), "fe80::1%lo");
+
+  assert!(resolve_address("fe80::1%no-such-if", 13400).await.is_err());
+  assert!(resolve_address("192.168.1.10%eth0", 13400).await.is_err());
+}
+
+#[tokio::test]
+async fn resolves_host_names() {
+  let address = resolve_address("localhost", 13400).await.unwrap();
+  assert!(address.ip().is_loopback());
+  assert_eq!(address.port(), 13400);
+}
+
+#[test]
+fn host_name_strips_brackets_and_zone() {
+  assert_eq!(host_name("192.168.1.10"), "192.168.1.10");
+  assert_eq!(host_name("[fe80::1%eth0]"), "fe80::1");
+  assert_eq!(host_name("ecu.local"), "ecu.local");
+}
+
+#[tokio::test]
+async fn binds_the_local_address_and_port() {
+  // Find a free local port
+  let port = TcpListener::bind("127.0.0.1:0")
+    .await
+    .unwrap()
+    .local_addr()
+    .unwrap()
+    .port();
+  let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+  let binding = LocalBinding {
+    address: Some(local),
+    interface: None,
+  };
+
+  // The entity sees the tester connect from the bound address and port
+  let entity = TcpListener::bind("127.0.0.86:13400").await.unwrap();
+  let _stream = binding.connect(entity.local_addr().unwrap()).await.unwrap();
+  let (_, peer) = entity.accept().await.unwrap();
+  assert_eq!(peer, local);
+
+  let entity = UdpSocket::bind("127.0.0.86:13400").await.unwrap();
+  let socket = binding
+    .udp_socket(&entity.local_addr().unwrap())
+    .await
+    .unwrap();
+  socket
+    .send_to(&[0x02], entity.local_addr().unwrap())
+    .await
+    .unwrap();
+  let (_, peer) = entity.recv_from(&mut [0u8; 8]).await.unwrap();
+  assert_eq!(peer, local);
+}
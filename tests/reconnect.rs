@@ -0,0 +1,84 @@
+mod common;
+
+use common::{TESTER, demo_vehicle, start_proxy, start_vehicle};
+use doip2http::doip_client::DoipConfig;
+use doip2http::uds_client::{ConnectionState, ReconnectPolicy, UdsClient, UdsTiming};
+use doip2http::uds_service::EXTENDED_SESSION;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+static ENGINE: u16 = 0x1010;
+
+async fn connect(address: &str, max_attempts: Option<u32>) -> UdsClient {
+  let reconnect = ReconnectPolicy {
+    enabled: true,
+    max_attempts,
+    initial_delay: Duration::from_millis(50),
+    max_delay: Duration::from_millis(100),
+    restore_session: true,
+  };
+  UdsClient::new(
+    address.to_string(),
+    TESTER,
+    UdsTiming::default(),
+    DoipConfig::default(),
+    reconnect,
+  )
+  .await
+  .unwrap()
+}
+
+async fn wait_for_state(client: &UdsClient, state: ConnectionState) {
+  timeout(Duration::from_secs(5), async {
+    while client.state() != state {
+      sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .unwrap_or_else(|_| panic!("still {:?}, expected {:?}", client.state(), state));
+}
+
+fn has_event(client: &UdsClient, event: &str) -> bool {
+  client.events().iter().any(|e| e.event == event)
+}
+
+#[tokio::test]
+async fn reconnects_and_restores_the_session() {
+  start_vehicle(&demo_vehicle("127.0.0.75")).await;
+  let proxy = start_proxy("127.0.0.74", "127.0.0.75").await;
+  let client = connect("127.0.0.74", None).await;
+  client
+    .diagnostic_session_control(ENGINE, EXTENDED_SESSION)
+    .await
+    .unwrap();
+
+  proxy.abort();
+  wait_for_state(&client, ConnectionState::Reconnecting).await;
+  assert!(client.request(ENGINE, &[0x3E, 0x00]).await.is_err());
+
+  let _proxy = start_proxy("127.0.0.74", "127.0.0.75").await;
+  wait_for_state(&client, ConnectionState::Connected).await;
+  assert!(has_event(&client, "reconnected"));
+  assert!(has_event(&client, "session_restored"));
+  // SecurityAccess is only served in the extended session
+  let seed = client.request(ENGINE, &[0x27, 0x01]).await.unwrap();
+  assert_eq!(seed[..2], [0x67, 0x01]);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+  start_vehicle(&demo_vehicle("127.0.0.77")).await;
+  let proxy = start_proxy("127.0.0.76", "127.0.0.77").await;
+  let client = connect("127.0.0.76", Some(2)).await;
+
+  proxy.abort();
+  wait_for_state(&client, ConnectionState::Disconnected).await;
+  let failures = client
+    .events()
+    .iter()
+    .filter(|e| e.event == "reconnect_failed")
+    .count();
+  assert_eq!(failures, 2);
+  assert!(has_event(&client, "gave_up"));
+  assert!(client.request(ENGINE, &[0x3E, 0x00]).await.is_err());
+}
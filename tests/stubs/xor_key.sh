@@ -0,0 +1,12 @@
+#!/bin/sh
+# Key command stub: reads a key request from stdin and answers with the seed XOR the
+# mask byte given as the first argument
+read -r request
+seed=$(printf '%s' "$request" | sed -n 's/.*"seed":"0x\([0-9A-Fa-f]*\)".*/\1/p')
+key=""
+while [ -n "$seed" ]; do
+  byte=${seed%"${seed#??}"}
+  seed=${seed#??}
+  key="$key$(printf '%02X' $((0x$byte ^ 0x$1)))"
+done
+printf '{"key": "0x%s"}\n' "$key"
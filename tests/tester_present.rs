@@ -0,0 +1,161 @@
+mod common;
+
+use common::{connect, demo_vehicle, post, start_server, start_vehicle};
+use doip2http::connection::{ConnectionHandle, TesterPresentConfig};
+use doip2http::doip_entity::{DoipEntity, DoipEntityConfig, Responder, UdsHandler, UdsRequest};
+use doip2http::doip2http::AppState;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::sleep;
+
+static ENGINE: u16 = 0x1010;
+
+// Demo vehicle whose engine falls back to the default session after 300ms
+async fn start_demo(address: &str) {
+  let mut description = demo_vehicle(address);
+  description.ecus[1].s3_ms = 300;
+  start_vehicle(&description).await;
+}
+
+// ECU that rejects TesterPresent 3E 80 with a negative response after 30ms, while
+// answering ReadDataByIdentifier after 60ms
+struct RejectingTesterPresent;
+
+impl UdsHandler for RejectingTesterPresent {
+  async fn handle(&self, request: UdsRequest, _responder: Responder) -> Option<Vec<u8>> {
+    let service_id = request.data[0];
+    if service_id == 0x3E {
+      sleep(Duration::from_millis(30)).await;
+      return Some(vec![0x7F, service_id, 0x12]);
+    }
+    sleep(Duration::from_millis(60)).await;
+    let mut response = request.data;
+    response[0] = service_id + 0x40;
+    Some(response)
+  }
+}
+
+async fn diagnostic(server: SocketAddr, connection: &Value, uds_data: &str) -> String {
+  let mut request = connection.clone();
+  request["doip_target_address"] = "0x1010".into();
+  request["uds_data"] = uds_data.into();
+  let (status, body) = post(server, "/diagnostic", request).await;
+  assert_eq!(status, 200, "{}", body);
+  body["response_data"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn keeps_the_session_alive_until_stopped() {
+  start_demo("127.0.0.61").await;
+  let server = start_server(AppState::new()).await;
+
+  let connection = serde_json::json!({
+    "ecu_ip": "127.0.0.61",
+    "doip_source_address": "0x0E00",
+  });
+  let mut connect = connection.clone();
+  connect["tester_present"] = serde_json::json!({"target_address": "0x1010", "interval_ms": 100});
+  let (status, body) = post(server, "/connect", connect).await;
+  assert_eq!(status, 200, "{}", body);
+  let (_, body) = post(server, "/status", connection.clone()).await;
+  assert_eq!(
+    body["connections"][0]["tester_present"],
+    serde_json::json!({"target_address": "0x1010", "interval_ms": 100})
+  );
+
+  // Requests interleave with the keep-alive on the same target
+  diagnostic(server, &connection, "0x1003").await;
+  for _ in 0..10 {
+    assert!(
+      diagnostic(server, &connection, "0x22F190")
+        .await
+        .starts_with("0x[62, F1, 90")
+    );
+    sleep(Duration::from_millis(70)).await;
+  }
+  // Only TesterPresent reaches the ECU for longer than S3
+  sleep(Duration::from_millis(700)).await;
+  assert!(
+    diagnostic(server, &connection, "0x2701")
+      .await
+      .starts_with("0x[67, 1")
+  );
+
+  let mut toggle = connection.clone();
+  toggle["enabled"] = false.into();
+  let (status, body) = post(server, "/tester_present", toggle.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["message"], "TesterPresent stopped");
+  sleep(Duration::from_millis(500)).await;
+  assert_eq!(
+    diagnostic(server, &connection, "0x2701").await,
+    "0x[7F, 27, 7F]"
+  );
+
+  toggle["enabled"] = true.into();
+  let (status, _) = post(server, "/tester_present", toggle.clone()).await;
+  assert_eq!(status, 400);
+  toggle["target_address"] = "0x1010".into();
+  let (status, body) = post(server, "/tester_present", toggle).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["tester_present"]["interval_ms"], 2000);
+}
+
+#[tokio::test]
+async fn stops_with_the_connection_without_counting_as_activity() {
+  start_demo("127.0.0.62").await;
+  let client = connect("127.0.0.62").await;
+  let handle = ConnectionHandle::spawn(client, 4);
+  let config = TesterPresentConfig {
+    target_address: ENGINE,
+    interval: Duration::from_millis(50),
+  };
+  handle.start_tester_present(config);
+  assert_eq!(handle.tester_present(), Some(config));
+
+  // TesterPresent keeps the ECU's session, not the idle timeout of the connection
+  sleep(Duration::from_millis(300)).await;
+  assert!(handle.client().idle_time() >= Duration::from_millis(300));
+
+  handle.shutdown().await;
+  assert_eq!(handle.tester_present(), None);
+}
+
+#[tokio::test]
+async fn late_negative_responses_to_tester_present_are_dropped() {
+  let config = DoipEntityConfig {
+    address: "127.0.0.63".parse().unwrap(),
+    target_addresses: vec![ENGINE],
+    ..DoipEntityConfig::default()
+  };
+  let entity = DoipEntity::bind(config, RejectingTesterPresent)
+    .await
+    .unwrap();
+  tokio::spawn(entity.run());
+  let client = connect("127.0.0.63").await;
+
+  // The NRC to 3E 80 arrives while the next request waits for its response
+  client.keep_alive(ENGINE).await.unwrap();
+  assert_eq!(
+    client.request(ENGINE, &[0x22, 0xF1, 0x90]).await.unwrap(),
+    vec![0x62, 0xF1, 0x90]
+  );
+
+  let handle = ConnectionHandle::spawn(client, 4);
+  handle.start_tester_present(TesterPresentConfig {
+    target_address: ENGINE,
+    interval: Duration::from_millis(20),
+  });
+  for _ in 0..10 {
+    assert_eq!(
+      handle
+        .client()
+        .request(ENGINE, &[0x22, 0xF1, 0x90])
+        .await
+        .unwrap(),
+      vec![0x62, 0xF1, 0x90]
+    );
+  }
+  handle.shutdown().await;
+}
@@ -0,0 +1,184 @@
+use axum::http::StatusCode;
+use axum::{Json, Router, routing::post};
+mod common;
+
+use common::{connect, demo_vehicle, start_vehicle};
+use doip2http::uds_key_provider::{KeyCommand, KeyServer, KeyServerUrl};
+use doip2http::uds_security::{KeyAlgorithms, SecurityAccessStatus, SeedKeyAlgorithm};
+use doip2http::uds_service::{EXTENDED_SESSION, PROGRAMMING_SESSION};
+use serde_json::{Value, json};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+static ENGINE: u16 = 0x1010;
+
+fn stub_command(mask: &str) -> KeyCommand {
+  KeyCommand {
+    program: "sh".to_string(),
+    args: vec![
+      concat!(env!("CARGO_MANIFEST_DIR"), "/tests/stubs/xor_key.sh").to_string(),
+      mask.to_string(),
+    ],
+    timeout: Duration::from_secs(5),
+  }
+}
+
+fn sh(script: &str, timeout: Duration) -> KeyCommand {
+  KeyCommand {
+    program: "sh".to_string(),
+    args: vec!["-c".to_string(), script.to_string()],
+    timeout,
+  }
+}
+
+fn key_server(server: SocketAddr, timeout: Duration) -> KeyServer {
+  KeyServer {
+    url: KeyServerUrl::parse(&format!("http://{}/key", server)).unwrap(),
+    tls: None,
+    timeout,
+  }
+}
+
+// Key server stub computing seed XOR 0xC3 for the engine, errors for other ECUs
+async fn start_key_server() -> SocketAddr {
+  let app = Router::new()
+    .route(
+      "/key",
+      post(|Json(request): Json<Value>| async move {
+        if request["ecu"] != "0x1010" || request["level"] != "0x11" {
+          return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "no key for this ECU"})),
+          );
+        }
+        let seed = request["seed"].as_str().unwrap();
+        let key: String = (2..seed.len())
+          .step_by(2)
+          .map(|i| {
+            format!(
+              "{:02X}",
+              u8::from_str_radix(&seed[i..i + 2], 16).unwrap() ^ 0xC3
+            )
+          })
+          .collect();
+        (StatusCode::OK, Json(json!({ "key": format!("0x{}", key) })))
+      }),
+    )
+    .route(
+      "/broken",
+      post(|| async { (StatusCode::BAD_GATEWAY, "down") }),
+    );
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let server = listener.local_addr().unwrap();
+  tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+  server
+}
+
+#[tokio::test]
+async fn unlocks_with_key_command_and_key_server() {
+  start_vehicle(&demo_vehicle("127.0.0.53")).await;
+  let client = connect("127.0.0.53").await;
+
+  client
+    .diagnostic_session_control(ENGINE, EXTENDED_SESSION)
+    .await
+    .unwrap();
+  assert_eq!(
+    client
+      .security_unlock(ENGINE, 0x01, &stub_command("5A"))
+      .await
+      .unwrap(),
+    SecurityAccessStatus::Unlocked
+  );
+
+  let server = start_key_server().await;
+  client
+    .diagnostic_session_control(ENGINE, PROGRAMMING_SESSION)
+    .await
+    .unwrap();
+  assert_eq!(
+    client
+      .security_unlock(ENGINE, 0x11, &key_server(server, Duration::from_secs(5)))
+      .await
+      .unwrap(),
+    SecurityAccessStatus::Unlocked
+  );
+}
+
+#[tokio::test]
+async fn reports_provider_errors_and_timeouts() {
+  let seed = [0x12, 0x34];
+
+  let started = Instant::now();
+  let error = sh("sleep 5", Duration::from_millis(200))
+    .compute_key(ENGINE, 0x01, &seed)
+    .await
+    .unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::TimedOut);
+  assert!(started.elapsed() < Duration::from_secs(2));
+
+  let error = sh(r#"echo '{"error": "unknown ECU"}'"#, Duration::from_secs(5))
+    .compute_key(ENGINE, 0x01, &seed)
+    .await
+    .unwrap_err();
+  assert!(error.to_string().contains("unknown ECU"), "{}", error);
+  let error = sh("echo failing >&2; exit 3", Duration::from_secs(5))
+    .compute_key(ENGINE, 0x01, &seed)
+    .await
+    .unwrap_err();
+  assert!(error.to_string().contains("failing"), "{}", error);
+
+  let server = start_key_server().await;
+  let error = key_server(server, Duration::from_secs(5))
+    .compute_key(0x1020, 0x11, &seed)
+    .await
+    .unwrap_err();
+  assert!(
+    error.to_string().contains("no key for this ECU"),
+    "{}",
+    error
+  );
+  let mut broken = key_server(server, Duration::from_secs(5));
+  broken.url.path = "/broken".to_string();
+  let error = broken.compute_key(ENGINE, 0x11, &seed).await.unwrap_err();
+  assert!(error.to_string().contains("HTTP 502"), "{}", error);
+
+  // A server that accepts but never answers
+  let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let error = key_server(silent.local_addr().unwrap(), Duration::from_millis(200))
+    .compute_key(ENGINE, 0x11, &seed)
+    .await
+    .unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn parses_key_provider_configuration() {
+  let url = KeyServerUrl::parse("https://keys.example.com/v1/key").unwrap();
+  assert!(url.tls);
+  assert_eq!((url.host.as_str(), url.port), ("keys.example.com", 443));
+  assert_eq!(url.path, "/v1/key");
+  let url = KeyServerUrl::parse("http://[::1]:8081").unwrap();
+  assert_eq!((url.host.as_str(), url.port), ("::1", 8081));
+  assert_eq!(url.path, "/");
+  assert!(KeyServerUrl::parse("ftp://keys").is_err());
+  assert!(KeyServerUrl::parse("http://keys:port").is_err());
+
+  let algorithms = KeyAlgorithms::from_json(
+    r#"{"ecus": [
+      {"target_address": "0x1010", "algorithm": "command", "command": "oem-keys", "args": ["--engine"]},
+      {"target_address": "0x1020", "algorithm": "key_server", "url": "http://127.0.0.1:8081/key", "timeout_ms": 1000}
+    ]}"#,
+  )
+  .unwrap();
+  assert!(format!("{:?}", algorithms.get(0x1010, 0x01).unwrap()).contains("oem-keys"));
+  assert!(format!("{:?}", algorithms.get(0x1020, 0x01).unwrap()).contains("1s"));
+
+  let error = KeyAlgorithms::from_json(
+    r#"{"ecus": [{"target_address": "0x1010", "algorithm": "key_server", "url": "https://keys"}]}"#,
+  )
+  .unwrap_err();
+  assert!(error.to_string().contains("tls"), "{}", error);
+}
@@ -0,0 +1,237 @@
+mod common;
+
+use common::{connect, demo_vehicle, post, start_server, start_vehicle};
+use doip2http::doip_entity::{DoipEntity, DoipEntityConfig, Responder, UdsHandler, UdsRequest};
+use doip2http::doip2http::{AppState, ServerConfig};
+use doip2http::uds_nrc::Nrc;
+use doip2http::uds_security::{
+  FixedKey, KeyAlgorithms, SecurityAccessStatus, SeedKeyAlgorithm, XorMask,
+};
+use doip2http::uds_service::{EXTENDED_SESSION, UdsError};
+use std::time::Duration;
+use tokio::time::sleep;
+
+static ENGINE: u16 = 0x1010;
+
+fn demo_security() -> KeyAlgorithms {
+  KeyAlgorithms::load(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vehicles/demo_security.json"
+  ))
+  .unwrap()
+}
+
+// Demo vehicle whose engine locks security access out for `lockout_ms`
+async fn start_demo(address: &str, lockout_ms: u64) {
+  let mut description = demo_vehicle(address);
+  description.ecus[1].security_levels[0].lockout_ms = lockout_ms;
+  start_vehicle(&description).await;
+}
+
+// ECU answering requestSeed 0x01 with a seed and 0x03 with an all-zero seed (already
+// unlocked). sendKey is answered with the NRC given as first key byte, 0x00 unlocks.
+struct ScriptedSecurity;
+
+impl UdsHandler for ScriptedSecurity {
+  async fn handle(&self, request: UdsRequest, _responder: Responder) -> Option<Vec<u8>> {
+    match request.data[..] {
+      [0x27, 0x01] => Some(vec![0x67, 0x01, 0x12, 0x34]),
+      [0x27, 0x03] => Some(vec![0x67, 0x03, 0x00, 0x00]),
+      [0x27, level, 0x00, ..] => Some(vec![0x67, level]),
+      [0x27, _, nrc, ..] => Some(vec![0x7F, 0x27, nrc]),
+      _ => Some(vec![0x7F, request.data[0], 0x11]),
+    }
+  }
+}
+
+#[tokio::test]
+async fn unlocks_and_reports_attempt_counter() {
+  start_demo("127.0.0.51", 300).await;
+  let client = connect("127.0.0.51").await;
+  client
+    .diagnostic_session_control(ENGINE, EXTENDED_SESSION)
+    .await
+    .unwrap();
+
+  // Three invalid keys lock the level until the delay has expired
+  let wrong = FixedKey { key: vec![0x00; 4] };
+  for expected in [
+    SecurityAccessStatus::InvalidKey,
+    SecurityAccessStatus::InvalidKey,
+    SecurityAccessStatus::ExceededNumberOfAttempts,
+    SecurityAccessStatus::RequiredTimeDelayNotExpired,
+  ] {
+    assert_eq!(
+      client.security_unlock(ENGINE, 0x01, &wrong).await.unwrap(),
+      expected
+    );
+  }
+
+  sleep(Duration::from_millis(350)).await;
+  let algorithm = demo_security().get(ENGINE, 0x01).unwrap();
+  assert_eq!(
+    client
+      .security_unlock(ENGINE, 0x01, algorithm.as_ref())
+      .await
+      .unwrap(),
+    SecurityAccessStatus::Unlocked
+  );
+  assert_eq!(
+    client
+      .security_unlock(ENGINE, 0x01, algorithm.as_ref())
+      .await
+      .unwrap(),
+    SecurityAccessStatus::AlreadyUnlocked
+  );
+
+  // Other negative responses stay errors
+  match client
+    .security_unlock(ENGINE, 0x11, algorithm.as_ref())
+    .await
+  {
+    Err(UdsError::NegativeResponse {
+      service_id: 0x27,
+      nrc: Nrc::SubFunctionNotSupportedInActiveSession,
+    }) => {}
+    other => panic!("expected NRC 0x7E, got {:?}", other),
+  }
+}
+
+#[tokio::test]
+async fn maps_security_access_responses_to_status() {
+  let config = DoipEntityConfig {
+    address: "127.0.0.54".parse().unwrap(),
+    target_addresses: vec![ENGINE],
+    ..DoipEntityConfig::default()
+  };
+  let entity = DoipEntity::bind(config, ScriptedSecurity).await.unwrap();
+  tokio::spawn(entity.run());
+  let client = connect("127.0.0.54").await;
+
+  for (key, expected) in [
+    (0x00, SecurityAccessStatus::Unlocked),
+    (0x35, SecurityAccessStatus::InvalidKey),
+    (0x36, SecurityAccessStatus::ExceededNumberOfAttempts),
+    (0x37, SecurityAccessStatus::RequiredTimeDelayNotExpired),
+  ] {
+    let algorithm = FixedKey { key: vec![key] };
+    let status = client
+      .security_unlock(ENGINE, 0x01, &algorithm)
+      .await
+      .unwrap();
+    assert_eq!(status, expected);
+    assert_eq!(status.is_unlocked(), key == 0x00);
+    assert_eq!(
+      status.nrc().map(|nrc| nrc.code()),
+      (key != 0x00).then_some(key)
+    );
+  }
+
+  // An all-zero seed means the level is unlocked, no key is sent
+  let algorithm = FixedKey { key: vec![0x35] };
+  assert_eq!(
+    client
+      .security_unlock(ENGINE, 0x03, &algorithm)
+      .await
+      .unwrap(),
+    SecurityAccessStatus::AlreadyUnlocked
+  );
+
+  // Other NRCs to sendKey stay errors
+  let algorithm = FixedKey { key: vec![0x33] };
+  match client.security_unlock(ENGINE, 0x01, &algorithm).await {
+    Err(UdsError::NegativeResponse {
+      nrc: Nrc::SecurityAccessDenied,
+      ..
+    }) => {}
+    other => panic!("expected NRC 0x33, got {:?}", other),
+  }
+}
+
+#[tokio::test]
+async fn serves_security_access_over_http() {
+  start_demo("127.0.0.52", 10_000).await;
+  let server = start_server(AppState::with_config(ServerConfig {
+    key_algorithms: demo_security(),
+    ..ServerConfig::default()
+  }))
+  .await;
+
+  let connection = serde_json::json!({
+    "ecu_ip": "127.0.0.52",
+    "doip_source_address": "0x0E00",
+  });
+  let (status, body) = post(server, "/connect", connection.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+  let mut diagnostic = connection.clone();
+  diagnostic["doip_target_address"] = "0x1010".into();
+  diagnostic["uds_data"] = "0x1003".into();
+  post(server, "/diagnostic", diagnostic).await;
+
+  let mut unlock = connection.clone();
+  unlock["doip_target_address"] = "0x1010".into();
+  unlock["level"] = "0x01".into();
+  let (status, body) = post(server, "/security_access", unlock.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["success"], true);
+  assert_eq!(body["status"], "unlocked");
+
+  // The programming level is not available in the extended session
+  unlock["level"] = "0x11".into();
+  let (status, body) = post(server, "/security_access", unlock.clone()).await;
+  assert_eq!(status, 200, "{}", body);
+  assert_eq!(body["success"], false);
+  assert_eq!(body["nrc"], "0x7E");
+
+  unlock["level"] = "0x02".into();
+  let (status, _) = post(server, "/security_access", unlock.clone()).await;
+  assert_eq!(status, 400);
+  unlock["level"] = "0x03".into();
+  let (status, body) = post(server, "/security_access", unlock).await;
+  assert_eq!(status, 400);
+  assert!(
+    body["message"]
+      .as_str()
+      .unwrap()
+      .contains("No seed/key algorithm")
+  );
+}
+
+#[test]
+fn selects_algorithms_per_ecu_and_level() {
+  let algorithms = KeyAlgorithms::from_json(
+    r#"{"ecus": [
+      {"target_address": "0x1010", "level": "0x01", "algorithm": "xor_mask", "mask": "0x5A5A"},
+      {"target_address": "0x1010", "algorithm": "fixed_key", "key": "0x1234"}
+    ]}"#,
+  )
+  .unwrap();
+  assert_eq!(algorithms.len(), 2);
+  assert!(format!("{:?}", algorithms.get(0x1010, 0x01).unwrap()).contains("XorMask"));
+  assert!(format!("{:?}", algorithms.get(0x1010, 0x03).unwrap()).contains("FixedKey"));
+  assert!(algorithms.get(0x1020, 0x01).is_none());
+
+  let error = KeyAlgorithms::from_json(
+    r#"{"ecus": [{"target_address": "0x1010", "level": "0x02", "algorithm": "fixed_key", "key": "0x00"}]}"#,
+  )
+  .unwrap_err();
+  assert!(error.to_string().contains("must be odd"));
+  assert!(
+    KeyAlgorithms::from_json(r#"{"ecus": [{"target_address": "0x1010", "algorithm": "rot13"}]}"#)
+      .is_err()
+  );
+}
+
+#[tokio::test]
+async fn xor_mask_repeats_for_long_seeds() {
+  let algorithm = XorMask {
+    mask: vec![0xFF, 0x00],
+  };
+  assert_eq!(
+    algorithm
+      .compute_key(ENGINE, 0x01, &[0x12, 0x34, 0x56])
+      .await
+      .unwrap(),
+    [0xED, 0x34, 0xA9]
+  );
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use common::{connect, demo_vehicle, start_vehicle};
+use doip2http::uds_client::UdsClient;
+use doip2http::uds_nrc::Nrc;
+use doip2http::uds_service::{
+  ALL_DTC_GROUPS, DataRecord, DtcRecord, EXTENDED_SESSION, PROGRAMMING_SESSION, ResetType,
+  RoutineControlType, UdsError,
+};
+use std::time::Duration;
+
+static GATEWAY: u16 = 0x1001;
+static ENGINE: u16 = 0x1010;
+
+async fn start_demo(address: &str) -> UdsClient {
+  let mut description = demo_vehicle(address);
+  description.ecus[1].routines[0].duration_ms = 200;
+  start_vehicle(&description).await;
+  connect(address).await
+}
+
+#[tokio::test]
+async fn reads_and_writes_data_identifiers() {
+  let client = start_demo("127.0.0.41").await;
+
+  let records = client
+    .read_data_by_identifiers(GATEWAY, &[(0xF190, 17), (0xF18C, 5)])
+    .await
+    .unwrap();
+  assert_eq!(
+    records,
+    [
+      DataRecord {
+        did: 0xF190,
+        data: b"DOIP2HTTPDEMO0001".to_vec()
+      },
+      DataRecord {
+        did: 0xF18C,
+        data: b"GW001".to_vec()
+      },
+    ]
+  );
+
+  assert_eq!(
+    client
+      .read_data_by_identifier(GATEWAY, 0xF18C)
+      .await
+      .unwrap()
+      .data,
+    b"GW001"
+  );
+  // Lengths not matching the response are an error, not a guess
+  assert!(matches!(
+    client
+      .read_data_by_identifiers(GATEWAY, &[(0xF190, 16), (0xF18C, 5)])
+      .await,
+    Err(UdsError::InvalidResponse(_))
+  ));
+  assert!(matches!(
+    client
+      .read_data_by_identifiers(GATEWAY, &[(0xF190, 17), (0xF18C, 4)])
+      .await,
+    Err(UdsError::InvalidResponse(_))
+  ));
+
+  match client.read_data_by_identifier(ENGINE, 0x0100).await {
+    Err(UdsError::NegativeResponse {
+      service_id: 0x22,
+      nrc: Nrc::RequestOutOfRange,
+    }) => {}
+    other => panic!("expected NRC 0x31, got {:?}", other),
+  }
+
+  let timing = client
+    .diagnostic_session_control(ENGINE, EXTENDED_SESSION)
+    .await
+    .unwrap();
+  assert_eq!(timing.p2, Duration::from_millis(50));
+  assert_eq!(timing.p2_star, Duration::from_millis(5000));
+
+  let seed = client
+    .security_access_request_seed(ENGINE, 0x01)
+    .await
+    .unwrap();
+  let key: Vec<u8> = seed.iter().map(|byte| byte ^ 0x5A).collect();
+  client
+    .security_access_send_key(ENGINE, 0x01, &key)
+    .await
+    .unwrap();
+  client
+    .write_data_by_identifier(ENGINE, 0x0100, &[0x12, 0x34])
+    .await
+    .unwrap();
+  assert_eq!(
+    client
+      .read_data_by_identifier(ENGINE, 0x0100)
+      .await
+      .unwrap()
+      .data,
+    [0x12, 0x34]
+  );
+}
+
+#[tokio::test]
+async fn runs_routines_dtc_services_and_resets() {
+  let client = start_demo("127.0.0.42").await;
+
+  let report = client.read_dtc_by_status_mask(ENGINE, 0x08).await.unwrap();
+  assert_eq!(report.availability_mask, 0xFF);
+  assert_eq!(
+    report.dtcs,
+    [
+      DtcRecord {
+        code: 0x012300,
+        status: 0x2F
+      },
+      DtcRecord {
+        code: 0x030100,
+        status: 0x08
+      },
+    ]
+  );
+  client
+    .clear_diagnostic_information(ENGINE, ALL_DTC_GROUPS)
+    .await
+    .unwrap();
+  let count = client
+    .read_number_of_dtc_by_status_mask(ENGINE, 0xFF)
+    .await
+    .unwrap();
+  assert_eq!(count.count, 0);
+  assert_eq!(
+    client.read_supported_dtcs(ENGINE).await.unwrap().dtcs.len(),
+    3
+  );
+
+  client
+    .diagnostic_session_control(ENGINE, PROGRAMMING_SESSION)
+    .await
+    .unwrap();
+  let seed = client
+    .security_access_request_seed(ENGINE, 0x11)
+    .await
+    .unwrap();
+  let key: Vec<u8> = seed.iter().map(|byte| byte ^ 0xC3).collect();
+  client
+    .security_access_send_key(ENGINE, 0x11, &key)
+    .await
+    .unwrap();
+  let routine = client
+    .routine_control(ENGINE, RoutineControlType::StartRoutine, 0xFF00, &[])
+    .await
+    .unwrap();
+  assert_eq!(routine.routine_id, 0xFF00);
+  assert_eq!(routine.status_record, [0x00]);
+
+  client.tester_present(ENGINE, true).await.unwrap();
+  client.tester_present(ENGINE, false).await.unwrap();
+
+  let reset = client
+    .ecu_reset(ENGINE, ResetType::SoftReset)
+    .await
+    .unwrap();
+  assert_eq!(reset.reset_type, 0x03);
+  // The reset locked security access again
+  match client.security_access_request_seed(ENGINE, 0x11).await {
+    Err(UdsError::NegativeResponse {
+      nrc: Nrc::ServiceNotSupportedInActiveSession,
+      ..
+    }) => {}
+    other => panic!("expected NRC 0x7F, got {:?}", other),
+  }
+  // sendKey is level + 1, even levels and levels past 0x7D are never sent
+  for level in [0x02, 0x7F, 0xFF] {
+    assert!(matches!(
+      client
+        .security_access_send_key(ENGINE, level, &[0x00])
+        .await,
+      Err(UdsError::InvalidRequest(_))
+    ));
+  }
+}
+
+#[test]
+fn maps_negative_response_codes() {
+  assert_eq!(Nrc::from(0x33), Nrc::SecurityAccessDenied);
+  assert_eq!(Nrc::SecurityAccessDenied.name(), "securityAccessDenied");
+  assert_eq!(Nrc::from(0x36).code(), 0x36);
+  assert_eq!(Nrc::from(0xF3), Nrc::ManufacturerSpecific(0xF3));
+  assert_eq!(Nrc::from(0x40), Nrc::Reserved(0x40));
+  assert_eq!(
+    Nrc::RequestOutOfRange.to_string(),
+    "requestOutOfRange (0x31)"
+  );
+  for code in 0..=0xFF {
+    assert_eq!(Nrc::from(code).code(), code);
+  }
+}